@@ -0,0 +1,192 @@
+//! Compares the packed byte encoding of `Chunk` with the one it replaced,
+//! a `Vec<OpCode>` of 16-byte enums, on arithmetic-heavy scripts.
+//!
+//! Both are run by the same small dispatch loop, which only knows numbers,
+//! so the difference is in fetching and decoding instructions alone. The
+//! time the full `Vm` takes on the packed chunk is shown for scale.
+//!
+//! Run it with `cargo bench --bench encoding`.
+
+use std::{hint::black_box, time::Instant};
+
+use rabbit::{compile, Chunk, OpCode, Vm};
+
+const SCRIPTS: &[(&str, &str)] = &[
+    (
+        "counting loop",
+        "{ let i = 0; let sum = 0;
+           while i < 300000 { sum = sum + i * 2 - i / 4; i = i + 1; } }",
+    ),
+    (
+        "polynomial",
+        "{ let x = 0; let acc = 0;
+           while x < 200000 { acc = acc + (x * x * 3 + x * 2 + 1) % 7; x = x + 1; } }",
+    ),
+    (
+        "nested loops",
+        "{ let i = 0; let total = 0;
+           while i < 500 {
+             let j = 0;
+             while j < 500 { total = total + i * j - (i - j); j = j + 1; }
+             i = i + 1;
+           } }",
+    ),
+];
+
+const RUNS: usize = 5;
+
+trait Code {
+    /// The instruction at `pc` and where the next one starts.
+    fn fetch(&self, pc: usize) -> (OpCode, usize);
+}
+
+struct Packed<'a>(&'a Chunk);
+
+impl Code for Packed<'_> {
+    #[inline]
+    fn fetch(&self, pc: usize) -> (OpCode, usize) {
+        let (opcode, size) = self.0.read(pc);
+        (opcode, pc + size)
+    }
+}
+
+/// One enum per instruction, with jumps counted in instructions.
+struct Unpacked(Vec<OpCode>);
+
+impl Unpacked {
+    fn new(chunk: &Chunk) -> Unpacked {
+        let offsets: Vec<usize> = chunk.instructions().map(|(offset, _)| offset).collect();
+        let index = |offset: usize| offsets.binary_search(&offset).unwrap();
+        let code = chunk
+            .instructions()
+            .enumerate()
+            .map(|(i, (offset, opcode))| {
+                let next = offset + opcode.size();
+                match opcode {
+                    OpCode::Jump(distance) => OpCode::Jump(index(next + distance) - i - 1),
+                    OpCode::JumpIfFalse(distance) => {
+                        OpCode::JumpIfFalse(index(next + distance) - i - 1)
+                    }
+                    OpCode::JumpIfNotLess(distance) => {
+                        OpCode::JumpIfNotLess(index(next + distance) - i - 1)
+                    }
+                    OpCode::Loop(distance) => OpCode::Loop(i + 1 - index(next - distance)),
+                    opcode => opcode,
+                }
+            })
+            .collect();
+        Unpacked(code)
+    }
+}
+
+impl Code for Unpacked {
+    #[inline]
+    fn fetch(&self, pc: usize) -> (OpCode, usize) {
+        (self.0[pc], pc + 1)
+    }
+}
+
+/// Runs a chunk of numeric code, with `0` and `1` for booleans, returning
+/// how many instructions it executed.
+fn run(code: &impl Code, constants: &[f64]) -> usize {
+    let mut stack: Vec<f64> = Vec::with_capacity(64);
+    let mut pc = 0;
+    let mut executed = 0;
+    loop {
+        let (opcode, next) = code.fetch(pc);
+        pc = next;
+        executed += 1;
+        macro_rules! binary {
+            ($op:expr) => {{
+                let b = stack.pop().unwrap();
+                let a = stack.pop().unwrap();
+                stack.push($op(a, b));
+            }};
+        }
+        match opcode {
+            OpCode::Constant(index) | OpCode::ConstantLong(index) => stack.push(constants[index]),
+            OpCode::GetLocal(slot) => stack.push(stack[slot]),
+            OpCode::SetLocal(slot) => stack[slot] = *stack.last().unwrap(),
+            OpCode::Pop => {
+                stack.pop();
+            }
+            OpCode::Add => binary!(|a, b| a + b),
+            OpCode::Subtract => binary!(|a, b| a - b),
+            OpCode::Multiply => binary!(|a, b| a * b),
+            OpCode::Divide => binary!(|a, b| a / b),
+            OpCode::Modulo => binary!(|a: f64, b| a % b),
+            OpCode::Less => binary!(|a, b| f64::from(u8::from(a < b))),
+            OpCode::Greater => binary!(|a, b| f64::from(u8::from(a > b))),
+            OpCode::Not => {
+                let value = stack.pop().unwrap();
+                stack.push(f64::from(u8::from(value == 0.0)));
+            }
+            OpCode::Jump(distance) => pc += distance,
+            OpCode::JumpIfFalse(distance) => {
+                if *stack.last().unwrap() == 0.0 {
+                    pc += distance;
+                }
+            }
+            OpCode::Loop(distance) => pc -= distance,
+            OpCode::AddLocalConst(slot, index) => stack.push(stack[slot] + constants[index]),
+            OpCode::IncrementLocal(slot) => stack[slot] += 1.0,
+            OpCode::JumpIfNotLess(distance) => {
+                let b = stack.pop().unwrap();
+                let a = stack.pop().unwrap();
+                if a >= b {
+                    pc += distance;
+                }
+            }
+            OpCode::Return => return executed,
+            opcode => panic!("{opcode:?} isn't numeric"),
+        }
+    }
+}
+
+/// The best of a few runs, in milliseconds.
+fn time(mut f: impl FnMut()) -> f64 {
+    (0..RUNS)
+        .map(|_| {
+            let start = Instant::now();
+            f();
+            start.elapsed().as_secs_f64() * 1000.0
+        })
+        .fold(f64::INFINITY, f64::min)
+}
+
+fn main() {
+    println!(
+        "{:14} {:>13} {:>13} {:>11} {:>11} {:>9}",
+        "script", "enum bytes", "packed bytes", "enum ms", "packed ms", "vm ms"
+    );
+    for (name, source) in SCRIPTS {
+        let chunk = compile(source).unwrap();
+        let constants: Vec<f64> = chunk
+            .constants()
+            .iter()
+            .map(|constant| constant.as_number().unwrap_or(0.0))
+            .collect();
+        let unpacked = Unpacked::new(&chunk);
+        let packed = Packed(&chunk);
+        assert_eq!(run(&unpacked, &constants), run(&packed, &constants));
+
+        let enum_ms = time(|| {
+            black_box(run(black_box(&unpacked), &constants));
+        });
+        let packed_ms = time(|| {
+            black_box(run(black_box(&packed), &constants));
+        });
+        let vm_ms = time(|| {
+            Vm::new().interpret(compile(source).unwrap()).unwrap();
+        });
+        println!(
+            "{:14} {:>13} {:>13} {:>11.2} {:>11.2} {:>9.2}",
+            name,
+            unpacked.0.len() * size_of::<OpCode>(),
+            chunk.code().len(),
+            enum_ms,
+            packed_ms,
+            vm_ms
+        );
+    }
+}
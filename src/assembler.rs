@@ -0,0 +1,622 @@
+use std::{collections::HashMap, error::Error, fmt, rc::Rc};
+
+use crate::{
+    chunk::{Chunk, OpCode, Span},
+    function::{Capture, Function},
+    value::Value,
+};
+
+/// Builds a chunk from text in the format `Chunk::disassemble_chunk`
+/// writes, so `assemble` undoes a disassembly exactly.
+///
+/// Hand-written code can leave out the offsets and positions, taking the
+/// line of the text as its span, and jump to labels instead of counting
+/// instructions. Lines starting with `;` are comments.
+///
+/// ```
+/// use rabbit::{assemble, Value, Vm};
+///
+/// let chunk = assemble(
+///     "== countdown ==
+///      OP_CONSTANT 0
+///      top:
+///      OP_GET_LOCAL 0
+///      OP_CONSTANT 2
+///      OP_GREATER
+///      OP_JUMP_IF_FALSE done
+///      OP_POP
+///      OP_GET_LOCAL 0
+///      OP_CONSTANT 1
+///      OP_SUBTRACT
+///      OP_SET_LOCAL 0
+///      OP_POP
+///      OP_LOOP top
+///      done:
+///      OP_POP
+///      OP_DEFINE_GLOBAL 3
+///      OP_RETURN
+///      -- constants --
+///      0 3.0
+///      1 1.0
+///      2 0.0
+///      3 \"left\"",
+/// )
+/// .unwrap();
+/// let mut vm = Vm::new();
+/// vm.interpret(chunk).unwrap();
+/// assert_eq!(vm.get_global("left"), Some(&Value::Number(0.0)));
+/// ```
+pub fn assemble(text: &str) -> Result<Chunk, AssembleError> {
+    let lines = text
+        .lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with(';'))
+        .collect();
+    let mut assembler = Assembler { lines, next: 0 };
+    let chunk = assembler.chunk()?;
+    match assembler.peek() {
+        Some((line, _)) => Err(AssembleError::new(line, "Expected the end of the input.")),
+        None => Ok(chunk),
+    }
+}
+
+/// Why text couldn't be assembled.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AssembleError {
+    /// The line of the text at fault.
+    pub line: usize,
+    pub message: String,
+}
+
+impl AssembleError {
+    fn new(line: usize, message: impl Into<String>) -> AssembleError {
+        AssembleError {
+            line,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for AssembleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[line {}] {}", self.line, self.message)
+    }
+}
+
+impl Error for AssembleError {}
+
+/// A jump's operand, which may name a label defined further on.
+enum Target<'a> {
+    Distance(usize),
+    Label(&'a str),
+}
+
+/// A function constant, whose chunk follows the constant pool.
+struct FunctionHeader {
+    name: Option<Rc<str>>,
+    arity: usize,
+    captures: Vec<Capture>,
+}
+
+struct Assembler<'a> {
+    lines: Vec<(usize, &'a str)>,
+    next: usize,
+}
+
+impl<'a> Assembler<'a> {
+    fn peek(&self) -> Option<(usize, &'a str)> {
+        self.lines.get(self.next).copied()
+    }
+    fn chunk(&mut self) -> Result<Chunk, AssembleError> {
+        match self.peek() {
+            Some((_, line)) if line.starts_with("==") && line.ends_with("==") => self.next += 1,
+            Some((line, _)) => {
+                return Err(AssembleError::new(line, "Expected a '== name ==' header."))
+            }
+            None => return Err(AssembleError::new(self.last_line(), "Expected a chunk.")),
+        }
+        let mut chunk = Chunk::new();
+        let mut labels = HashMap::new();
+        let mut jumps = vec![];
+        while let Some((number, line)) = self.peek() {
+            if line.starts_with("==") || line == "-- constants --" {
+                break;
+            }
+            self.next += 1;
+            if let Some(label) = line.strip_suffix(':').filter(|label| is_label(label)) {
+                if labels.insert(label, chunk.code().len()).is_some() {
+                    return Err(AssembleError::new(
+                        number,
+                        format!("Label '{label}' is defined twice."),
+                    ));
+                }
+            } else if line.starts_with("local ") || line.starts_with("upvalue ") {
+                // Captures listed under `OP_CLOSURE`, which the constant
+                // pool has too.
+            } else {
+                let default = Span {
+                    line: number,
+                    column: 1,
+                };
+                let (opcode, span, target) = instruction(number, line, default)?;
+                if let Some(Target::Label(label)) = target {
+                    jumps.push((chunk.code().len(), label, number));
+                }
+                if !opcode.fits() {
+                    return Err(AssembleError::new(
+                        number,
+                        "The operand is too large for the instruction.",
+                    ));
+                }
+                chunk.write(opcode, span);
+            }
+        }
+        for (offset, label, number) in jumps {
+            let Some(&target) = labels.get(label) else {
+                return Err(AssembleError::new(
+                    number,
+                    format!("Undefined label '{label}'."),
+                ));
+            };
+            let jump = chunk.decode(offset);
+            let next = offset + jump.size();
+            let opcode = match jump {
+                OpCode::Jump(_) if target >= next => OpCode::Jump(target - next),
+                OpCode::JumpIfFalse(_) if target >= next => OpCode::JumpIfFalse(target - next),
+                OpCode::JumpIfNotLess(_) if target >= next => OpCode::JumpIfNotLess(target - next),
+                OpCode::Loop(_) if target <= next => OpCode::Loop(next - target),
+                OpCode::Loop(_) => {
+                    return Err(AssembleError::new(
+                        number,
+                        "OP_LOOP can only jump backwards.",
+                    ))
+                }
+                _ => return Err(AssembleError::new(number, "Jumps can only go forwards.")),
+            };
+            if !opcode.fits() {
+                return Err(AssembleError::new(
+                    number,
+                    format!("Label '{label}' is too far away."),
+                ));
+            }
+            chunk.patch(offset, opcode);
+        }
+        let mut constants = vec![];
+        if self
+            .peek()
+            .is_some_and(|(_, line)| line == "-- constants --")
+        {
+            self.next += 1;
+            while let Some((number, line)) = self.peek() {
+                let Some((index, literal)) = line.split_once(char::is_whitespace) else {
+                    break;
+                };
+                let Ok(index) = index.parse::<usize>() else {
+                    break;
+                };
+                self.next += 1;
+                if index != constants.len() {
+                    return Err(AssembleError::new(
+                        number,
+                        format!("Expected constant {}.", constants.len()),
+                    ));
+                }
+                constants.push(
+                    constant(literal.trim())
+                        .map_err(|message| AssembleError::new(number, message))?,
+                );
+            }
+        }
+        for constant in constants {
+            let value = match constant {
+                Ok(value) => value,
+                Err(header) => Value::Function(Rc::new(Function {
+                    name: header.name,
+                    arity: header.arity,
+                    chunk: self.chunk()?,
+                    captures: header.captures,
+                })),
+            };
+            chunk.push_constant(value);
+        }
+        Ok(chunk)
+    }
+    fn last_line(&self) -> usize {
+        self.lines.last().map_or(1, |(line, _)| *line)
+    }
+}
+
+fn is_label(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+}
+
+/// Parses an instruction line: an optional offset and `line:column`, the
+/// mnemonic and its operand. Anything after the operand, like the constant
+/// it refers to, is there for people and ignored.
+fn instruction<'a>(
+    number: usize,
+    line: &'a str,
+    default: Span,
+) -> Result<(OpCode, Span, Option<Target<'a>>), AssembleError> {
+    let error = |message: String| AssembleError::new(number, message);
+    let mut words = line.split_whitespace();
+    let mut span = default;
+    let mnemonic = loop {
+        match words.next() {
+            Some(word) if word.starts_with("OP_") => break word,
+            Some(word) if word.bytes().all(|b| b.is_ascii_digit()) => {}
+            Some(word) => {
+                let position = word.split_once(':').and_then(|(line, column)| {
+                    Some(Span {
+                        line: line.parse().ok()?,
+                        column: column.parse().ok()?,
+                    })
+                });
+                span = position.ok_or_else(|| error(format!("Unexpected '{word}'.")))?;
+            }
+            None => return Err(error(String::from("Expected an instruction."))),
+        }
+    };
+    let mut operand = || {
+        let word = words
+            .next()
+            .ok_or_else(|| error(format!("{mnemonic} needs an operand.")))?;
+        word.parse::<usize>()
+            .map_err(|_| error(format!("Expected a number after {mnemonic}.")))
+    };
+    let opcode = match mnemonic {
+        "OP_CONSTANT" => OpCode::Constant(operand()?),
+        "OP_CONSTANT_LONG" => OpCode::ConstantLong(operand()?),
+        "OP_NULL" => OpCode::Null,
+        "OP_TRUE" => OpCode::True,
+        "OP_FALSE" => OpCode::False,
+        "OP_POP" => OpCode::Pop,
+        "OP_GET_LOCAL" => OpCode::GetLocal(operand()?),
+        "OP_SET_LOCAL" => OpCode::SetLocal(operand()?),
+        "OP_GET_GLOBAL" => OpCode::GetGlobal(operand()?),
+        "OP_DEFINE_GLOBAL" => OpCode::DefineGlobal(operand()?),
+        "OP_SET_GLOBAL" => OpCode::SetGlobal(operand()?),
+        "OP_GET_UPVALUE" => OpCode::GetUpvalue(operand()?),
+        "OP_SET_UPVALUE" => OpCode::SetUpvalue(operand()?),
+        "OP_GET_PROPERTY" => OpCode::GetProperty(operand()?),
+        "OP_SET_PROPERTY" => OpCode::SetProperty(operand()?),
+        "OP_EQUAL" => OpCode::Equal,
+        "OP_GREATER" => OpCode::Greater,
+        "OP_LESS" => OpCode::Less,
+        "OP_ADD" => OpCode::Add,
+        "OP_SUBTRACT" => OpCode::Subtract,
+        "OP_MULTIPLY" => OpCode::Multiply,
+        "OP_DIVIDE" => OpCode::Divide,
+        "OP_MODULO" => OpCode::Modulo,
+        "OP_BUILD_LIST" => OpCode::BuildList(operand()?),
+        "OP_INDEX" => OpCode::Index,
+        "OP_CALL" => OpCode::Call(operand()?),
+        "OP_CLOSURE" => OpCode::Closure(operand()?),
+        "OP_CLOSE_UPVALUE" => OpCode::CloseUpvalue,
+        "OP_NOT" => OpCode::Not,
+        "OP_NEGATE" => OpCode::Negate,
+        "OP_PRINT" => OpCode::Print,
+        "OP_RETURN" => OpCode::Return,
+        "OP_ADD_LOCAL_CONST" => OpCode::AddLocalConst(operand()?, operand()?),
+        "OP_INCREMENT_LOCAL" => OpCode::IncrementLocal(operand()?),
+        "OP_JUMP" | "OP_JUMP_IF_FALSE" | "OP_JUMP_IF_NOT_LESS" | "OP_LOOP" => {
+            let word = words
+                .next()
+                .ok_or_else(|| error(format!("{mnemonic} needs a distance or a label.")))?;
+            let target = match word.parse() {
+                Ok(distance) => Target::Distance(distance),
+                Err(_) if is_label(word) => Target::Label(word),
+                Err(_) => return Err(error(format!("Bad jump target '{word}'."))),
+            };
+            // Labels are patched in once every instruction is known.
+            let distance = match target {
+                Target::Distance(distance) => distance,
+                Target::Label(_) => 0,
+            };
+            let opcode = match mnemonic {
+                "OP_JUMP" => OpCode::Jump(distance),
+                "OP_JUMP_IF_FALSE" => OpCode::JumpIfFalse(distance),
+                "OP_JUMP_IF_NOT_LESS" => OpCode::JumpIfNotLess(distance),
+                _ => OpCode::Loop(distance),
+            };
+            return Ok((opcode, span, Some(target)));
+        }
+        _ => return Err(error(format!("Unknown instruction '{mnemonic}'."))),
+    };
+    Ok((opcode, span, None))
+}
+
+/// Parses a constant as the disassembler lists it. Functions only get their
+/// header here, since their chunk comes later.
+fn constant(literal: &str) -> Result<Result<Value, FunctionHeader>, String> {
+    let value = match literal {
+        "null" => Value::Null,
+        "true" => Value::Bool(true),
+        "false" => Value::Bool(false),
+        _ if literal.starts_with('"') => Value::String(Rc::from(string(literal)?)),
+        _ if literal.starts_with('<') => return function(literal).map(Err),
+        _ => Value::Number(
+            literal
+                .parse()
+                .map_err(|_| format!("Unknown constant '{literal}'."))?,
+        ),
+    };
+    Ok(Ok(value))
+}
+
+/// Reads a string literal quoted and escaped the way Rust's `{:?}` does.
+fn string(literal: &str) -> Result<String, String> {
+    let error = || format!("Bad string constant {literal}.");
+    let inner = literal
+        .strip_prefix('"')
+        .and_then(|rest| rest.strip_suffix('"'))
+        .ok_or_else(error)?;
+    let mut string = String::new();
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        if c == '"' {
+            return Err(error());
+        }
+        if c != '\\' {
+            string.push(c);
+            continue;
+        }
+        string.push(match chars.next().ok_or_else(error)? {
+            'n' => '\n',
+            'r' => '\r',
+            't' => '\t',
+            '0' => '\0',
+            c @ ('\\' | '"' | '\'') => c,
+            'u' => {
+                let rest = chars.as_str();
+                let (hex, after) = rest
+                    .strip_prefix('{')
+                    .and_then(|rest| rest.split_once('}'))
+                    .ok_or_else(error)?;
+                chars = after.chars();
+                u32::from_str_radix(hex, 16)
+                    .ok()
+                    .and_then(char::from_u32)
+                    .ok_or_else(error)?
+            }
+            _ => return Err(error()),
+        });
+    }
+    Ok(string)
+}
+
+/// Reads `<fn name> arity 2 local 0 upvalue 1`, or `<script> arity 0`.
+fn function(literal: &str) -> Result<FunctionHeader, String> {
+    let error = || format!("Bad function constant '{literal}'.");
+    let (name, rest) = if let Some(rest) = literal.strip_prefix("<script>") {
+        (None, rest)
+    } else {
+        let (name, rest) = literal
+            .strip_prefix("<fn ")
+            .and_then(|rest| rest.split_once('>'))
+            .ok_or_else(error)?;
+        (Some(Rc::from(name)), rest)
+    };
+    let mut words = rest.split_whitespace();
+    let number = |words: &mut std::str::SplitWhitespace| {
+        words
+            .next()
+            .and_then(|word| word.parse::<usize>().ok())
+            .ok_or_else(error)
+    };
+    if words.next() != Some("arity") {
+        return Err(error());
+    }
+    let arity = number(&mut words)?;
+    let mut captures = vec![];
+    while let Some(kind) = words.next() {
+        captures.push(match kind {
+            "local" => Capture::Local(number(&mut words)?),
+            "upvalue" => Capture::Upvalue(number(&mut words)?),
+            _ => return Err(error()),
+        });
+    }
+    Ok(FunctionHeader {
+        name,
+        arity,
+        captures,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{compiler::compile, value::ValueKind};
+
+    fn disassemble(chunk: &Chunk) -> String {
+        let mut out = vec![];
+        chunk.disassemble_chunk("script", &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    /// Whether the chunks have the same code, spans and constants, looking
+    /// inside functions, which `==` on values compares by identity.
+    fn same_chunk(a: &Chunk, b: &Chunk) -> bool {
+        a.code() == b.code()
+            && (0..a.code().len()).all(|i| a.span_at(i) == b.span_at(i))
+            && a.constants().len() == b.constants().len()
+            && a.constants()
+                .iter()
+                .zip(b.constants())
+                .all(|(a, b)| match (a.kind(), b.kind()) {
+                    (ValueKind::Number(a), ValueKind::Number(b)) => {
+                        a.to_bits() == b.to_bits() || (a.is_nan() && b.is_nan())
+                    }
+                    (ValueKind::Function(a), ValueKind::Function(b)) => {
+                        a.name == b.name
+                            && a.arity == b.arity
+                            && a.captures == b.captures
+                            && same_chunk(&a.chunk, &b.chunk)
+                    }
+                    _ => a == b,
+                })
+    }
+
+    /// A xorshift generator, so the property test is reproducible.
+    struct Random(u64);
+
+    impl Random {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+        fn below(&mut self, n: usize) -> usize {
+            (self.next() % n as u64) as usize
+        }
+    }
+
+    fn random_chunk(random: &mut Random, depth: usize) -> Chunk {
+        let mut chunk = Chunk::new();
+        let constants = random.below(6) + 1;
+        for _ in 0..constants {
+            let value = match random.below(if depth < 2 { 6 } else { 5 }) {
+                0 => Value::Null,
+                1 => Value::Bool(random.below(2) == 0),
+                2 => Value::Number(f64::from_bits(random.next())),
+                3 => Value::Number(random.below(1000) as f64 / 8.0),
+                4 => {
+                    let alphabet = ['a', '"', '\\', '\n', '\t', '\'', ';', 'é', '\u{1b}', ' '];
+                    let length = random.below(8);
+                    let string: String = (0..length)
+                        .map(|_| alphabet[random.below(alphabet.len())])
+                        .collect();
+                    Value::String(Rc::from(string))
+                }
+                _ => {
+                    let captures = (0..random.below(3))
+                        .map(|_| match random.below(2) {
+                            0 => Capture::Local(random.below(4)),
+                            _ => Capture::Upvalue(random.below(4)),
+                        })
+                        .collect();
+                    Value::Function(Rc::new(Function {
+                        name: match random.below(3) {
+                            0 => None,
+                            _ => Some(Rc::from(format!("f{}", random.below(10)))),
+                        },
+                        arity: random.below(4),
+                        chunk: random_chunk(random, depth + 1),
+                        captures,
+                    }))
+                }
+            };
+            chunk.push_constant(value);
+        }
+        for _ in 0..random.below(20) + 1 {
+            let constant = random.below(constants);
+            let small = random.below(5);
+            let opcode = match random.below(13) {
+                0 if random.below(2) == 0 => OpCode::Constant(constant),
+                0 => OpCode::ConstantLong(constant),
+                1 => OpCode::GetGlobal(constant),
+                2 => OpCode::SetProperty(constant),
+                3 => OpCode::Closure(constant),
+                4 => OpCode::GetLocal(small),
+                5 => OpCode::Call(small),
+                6 => OpCode::Jump(small),
+                7 => OpCode::JumpIfFalse(small),
+                8 => OpCode::Loop(random.below(chunk.code().len() + 4)),
+                9 => OpCode::Add,
+                10 => OpCode::CloseUpvalue,
+                11 if constant < 256 => OpCode::AddLocalConst(small, constant),
+                11 => OpCode::JumpIfNotLess(small),
+                _ => OpCode::Return,
+            };
+            let span = Span {
+                line: random.below(100) + 1,
+                column: random.below(100) + 1,
+            };
+            chunk.write(opcode, span);
+        }
+        chunk
+    }
+
+    #[test]
+    fn assembling_undoes_disassembling() {
+        let mut random = Random(0x2545_f491_4f6c_dd1d);
+        for _ in 0..500 {
+            let chunk = random_chunk(&mut random, 0);
+            let text = disassemble(&chunk);
+            let assembled = assemble(&text).unwrap_or_else(|error| panic!("{error}\n{text}"));
+            assert!(same_chunk(&assembled, &chunk), "{text}");
+        }
+        let source = "
+            fn counter(step) {
+                let count = 0;
+                return fn() { count += step; return count; };
+            }
+            let c = counter(2);
+            while c() < 10 { if c() == 4 { print \"four\"; } else { print [1, null]; } }
+        ";
+        let chunk = compile(source).unwrap();
+        assert!(same_chunk(&assemble(&disassemble(&chunk)).unwrap(), &chunk));
+    }
+
+    #[test]
+    fn labels_are_resolved() {
+        let chunk = assemble(
+            "== script ==
+             ; Loops while the condition holds.
+             start:
+             OP_TRUE
+             OP_JUMP_IF_FALSE end
+             OP_POP
+             OP_LOOP start
+             end:
+             OP_POP
+             OP_RETURN",
+        )
+        .unwrap();
+        assert_eq!(
+            chunk
+                .instructions()
+                .map(|(_, opcode)| opcode)
+                .collect::<Vec<_>>(),
+            [
+                OpCode::True,
+                OpCode::JumpIfFalse(4),
+                OpCode::Pop,
+                OpCode::Loop(8),
+                OpCode::Pop,
+                OpCode::Return,
+            ]
+        );
+        assert_eq!(chunk.span_at(0), Span { line: 4, column: 1 });
+    }
+
+    #[test]
+    fn mistakes_are_reported_with_their_line() {
+        let error = |text: &str| assemble(text).unwrap_err().to_string();
+        assert_eq!(
+            error("== s ==\nOP_FROB"),
+            "[line 2] Unknown instruction 'OP_FROB'."
+        );
+        assert_eq!(
+            error("== s ==\nOP_CONSTANT"),
+            "[line 2] OP_CONSTANT needs an operand."
+        );
+        assert_eq!(
+            error("== s ==\nOP_JUMP nowhere\nOP_RETURN"),
+            "[line 2] Undefined label 'nowhere'."
+        );
+        assert_eq!(
+            error("== s ==\nOP_RETURN\n-- constants --\n1 null"),
+            "[line 4] Expected constant 0."
+        );
+        assert_eq!(
+            error("OP_RETURN"),
+            "[line 1] Expected a '== name ==' header."
+        );
+    }
+}
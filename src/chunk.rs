@@ -1,40 +1,478 @@
-use crate::value::Value;
+use std::{cell::Cell, collections::HashMap, rc::Rc};
 
+use crate::value::{Value, ValueKind};
+
+/// The most constants a chunk can have, as `ConstantLong` and the other
+/// instructions naming a constant have three bytes for its index. Going
+/// past it is a compile error.
+pub const MAX_CONSTANTS: usize = 1 << 24;
+
+/// Compiled bytecode: instructions packed into bytes, the constants they
+/// refer to and where in the source each instruction came from.
+#[derive(Debug, Default)]
 pub struct Chunk {
-    pub code: Vec<OpCode>,
-    pub constants: Vec<Value>,
-    pub spans: Vec<Span>,
+    code: Vec<u8>,
+    constants: Vec<Value>,
+    /// Where each immutable constant is in the pool, so it's only added
+    /// once.
+    interned: HashMap<ConstantKey, usize>,
+    /// How many times `add_constant` found its value already there.
+    reused: usize,
+    /// Positions, run-length encoded: each run starts at an offset and
+    /// lasts until the next one, as neighbouring instructions often come
+    /// from the same token.
+    spans: Vec<SpanRun>,
+    /// How many stack slots the code needs above its frame's base, once the
+    /// verifier has passed it, and what it was verified as. Changing the
+    /// code forgets it.
+    verified_stack: Cell<Option<(VerifiedAs, usize)>>,
+}
+
+/// What the verifier assumed about how a chunk runs. The stack it needs
+/// only holds for chunks run the same way.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct VerifiedAs {
+    /// What the stack holds above the frame's base on entry.
+    pub height: usize,
+    /// How many upvalues the code can refer to.
+    pub upvalues: usize,
+    pub is_function: bool,
+}
+
+impl VerifiedAs {
+    pub const SCRIPT: VerifiedAs = VerifiedAs {
+        height: 0,
+        upvalues: 0,
+        is_function: false,
+    };
+    pub fn function(arity: usize, upvalues: usize) -> VerifiedAs {
+        VerifiedAs {
+            height: arity,
+            upvalues,
+            is_function: true,
+        }
+    }
+}
+
+/// A constant compared the way deduplication needs: numbers by their bits,
+/// so `0` and `-0` stay apart, and strings by content.
+#[derive(Debug, PartialEq, Eq, Hash)]
+enum ConstantKey {
+    Null,
+    Bool(bool),
+    Number(u64),
+    String(Rc<str>),
+}
+
+impl ConstantKey {
+    fn new(value: &Value) -> Option<ConstantKey> {
+        match value.kind() {
+            ValueKind::Null => Some(ConstantKey::Null),
+            ValueKind::Bool(value) => Some(ConstantKey::Bool(value)),
+            ValueKind::Number(value) => Some(ConstantKey::Number(value.to_bits())),
+            ValueKind::String(value) => Some(ConstantKey::String(value)),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct SpanRun {
+    start: u32,
+    line: u32,
+    column: u32,
 }
 
 impl Chunk {
     pub fn new() -> Chunk {
-        Chunk {
-            code: vec![],
-            constants: vec![],
-            spans: vec![],
-        }
+        Chunk::default()
     }
+    /// Appends an instruction compiled from the source at `span`.
+    ///
+    /// Panics if the operand doesn't fit the instruction's encoding, which
+    /// `OpCode::fits` tells beforehand.
     pub fn write(&mut self, opcode: OpCode, span: Span) {
-        self.code.push(opcode);
-        self.spans.push(span);
+        assert!(opcode.fits(), "{opcode:?} doesn't fit its encoding");
+        self.verified_stack.set(None);
+        let (tag, operand) = opcode.encode();
+        let run = SpanRun {
+            start: narrow(self.code.len()),
+            line: narrow(span.line),
+            column: narrow(span.column),
+        };
+        if self
+            .spans
+            .last()
+            .is_none_or(|last| (last.line, last.column) != (run.line, run.column))
+        {
+            self.spans.push(run);
+        }
+        self.code.push(tag);
+        self.code
+            .extend_from_slice(&operand.to_le_bytes()[..opcode.size() - 1]);
     }
+    /// Adds a constant to the pool, returning its index. Numbers, strings,
+    /// booleans and `null` already in the pool are reused instead.
     pub fn add_constant(&mut self, value: Value) -> usize {
+        let interned = ConstantKey::new(&value).and_then(|key| self.interned.get(&key));
+        if let Some(&index) = interned {
+            self.reused += 1;
+            return index;
+        }
+        self.push_constant(value)
+    }
+    /// Adds a constant at the end of the pool even if it's already there,
+    /// for rebuilding a pool exactly.
+    pub fn push_constant(&mut self, value: Value) -> usize {
+        let index = self.constants.len();
+        if let Some(key) = ConstantKey::new(&value) {
+            self.interned.entry(key).or_insert(index);
+        }
         self.constants.push(value);
-        self.constants.len() - 1
+        index
+    }
+    /// Replaces the instruction at `offset` with one of the same size, as
+    /// when a jump's target becomes known.
+    pub(crate) fn patch(&mut self, offset: usize, opcode: OpCode) {
+        let size = self.decode(offset).size();
+        assert!(opcode.size() == size && opcode.fits());
+        self.verified_stack.set(None);
+        let (tag, operand) = opcode.encode();
+        self.code[offset] = tag;
+        self.code[offset + 1..offset + size].copy_from_slice(&operand.to_le_bytes()[..size - 1]);
+    }
+    /// Drops the code from `offset` on, which must be where an instruction
+    /// starts. Constants stay.
+    pub(crate) fn truncate(&mut self, offset: usize) {
+        self.verified_stack.set(None);
+        self.code.truncate(offset);
+        self.spans.retain(|run| (run.start as usize) < offset);
+    }
+    /// The encoded instructions, each an opcode byte followed by its
+    /// operand in little-endian order.
+    pub fn code(&self) -> &[u8] {
+        &self.code
+    }
+    /// The instruction starting at `offset`.
+    pub fn decode(&self, offset: usize) -> OpCode {
+        self.read(offset).0
+    }
+    /// The instruction starting at `offset` and its size, so the offset
+    /// of the next one is known too.
+    #[inline]
+    pub fn read(&self, offset: usize) -> (OpCode, usize) {
+        let code = &self.code[offset..];
+        decode_with(|i| code[i] as usize)
+    }
+    /// Like `read`, without checking that `offset` is in the code.
+    ///
+    /// # Safety
+    ///
+    /// The chunk must have been verified, and `offset` must be where an
+    /// instruction the verifier reached starts.
+    #[inline(always)]
+    pub(crate) unsafe fn read_unchecked(&self, offset: usize) -> (OpCode, usize) {
+        decode_with(|i| *self.code.get_unchecked(offset + i) as usize)
+    }
+    /// Every instruction with its offset, in order.
+    pub fn instructions(&self) -> impl Iterator<Item = (usize, OpCode)> + '_ {
+        let mut offset = 0;
+        std::iter::from_fn(move || {
+            let (opcode, size) = (offset < self.code.len()).then(|| self.read(offset))?;
+            let item = (offset, opcode);
+            offset += size;
+            Some(item)
+        })
+    }
+    /// How many stack slots the code needs, if the verifier has passed it
+    /// as run `like` since it last changed.
+    pub(crate) fn verified_stack(&self, like: VerifiedAs) -> Option<usize> {
+        match self.verified_stack.get() {
+            Some((verified, slots)) if verified == like => Some(slots),
+            _ => None,
+        }
+    }
+    pub(crate) fn set_verified_stack(&self, verified: VerifiedAs, slots: usize) {
+        self.verified_stack.set(Some((verified, slots)));
+    }
+    pub fn constants(&self) -> &[Value] {
+        &self.constants
     }
+    /// How many constants `add_constant` didn't have to add because they
+    /// were already in the pool.
+    pub fn reused_constants(&self) -> usize {
+        self.reused
+    }
+    /// The source position of the instruction covering `offset`, found by
+    /// binary search.
+    pub fn span_at(&self, offset: usize) -> Span {
+        assert!(offset < self.code.len(), "offset {offset} is past the code");
+        let run = self
+            .spans
+            .partition_point(|run| run.start as usize <= offset);
+        let run = self.spans[run - 1];
+        Span {
+            line: run.line as usize,
+            column: run.column as usize,
+        }
+    }
+}
+
+/// Decodes the instruction whose bytes `byte` returns, from the opcode at
+/// 0 on, and its size.
+#[inline(always)]
+fn decode_with(byte: impl Fn(usize) -> usize) -> (OpCode, usize) {
+    let short = || byte(1) | byte(2) << 8;
+    let long = || byte(1) | byte(2) << 8 | byte(3) << 16;
+    match byte(0) {
+        0 => (OpCode::Constant(byte(1)), 2),
+        1 => (OpCode::ConstantLong(long()), 4),
+        2 => (OpCode::Null, 1),
+        3 => (OpCode::True, 1),
+        4 => (OpCode::False, 1),
+        5 => (OpCode::Pop, 1),
+        6 => (OpCode::GetLocal(byte(1)), 2),
+        7 => (OpCode::SetLocal(byte(1)), 2),
+        8 => (OpCode::GetGlobal(long()), 4),
+        9 => (OpCode::DefineGlobal(long()), 4),
+        10 => (OpCode::SetGlobal(long()), 4),
+        11 => (OpCode::GetUpvalue(byte(1)), 2),
+        12 => (OpCode::SetUpvalue(byte(1)), 2),
+        13 => (OpCode::GetProperty(long()), 4),
+        14 => (OpCode::SetProperty(long()), 4),
+        15 => (OpCode::Equal, 1),
+        16 => (OpCode::Greater, 1),
+        17 => (OpCode::Less, 1),
+        18 => (OpCode::Add, 1),
+        19 => (OpCode::Subtract, 1),
+        20 => (OpCode::Multiply, 1),
+        21 => (OpCode::Divide, 1),
+        22 => (OpCode::Modulo, 1),
+        23 => (OpCode::BuildList(short()), 3),
+        24 => (OpCode::Index, 1),
+        25 => (OpCode::Jump(short()), 3),
+        26 => (OpCode::JumpIfFalse(short()), 3),
+        27 => (OpCode::Loop(short()), 3),
+        28 => (OpCode::Call(byte(1)), 2),
+        29 => (OpCode::Closure(long()), 4),
+        30 => (OpCode::CloseUpvalue, 1),
+        31 => (OpCode::Not, 1),
+        32 => (OpCode::Negate, 1),
+        33 => (OpCode::Print, 1),
+        34 => (OpCode::Return, 1),
+        35 => (OpCode::AddLocalConst(byte(1), byte(2)), 3),
+        36 => (OpCode::IncrementLocal(byte(1)), 2),
+        37 => (OpCode::JumpIfNotLess(short()), 3),
+        _ => unreachable!("chunks only hold encoded instructions"),
+    }
+}
+
+/// Sources with more than 4 billion lines or columns get clamped
+/// positions.
+fn narrow(value: usize) -> u32 {
+    u32::try_from(value).unwrap_or(u32::MAX)
 }
 
+/// An instruction, decoded. In a chunk it takes a byte, plus one to three
+/// for the operand: constants up to 255 and locals, upvalues and argument
+/// counts take one, jumps and list lengths two, and any constant three.
+/// `AddLocalConst` has a byte for each of its two operands.
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum OpCode {
     Constant(usize),
+    /// `Constant`, for pools with more than 256 constants.
+    ConstantLong(usize),
+    Null,
+    True,
+    False,
+    Pop,
+    GetLocal(usize),
+    SetLocal(usize),
+    GetGlobal(usize),
+    DefineGlobal(usize),
+    SetGlobal(usize),
+    GetUpvalue(usize),
+    SetUpvalue(usize),
+    GetProperty(usize),
+    SetProperty(usize),
+    Equal,
+    Greater,
+    Less,
     Add,
     Subtract,
     Multiply,
     Divide,
+    Modulo,
+    BuildList(usize),
+    Index,
+    /// Skips forward over that many bytes.
+    Jump(usize),
+    /// Like `Jump`, when the value on top of the stack, which is left
+    /// there, is falsey.
+    JumpIfFalse(usize),
+    /// Jumps back that many bytes, counting from the end of the
+    /// instruction.
+    Loop(usize),
+    Call(usize),
+    Closure(usize),
+    CloseUpvalue,
+    Not,
     Negate,
+    Print,
     Return,
+    /// `GetLocal`, `Constant` and `Add` fused by the peephole pass: pushes
+    /// the local in the first slot plus the constant at the second index.
+    AddLocalConst(usize, usize),
+    /// Adds one to a number in a local slot, for `i = i + 1;`.
+    IncrementLocal(usize),
+    /// `Less` and `JumpIfFalse` fused, with the `Pop`s after them on both
+    /// paths: pops two numbers and jumps unless the first is less.
+    JumpIfNotLess(usize),
 }
 
+impl OpCode {
+    /// How many bytes the instruction takes in a chunk.
+    pub fn size(self) -> usize {
+        1 + OpCode::operand_size(self.encode().0)
+    }
+    /// Whether the operand fits in the bytes its encoding has for it.
+    pub fn fits(self) -> bool {
+        if let OpCode::AddLocalConst(slot, index) = self {
+            return slot < 256 && index < 256;
+        }
+        let (tag, operand) = self.encode();
+        operand < 1 << (8 * OpCode::operand_size(tag))
+    }
+    #[inline]
+    fn operand_size(tag: u8) -> usize {
+        match tag {
+            0 | 6 | 7 | 11 | 12 | 28 | 36 => 1,
+            23 | 25..=27 | 35 | 37 => 2,
+            1 | 8..=10 | 13 | 14 | 29 => 3,
+            _ => 0,
+        }
+    }
+    fn encode(self) -> (u8, usize) {
+        match self {
+            OpCode::Constant(index) => (0, index),
+            OpCode::ConstantLong(index) => (1, index),
+            OpCode::Null => (2, 0),
+            OpCode::True => (3, 0),
+            OpCode::False => (4, 0),
+            OpCode::Pop => (5, 0),
+            OpCode::GetLocal(slot) => (6, slot),
+            OpCode::SetLocal(slot) => (7, slot),
+            OpCode::GetGlobal(index) => (8, index),
+            OpCode::DefineGlobal(index) => (9, index),
+            OpCode::SetGlobal(index) => (10, index),
+            OpCode::GetUpvalue(index) => (11, index),
+            OpCode::SetUpvalue(index) => (12, index),
+            OpCode::GetProperty(index) => (13, index),
+            OpCode::SetProperty(index) => (14, index),
+            OpCode::Equal => (15, 0),
+            OpCode::Greater => (16, 0),
+            OpCode::Less => (17, 0),
+            OpCode::Add => (18, 0),
+            OpCode::Subtract => (19, 0),
+            OpCode::Multiply => (20, 0),
+            OpCode::Divide => (21, 0),
+            OpCode::Modulo => (22, 0),
+            OpCode::BuildList(count) => (23, count),
+            OpCode::Index => (24, 0),
+            OpCode::Jump(distance) => (25, distance),
+            OpCode::JumpIfFalse(distance) => (26, distance),
+            OpCode::Loop(distance) => (27, distance),
+            OpCode::Call(count) => (28, count),
+            OpCode::Closure(index) => (29, index),
+            OpCode::CloseUpvalue => (30, 0),
+            OpCode::Not => (31, 0),
+            OpCode::Negate => (32, 0),
+            OpCode::Print => (33, 0),
+            OpCode::Return => (34, 0),
+            OpCode::AddLocalConst(slot, index) => (35, slot | index << 8),
+            OpCode::IncrementLocal(slot) => (36, slot),
+            OpCode::JumpIfNotLess(distance) => (37, distance),
+        }
+    }
+}
+
+/// A 1-based position in the source.
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub struct Span {
     pub line: usize,
     pub column: usize,
 }
+
+impl Span {
+    /// The position of the byte at `offset` in `source`.
+    pub fn at(source: &str, offset: usize) -> Span {
+        let before = &source[..offset.min(source.len())];
+        let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+        Span {
+            line: before.matches('\n').count() + 1,
+            column: offset - line_start + 1,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::compile;
+
+    #[test]
+    fn span_table_reproduces_every_span() {
+        // What the chunk used to store: one span per byte.
+        let mut expected = vec![];
+        let mut chunk = Chunk::new();
+        let mut x: u64 = 0x9e37_79b9_7f4a_7c15;
+        for _ in 0..1000 {
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            let opcode = match x % 4 {
+                0 => OpCode::Add,
+                1 => OpCode::Constant(x as usize % 256),
+                2 => OpCode::Jump(x as usize % 65536),
+                _ => OpCode::GetGlobal(x as usize % 70000),
+            };
+            // Few distinct positions, so runs form.
+            let span = Span {
+                line: (x >> 8) as usize % 3 + 1,
+                column: (x >> 16) as usize % 2 + 1,
+            };
+            chunk.write(opcode, span);
+            expected.resize(chunk.code().len(), span);
+        }
+        for (offset, span) in expected.iter().enumerate() {
+            assert_eq!(chunk.span_at(offset), *span, "at offset {offset}");
+        }
+    }
+
+    #[test]
+    fn numbers_are_deduplicated_bitwise() {
+        let mut chunk = Chunk::new();
+        let zero = chunk.add_constant(Value::Number(0.0));
+        assert_ne!(chunk.add_constant(Value::Number(-0.0)), zero);
+        let nan = chunk.add_constant(Value::Number(f64::NAN));
+        assert_eq!(chunk.add_constant(Value::Number(f64::NAN)), nan);
+        assert_eq!(chunk.add_constant(Value::Number(0.0)), zero);
+        assert_eq!(chunk.reused_constants(), 2);
+    }
+
+    #[test]
+    fn runs_are_shared_by_instructions_from_one_token() {
+        let chunk = compile("let a = 1;\nprint a != 2;").unwrap();
+        // `!=` compiles to `Equal` and `Not` at the same position.
+        assert!(chunk.spans.len() < chunk.instructions().count());
+        assert_eq!(chunk.span_at(0), Span { line: 1, column: 9 });
+        let last = chunk.code().len() - 1;
+        assert_eq!(
+            chunk.span_at(last),
+            Span {
+                line: 2,
+                column: 13
+            }
+        );
+    }
+}
@@ -0,0 +1,355 @@
+use std::{
+    any::Any,
+    cell::{Ref, RefCell, RefMut},
+    collections::HashMap,
+    fmt,
+    marker::PhantomData,
+    rc::Rc,
+};
+
+use crate::{
+    value::Value,
+    vm::{RuntimeError, Vm},
+};
+
+type Constructor = Box<dyn Fn(&mut Vm, &[Value]) -> Result<Box<dyn Any>, RuntimeError>>;
+type Method = Box<dyn Fn(&mut Vm, &mut dyn Any, &[Value]) -> Result<Value, RuntimeError>>;
+type Getter = Box<dyn Fn(&dyn Any) -> Value>;
+type Setter = Box<dyn Fn(&mut dyn Any, &Value) -> Result<(), RuntimeError>>;
+type DropHook = Box<dyn Fn(&mut dyn Any)>;
+
+/// A Rust type exposed to scripts as a class, built with `ClassBuilder`.
+/// Calling the class runs its constructor and gives the script an opaque
+/// `Userdata` object.
+pub struct NativeClass {
+    name: Rc<str>,
+    constructor: Option<(usize, Constructor)>,
+    methods: HashMap<Rc<str>, (usize, Method)>,
+    getters: HashMap<Rc<str>, Getter>,
+    setters: HashMap<Rc<str>, Setter>,
+    on_drop: Option<DropHook>,
+}
+
+impl NativeClass {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    /// The names of its getters and methods, in no particular order.
+    pub fn members(&self) -> impl Iterator<Item = &str> {
+        self.getters
+            .keys()
+            .chain(self.methods.keys())
+            .map(|name| &**name)
+    }
+    pub(crate) fn construct(
+        self: &Rc<NativeClass>,
+        vm: &mut Vm,
+        arguments: &[Value],
+    ) -> Result<Value, RuntimeError> {
+        let Some((_, constructor)) = &self.constructor else {
+            return Err(RuntimeError::new(format!(
+                "Class {} can't be constructed from scripts.",
+                self.name
+            )));
+        };
+        let data = constructor(vm, arguments)?;
+        Ok(Value::Userdata(Rc::new(Userdata {
+            class: self.clone(),
+            data: RefCell::new(data),
+        })))
+    }
+    pub(crate) fn constructor_arity(&self) -> usize {
+        self.constructor.as_ref().map_or(0, |(arity, _)| *arity)
+    }
+    pub(crate) fn method_arity(&self, name: &str) -> Option<usize> {
+        self.methods.get(name).map(|(arity, _)| *arity)
+    }
+}
+
+impl fmt::Debug for NativeClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "NativeClass({})", self.name)
+    }
+}
+
+impl PartialEq for NativeClass {
+    fn eq(&self, other: &NativeClass) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+/// Describes how scripts see the Rust type `T`. Every part is optional: a
+/// class without a constructor can only be instantiated from Rust.
+///
+/// ```
+/// use rabbit::{ClassBuilder, FromValue, IntoValue, RuntimeError, Value, Vm};
+///
+/// struct Counter(f64);
+///
+/// fn new(_vm: &mut Vm, arguments: &[Value]) -> Result<Counter, RuntimeError> {
+///     Ok(Counter(f64::from_value(&arguments[0])?))
+/// }
+///
+/// fn add(_vm: &mut Vm, counter: &mut Counter, arguments: &[Value]) -> Result<Value, RuntimeError> {
+///     counter.0 += f64::from_value(&arguments[0])?;
+///     Ok(Value::Null)
+/// }
+///
+/// let mut vm = Vm::new();
+/// vm.register_class(
+///     ClassBuilder::new("Counter")
+///         .constructor(1, new)
+///         .method("add", 1, add)
+///         .getter("count", |counter| counter.0.into_value()),
+/// );
+/// ```
+pub struct ClassBuilder<T> {
+    class: NativeClass,
+    marker: PhantomData<T>,
+}
+
+impl<T: 'static> ClassBuilder<T> {
+    pub fn new(name: &str) -> ClassBuilder<T> {
+        ClassBuilder {
+            class: NativeClass {
+                name: Rc::from(name),
+                constructor: None,
+                methods: HashMap::new(),
+                getters: HashMap::new(),
+                setters: HashMap::new(),
+                on_drop: None,
+            },
+            marker: PhantomData,
+        }
+    }
+    /// Run when a script calls the class with `arity` arguments.
+    pub fn constructor(
+        mut self,
+        arity: usize,
+        function: fn(&mut Vm, &[Value]) -> Result<T, RuntimeError>,
+    ) -> Self {
+        let constructor: Constructor = Box::new(move |vm, arguments| {
+            function(vm, arguments).map(|data| Box::new(data) as Box<dyn Any>)
+        });
+        self.class.constructor = Some((arity, constructor));
+        self
+    }
+    /// Callable from scripts as `object.name(...)`.
+    pub fn method(
+        mut self,
+        name: &str,
+        arity: usize,
+        function: fn(&mut Vm, &mut T, &[Value]) -> Result<Value, RuntimeError>,
+    ) -> Self {
+        let method: Method =
+            Box::new(move |vm, data, arguments| function(vm, downcast_mut(data), arguments));
+        self.class.methods.insert(Rc::from(name), (arity, method));
+        self
+    }
+    /// Read by scripts as `object.name`.
+    pub fn getter(mut self, name: &str, function: fn(&T) -> Value) -> Self {
+        let getter: Getter = Box::new(move |data| function(data.downcast_ref().unwrap()));
+        self.class.getters.insert(Rc::from(name), getter);
+        self
+    }
+    /// Run when a script assigns to `object.name`.
+    pub fn setter(
+        mut self,
+        name: &str,
+        function: fn(&mut T, &Value) -> Result<(), RuntimeError>,
+    ) -> Self {
+        let setter: Setter = Box::new(move |data, value| function(downcast_mut(data), value));
+        self.class.setters.insert(Rc::from(name), setter);
+        self
+    }
+    /// Run on an object when the last reference to it goes away, before
+    /// `T` itself is dropped.
+    pub fn on_drop(mut self, function: fn(&mut T)) -> Self {
+        self.class.on_drop = Some(Box::new(move |data| function(downcast_mut(data))));
+        self
+    }
+    pub fn build(self) -> NativeClass {
+        self.class
+    }
+}
+
+fn downcast_mut<T: 'static>(data: &mut dyn Any) -> &mut T {
+    data.downcast_mut()
+        .expect("userdata always holds the type of its class")
+}
+
+/// An instance of a `NativeClass`, wrapping a Rust value scripts can only
+/// reach through the class's methods and properties. Values are reference
+/// counted, so the drop hook runs as soon as no script or Rust code holds
+/// the object anymore.
+pub struct Userdata {
+    class: Rc<NativeClass>,
+    data: RefCell<Box<dyn Any>>,
+}
+
+impl Userdata {
+    pub fn class(&self) -> &Rc<NativeClass> {
+        &self.class
+    }
+    /// The wrapped value, if it is a `T` and not being mutated.
+    pub fn borrow<T: 'static>(&self) -> Option<Ref<'_, T>> {
+        let data = self.data.try_borrow().ok()?;
+        Ref::filter_map(data, |data| data.downcast_ref()).ok()
+    }
+    /// The wrapped value, if it is a `T` and not borrowed elsewhere.
+    pub fn borrow_mut<T: 'static>(&self) -> Option<RefMut<'_, T>> {
+        let data = self.data.try_borrow_mut().ok()?;
+        RefMut::filter_map(data, |data| data.downcast_mut()).ok()
+    }
+    /// Returns `Ok(None)` if there is no getter called `name`.
+    pub(crate) fn get(&self, name: &str) -> Result<Option<Value>, RuntimeError> {
+        let Some(getter) = self.class.getters.get(name) else {
+            return Ok(None);
+        };
+        let data = self.data.try_borrow().map_err(|_| self.in_use())?;
+        Ok(Some(getter(&**data)))
+    }
+    pub(crate) fn has_getter(&self, name: &str) -> bool {
+        self.class.getters.contains_key(name)
+    }
+    pub(crate) fn has_method(&self, name: &str) -> bool {
+        self.class.methods.contains_key(name)
+    }
+    /// Returns `Ok(false)` if there is no setter called `name`.
+    pub(crate) fn set(&self, name: &str, value: &Value) -> Result<bool, RuntimeError> {
+        let Some(setter) = self.class.setters.get(name) else {
+            return Ok(false);
+        };
+        let mut data = self.borrow_data()?;
+        setter(&mut **data, value)?;
+        Ok(true)
+    }
+    pub(crate) fn invoke(
+        &self,
+        vm: &mut Vm,
+        name: &str,
+        arguments: &[Value],
+    ) -> Result<Value, RuntimeError> {
+        let (_, method) = &self.class.methods[name];
+        let mut data = self.borrow_data()?;
+        method(vm, &mut **data, arguments)
+    }
+    fn borrow_data(&self) -> Result<RefMut<'_, Box<dyn Any>>, RuntimeError> {
+        self.data.try_borrow_mut().map_err(|_| self.in_use())
+    }
+    fn in_use(&self) -> RuntimeError {
+        RuntimeError::new(format!("{} object is already in use.", self.class.name))
+    }
+}
+
+impl Drop for Userdata {
+    fn drop(&mut self) {
+        if let Some(on_drop) = &self.class.on_drop {
+            on_drop(&mut **self.data.get_mut());
+        }
+    }
+}
+
+impl fmt::Debug for Userdata {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Userdata({})", self.class.name)
+    }
+}
+
+impl PartialEq for Userdata {
+    fn eq(&self, other: &Userdata) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use super::*;
+    use crate::{
+        compiler::compile,
+        error::RabbitError,
+        value::{FromValue, IntoValue, ValueKind},
+    };
+
+    thread_local! {
+        static CLOSED: Cell<usize> = const { Cell::new(0) };
+    }
+
+    struct File {
+        path: String,
+        lines: Vec<String>,
+    }
+
+    fn open(_vm: &mut Vm, arguments: &[Value]) -> Result<File, RuntimeError> {
+        Ok(File {
+            path: String::from_value(&arguments[0])?,
+            lines: vec![],
+        })
+    }
+
+    fn write(_vm: &mut Vm, file: &mut File, arguments: &[Value]) -> Result<Value, RuntimeError> {
+        file.lines.push(String::from_value(&arguments[0])?);
+        Ok(file.lines.len().to_string().into_value())
+    }
+
+    fn class() -> ClassBuilder<File> {
+        ClassBuilder::new("File")
+            .constructor(1, open)
+            .method("write", 1, write)
+            .getter("path", |file| file.path.as_str().into_value())
+            .getter("lines", |file| file.lines.clone().into_value())
+            .setter("path", |file, value| {
+                file.path = String::from_value(value)?;
+                Ok(())
+            })
+            .on_drop(|_| CLOSED.with(|closed| closed.set(closed.get() + 1)))
+    }
+
+    fn run(vm: &mut Vm, source: &str) -> Result<Value, RabbitError> {
+        vm.interpret(compile(source).unwrap())
+    }
+
+    #[test]
+    fn classes_expose_rust_values() {
+        let mut vm = Vm::new();
+        vm.register_class(class());
+        let source = "let f = File(\"a\"); let w = f.write; w(\"x\"); let n = f.write(\"y\"); f.path = f.path + \"b\";";
+        assert!(run(&mut vm, source).is_ok());
+        let globals: HashMap<&str, &Value> = vm.globals().collect();
+        assert_eq!(globals["n"], &Value::String(Rc::from("2")));
+        let ValueKind::Userdata(file) = globals["f"].kind() else {
+            panic!("expected an object");
+        };
+        let file = file.borrow::<File>().unwrap();
+        assert_eq!((file.path.as_str(), file.lines.len()), ("ab", 2));
+        assert_eq!(globals["f"].to_string(), "<File object>");
+    }
+
+    #[test]
+    fn misuse_is_a_runtime_error() {
+        let mut vm = Vm::new();
+        vm.register_class(class());
+        assert!(run(&mut vm, "File();").is_err());
+        assert!(run(&mut vm, "File(1);").is_err());
+        assert!(run(&mut vm, "let f = File(\"a\");").is_ok());
+        assert!(run(&mut vm, "f.size;").is_err());
+        assert!(run(&mut vm, "f.lines = [];").is_err());
+        assert!(run(&mut vm, "f.write();").is_err());
+        assert!(run(&mut vm, "1.5.x;").is_err());
+    }
+
+    #[test]
+    fn drop_hook_runs_when_the_last_reference_goes() {
+        let mut vm = Vm::new();
+        vm.register_class(class());
+        let before = CLOSED.with(Cell::get);
+        assert!(run(&mut vm, "{ let f = File(\"a\"); f.write(\"x\"); }").is_ok());
+        assert_eq!(CLOSED.with(Cell::get), before + 1);
+        assert!(run(&mut vm, "let f = File(\"a\");").is_ok());
+        assert_eq!(CLOSED.with(Cell::get), before + 1);
+        vm.reset();
+        assert_eq!(CLOSED.with(Cell::get), before + 2);
+    }
+}
@@ -0,0 +1,280 @@
+use std::path::Path;
+
+pub const USAGE: &str = "\
+Usage: rabbit [options] [command] [args...]
+
+Commands:
+  run <file> [args...]                Run a script, `-` reading it from stdin
+  check <file>                        Report compile errors without running
+  compile <file> [-o <out>]           Save bytecode to <out>, file.rbc by default
+  repl                                Start an interactive session (default)
+  tokens [--format=text|json] <file>  Print the tokens of a script
+  lsp                                 Start a language server on stdin/stdout
+  <file> [args...]                    Same as `run`
+
+Files starting like bytecode saved by `compile` are run without compiling.
+
+Options:
+  -e <code> [args...]                 Run code given on the command line
+  --trace                             Print the stack and each instruction run
+  --trace-file=<path>                 Like --trace, writing to a file instead
+  --disassemble                       Print the bytecode before running it
+  -h, --help                          Print this message
+  -V, --version                       Print the version
+
+Arguments after the script are available to it as the `args` list.";
+
+#[derive(Debug, PartialEq)]
+pub enum Source {
+    File(String),
+    Stdin,
+    Code(String),
+}
+
+impl Source {
+    fn from_path(path: &str) -> Source {
+        match path {
+            "-" => Source::Stdin,
+            path => Source::File(path.to_string()),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum TokensFormat {
+    Text,
+    Json,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct DebugOptions {
+    pub trace: bool,
+    pub trace_file: Option<String>,
+    pub disassemble: bool,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Run {
+        source: Source,
+        args: Vec<String>,
+    },
+    Check(Source),
+    Compile {
+        source: Source,
+        output: String,
+    },
+    Repl,
+    Tokens {
+        format: TokensFormat,
+        source: Source,
+    },
+    Lsp,
+    Help,
+    Version,
+}
+
+/// Parses the command line, `args` excluding the program name. Errors are
+/// messages meant to be printed above the usage. Debug options go before
+/// the command, or right after `run`, `check`, `compile` and `repl`.
+pub fn parse(args: &[String]) -> Result<(Command, DebugOptions), String> {
+    let mut options = DebugOptions::default();
+    let args = take_debug_options(args, &mut options)?;
+    let Some((first, rest)) = args.split_first() else {
+        return Ok((Command::Repl, options));
+    };
+    let rest = match first.as_str() {
+        "run" | "check" | "compile" | "repl" => take_debug_options(rest, &mut options)?,
+        _ => rest,
+    };
+    let command = match first.as_str() {
+        "-h" | "--help" | "help" => Command::Help,
+        "-V" | "--version" => Command::Version,
+        "-e" => {
+            let (code, args) = rest.split_first().ok_or("Missing code after -e.")?;
+            Command::Run {
+                source: Source::Code(code.clone()),
+                args: args.to_vec(),
+            }
+        }
+        "run" => {
+            let (path, args) = rest.split_first().ok_or("Missing script to run.")?;
+            Command::Run {
+                source: Source::from_path(path),
+                args: args.to_vec(),
+            }
+        }
+        "check" => match rest {
+            [path] => Command::Check(Source::from_path(path)),
+            _ => return Err(String::from("Expected exactly one script to check.")),
+        },
+        "compile" => {
+            let (source, output) = match rest {
+                [path] => (path, None),
+                [path, flag, output] | [flag, output, path] if flag == "-o" => {
+                    (path, Some(output.clone()))
+                }
+                _ => return Err(String::from("Expected a script and optionally -o <file>.")),
+            };
+            let source = Source::from_path(source);
+            let output = match (output, &source) {
+                (Some(output), _) => output,
+                (None, Source::File(path)) => {
+                    Path::new(path).with_extension("rbc").display().to_string()
+                }
+                (None, _) => {
+                    return Err(String::from("Missing -o <file> for bytecode from stdin."))
+                }
+            };
+            Command::Compile { source, output }
+        }
+        "repl" if rest.is_empty() => Command::Repl,
+        "lsp" if rest.is_empty() => Command::Lsp,
+        "repl" | "lsp" => return Err(format!("`{first}` takes no arguments.")),
+        "tokens" => {
+            let mut format = TokensFormat::Text;
+            let mut source = None;
+            for arg in rest {
+                match arg.strip_prefix("--format=") {
+                    Some("text") => format = TokensFormat::Text,
+                    Some("json") => format = TokensFormat::Json,
+                    Some(other) => return Err(format!("Unknown token format '{other}'.")),
+                    None if source.is_none() => source = Some(Source::from_path(arg)),
+                    None => return Err(String::from("Expected exactly one script.")),
+                }
+            }
+            let source = source.ok_or("Missing script to tokenize.")?;
+            Command::Tokens { format, source }
+        }
+        option if option.starts_with('-') && option != "-" => {
+            return Err(format!("Unknown option '{option}'."))
+        }
+        path => Command::Run {
+            source: Source::from_path(path),
+            args: rest.to_vec(),
+        },
+    };
+    Ok((command, options))
+}
+
+fn take_debug_options<'a>(
+    mut args: &'a [String],
+    options: &mut DebugOptions,
+) -> Result<&'a [String], String> {
+    while let Some((first, rest)) = args.split_first() {
+        match first.as_str() {
+            "--trace" => options.trace = true,
+            "--disassemble" => options.disassemble = true,
+            arg => match arg.strip_prefix("--trace-file=") {
+                Some("") => return Err(String::from("Missing path after --trace-file=.")),
+                Some(path) => {
+                    options.trace = true;
+                    options.trace_file = Some(path.to_string());
+                }
+                None => break,
+            },
+        }
+        args = rest;
+    }
+    Ok(args)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_str(args: &str) -> Result<Command, String> {
+        let args: Vec<String> = args.split_whitespace().map(str::to_string).collect();
+        parse(&args).map(|(command, _)| command)
+    }
+
+    fn options(args: &str) -> DebugOptions {
+        let args: Vec<String> = args.split_whitespace().map(str::to_string).collect();
+        parse(&args).unwrap().1
+    }
+
+    #[test]
+    fn commands_are_parsed() {
+        assert_eq!(parse_str(""), Ok(Command::Repl));
+        assert_eq!(parse_str("--version"), Ok(Command::Version));
+        assert_eq!(
+            parse_str("run - a b"),
+            Ok(Command::Run {
+                source: Source::Stdin,
+                args: vec![String::from("a"), String::from("b")]
+            })
+        );
+        assert_eq!(
+            parse_str("main.rb --flag"),
+            Ok(Command::Run {
+                source: Source::File(String::from("main.rb")),
+                args: vec![String::from("--flag")]
+            })
+        );
+        assert_eq!(
+            parse_str("-e print(1); x"),
+            Ok(Command::Run {
+                source: Source::Code(String::from("print(1);")),
+                args: vec![String::from("x")]
+            })
+        );
+        assert_eq!(
+            parse_str("tokens --format=json a.rb"),
+            Ok(Command::Tokens {
+                format: TokensFormat::Json,
+                source: Source::File(String::from("a.rb"))
+            })
+        );
+        assert_eq!(
+            parse_str("check a.rb"),
+            Ok(Command::Check(Source::File(String::from("a.rb"))))
+        );
+        assert_eq!(
+            parse_str("compile src/a.rb"),
+            Ok(Command::Compile {
+                source: Source::File(String::from("src/a.rb")),
+                output: String::from("src/a.rbc")
+            })
+        );
+        assert_eq!(
+            parse_str("compile -o out.rbc -"),
+            Ok(Command::Compile {
+                source: Source::Stdin,
+                output: String::from("out.rbc")
+            })
+        );
+    }
+
+    #[test]
+    fn debug_options_are_parsed() {
+        assert_eq!(
+            options("--disassemble run --trace-file=out.txt a.rb --trace"),
+            DebugOptions {
+                trace: true,
+                trace_file: Some(String::from("out.txt")),
+                disassemble: true,
+            }
+        );
+        assert_eq!(
+            parse_str("run --trace a.rb --trace"),
+            Ok(Command::Run {
+                source: Source::File(String::from("a.rb")),
+                args: vec![String::from("--trace")]
+            })
+        );
+        assert!(options("repl --trace").trace);
+        assert!(parse_str("--trace-file= a.rb").is_err());
+    }
+
+    #[test]
+    fn bad_usage_is_rejected() {
+        assert!(parse_str("-e").is_err());
+        assert!(parse_str("run").is_err());
+        assert!(parse_str("check a.rb b.rb").is_err());
+        assert!(parse_str("compile -").is_err());
+        assert!(parse_str("compile a.rb -x b.rbc").is_err());
+        assert!(parse_str("repl x").is_err());
+        assert!(parse_str("tokens --format=xml a.rb").is_err());
+        assert!(parse_str("--frobnicate").is_err());
+    }
+}
@@ -0,0 +1,1277 @@
+use std::rc::Rc;
+
+use crate::{
+    chunk::{Chunk, OpCode, Span, MAX_CONSTANTS},
+    error::RabbitError,
+    function::{Capture, Function},
+    peephole,
+    scanner::{Diagnostic, Scanner, Token, TokenType},
+    value::{IntoValue, Value, ValueKind},
+};
+
+/// How many locals and captured variables a function can have, as their
+/// slots are encoded in a byte.
+const MAX_LOCALS: usize = 256;
+/// How many arguments a call can pass, its count being a byte too.
+const MAX_ARGUMENTS: usize = 255;
+
+/// Compiles a whole script, failing with every error found.
+pub fn compile(source: &str) -> Result<Chunk, RabbitError> {
+    compile_with(source, false)
+}
+
+/// Compiles a REPL entry. Unlike scripts, the value of a final bare
+/// expression, whose semicolon is optional, is left for the VM to echo.
+pub fn compile_repl(source: &str) -> Result<Chunk, RabbitError> {
+    compile_with(source, true)
+}
+
+fn compile_with(source: &str, echo: bool) -> Result<Chunk, RabbitError> {
+    let tokens = Scanner::new(source).scan_tokens()?;
+    let (chunk, errors) = Compiler::new(source, tokens, echo).compile();
+    if errors.is_empty() {
+        Ok(chunk)
+    } else {
+        Err(RabbitError::Compile(errors))
+    }
+}
+
+/// Returns every scanner error, or every compiler error when the source
+/// scans cleanly, without printing anything.
+pub fn check(source: &str) -> Vec<Diagnostic> {
+    let (tokens, errors) = Scanner::new(source).scan();
+    if !errors.is_empty() {
+        return errors;
+    }
+    Compiler::new(source, tokens, false).compile().1
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+enum Precedence {
+    None,
+    Assignment,
+    Or,
+    And,
+    Equality,
+    Comparison,
+    Term,
+    Factor,
+    Unary,
+    Call,
+    Primary,
+}
+
+impl Precedence {
+    fn next(self) -> Precedence {
+        match self {
+            Precedence::None => Precedence::Assignment,
+            Precedence::Assignment => Precedence::Or,
+            Precedence::Or => Precedence::And,
+            Precedence::And => Precedence::Equality,
+            Precedence::Equality => Precedence::Comparison,
+            Precedence::Comparison => Precedence::Term,
+            Precedence::Term => Precedence::Factor,
+            Precedence::Factor => Precedence::Unary,
+            Precedence::Unary => Precedence::Call,
+            Precedence::Call | Precedence::Primary => Precedence::Primary,
+        }
+    }
+}
+
+type ParseFn<'a> = fn(&mut Compiler<'a>, bool);
+
+struct ParseRule<'a> {
+    prefix: Option<ParseFn<'a>>,
+    infix: Option<ParseFn<'a>>,
+    precedence: Precedence,
+}
+
+struct Local<'a> {
+    name: &'a str,
+    /// `None` while the variable's initializer is being compiled.
+    depth: Option<usize>,
+    /// Whether a closure captures the variable, which then has to be moved
+    /// off the stack when it goes out of scope.
+    captured: bool,
+}
+
+/// A function being compiled. Each nested declaration pushes a new one.
+struct FunctionState<'a> {
+    name: Option<Rc<str>>,
+    arity: usize,
+    chunk: Chunk,
+    locals: Vec<Local<'a>>,
+    captures: Vec<Capture>,
+    scope_depth: usize,
+    /// Where each instruction starts, so the last few can be folded.
+    starts: Vec<usize>,
+    /// Whether the code being compiled can't run, being after a `return`.
+    unreachable: bool,
+}
+
+impl FunctionState<'_> {
+    fn new(name: Option<Rc<str>>) -> Self {
+        FunctionState {
+            name,
+            arity: 0,
+            chunk: Chunk::new(),
+            locals: vec![],
+            captures: vec![],
+            scope_depth: 0,
+            starts: vec![],
+            unreachable: false,
+        }
+    }
+}
+
+struct Compiler<'a> {
+    source: &'a str,
+    tokens: Vec<Token>,
+    previous: usize,
+    current: usize,
+    line_starts: Vec<usize>,
+    functions: Vec<FunctionState<'a>>,
+    errors: Vec<Diagnostic>,
+    panic_mode: bool,
+    echo: bool,
+}
+
+impl<'a> Compiler<'a> {
+    fn new(source: &'a str, tokens: Vec<Token>, echo: bool) -> Compiler<'a> {
+        Compiler {
+            source,
+            tokens,
+            previous: 0,
+            current: 0,
+            line_starts: std::iter::once(0)
+                .chain(source.match_indices('\n').map(|(i, _)| i + 1))
+                .collect(),
+            functions: vec![FunctionState::new(None)],
+            errors: vec![],
+            panic_mode: false,
+            echo,
+        }
+    }
+    fn compile(mut self) -> (Chunk, Vec<Diagnostic>) {
+        while !self.match_token(TokenType::Eof) {
+            self.declaration();
+        }
+        self.emit(OpCode::Return);
+        let mut script = self.functions.pop().expect("the script is always compiled");
+        if self.errors.is_empty() {
+            peephole::optimize(&mut script.chunk);
+        }
+        (script.chunk, self.errors)
+    }
+
+    fn state(&mut self) -> &mut FunctionState<'a> {
+        self.functions
+            .last_mut()
+            .expect("the script is always compiled")
+    }
+    fn previous(&self) -> &Token {
+        &self.tokens[self.previous]
+    }
+    fn lexeme(&self, token: &Token) -> &'a str {
+        &self.source[token.span.clone()]
+    }
+    fn advance(&mut self) {
+        self.previous = self.current;
+        if self.current < self.tokens.len() - 1 {
+            self.current += 1;
+        }
+    }
+    fn check(&self, token_type: TokenType) -> bool {
+        self.tokens[self.current].token_type == token_type
+    }
+    fn match_token(&mut self, token_type: TokenType) -> bool {
+        if !self.check(token_type) {
+            return false;
+        }
+        self.advance();
+        true
+    }
+    fn consume(&mut self, token_type: TokenType, message: &str) {
+        if self.check(token_type) {
+            self.advance();
+        } else {
+            self.error_at(self.current, message);
+        }
+    }
+    fn error(&mut self, message: &str) {
+        self.error_at(self.previous, message);
+    }
+    fn error_at(&mut self, index: usize, message: &str) {
+        if self.panic_mode {
+            return;
+        }
+        self.panic_mode = true;
+        let token = self.tokens[index].clone();
+        let at = match token.token_type {
+            TokenType::Eof => String::from(" at end"),
+            _ => format!(" at '{}'", self.lexeme(&token)),
+        };
+        let span = self.span(&token);
+        self.errors.push(Diagnostic {
+            message: message.to_string(),
+            at,
+            token,
+            span,
+        });
+    }
+    fn synchronize(&mut self) {
+        self.panic_mode = false;
+        while !self.check(TokenType::Eof) {
+            if self.previous().token_type == TokenType::Semicolon {
+                return;
+            }
+            match self.tokens[self.current].token_type {
+                TokenType::Class
+                | TokenType::Fn
+                | TokenType::Let
+                | TokenType::For
+                | TokenType::If
+                | TokenType::While
+                | TokenType::Print
+                | TokenType::Return => return,
+                _ => self.advance(),
+            }
+        }
+    }
+
+    fn span(&self, token: &Token) -> Span {
+        let line = self
+            .line_starts
+            .partition_point(|&start| start <= token.span.start);
+        Span {
+            line,
+            column: token.span.start - self.line_starts[line - 1] + 1,
+        }
+    }
+    fn emit(&mut self, opcode: OpCode) {
+        let span = self.span(self.previous());
+        self.emit_at(opcode, span);
+    }
+    fn emit_at(&mut self, opcode: OpCode, span: Span) {
+        // Jumps are kept, as `patch_jump` expects them where it was told.
+        let is_jump = matches!(opcode, OpCode::Jump(_) | OpCode::JumpIfFalse(_));
+        if self.state().unreachable && !is_jump {
+            return;
+        }
+        if let Some((start, value)) = self.fold(opcode) {
+            self.truncate(start);
+            self.emit_value(value, span);
+            return;
+        }
+        // Limits are checked where operands are made, so this is a last
+        // resort against writing an instruction that can't be encoded.
+        if !opcode.fits() {
+            self.error("Too many values for one instruction.");
+            return;
+        }
+        let state = self.state();
+        state.starts.push(state.chunk.code().len());
+        state.chunk.write(opcode, span);
+    }
+    /// Emits whatever loads `value`.
+    fn emit_value(&mut self, value: Value, span: Span) {
+        let opcode = match value.kind() {
+            ValueKind::Null => OpCode::Null,
+            ValueKind::Bool(true) => OpCode::True,
+            ValueKind::Bool(false) => OpCode::False,
+            _ => {
+                let index = self.make_constant(value);
+                if OpCode::Constant(index).fits() {
+                    OpCode::Constant(index)
+                } else {
+                    OpCode::ConstantLong(index)
+                }
+            }
+        };
+        self.emit_at(opcode, span);
+    }
+    /// When `opcode` would only operate on constants loaded by the last
+    /// instructions, where the first of those starts and the result, so it
+    /// can be computed now.
+    fn fold(&mut self, opcode: OpCode) -> Option<(usize, Value)> {
+        let operands = match opcode {
+            OpCode::Negate | OpCode::Not => 1,
+            OpCode::Equal
+            | OpCode::Greater
+            | OpCode::Less
+            | OpCode::Add
+            | OpCode::Subtract
+            | OpCode::Multiply
+            | OpCode::Divide
+            | OpCode::Modulo => 2,
+            _ => return None,
+        };
+        let state = self.state();
+        let first = state.starts.len().checked_sub(operands)?;
+        let values = state.starts[first..]
+            .iter()
+            .map(|&start| loaded_constant(&state.chunk, start))
+            .collect::<Option<Vec<_>>>()?;
+        Some((state.starts[first], fold(opcode, &values)?))
+    }
+    /// Drops the code from `offset` on.
+    fn truncate(&mut self, offset: usize) {
+        let state = self.state();
+        state.chunk.truncate(offset);
+        state.starts.retain(|&start| start < offset);
+    }
+    /// Compiles code that can never run, for its errors, then drops it.
+    fn dead_code(&mut self, compile: impl FnOnce(&mut Self)) {
+        let state = self.state();
+        let (offset, unreachable) = (state.chunk.code().len(), state.unreachable);
+        state.unreachable = true;
+        compile(self);
+        self.truncate(offset);
+        self.state().unreachable = unreachable;
+    }
+    /// The constant the condition compiled from `start` on always is, if
+    /// it's a single instruction loading one.
+    fn constant_condition(&mut self, start: usize) -> Option<Value> {
+        let state = self.state();
+        match state.starts.last() {
+            Some(&last) if last == start => loaded_constant(&state.chunk, start),
+            _ => None,
+        }
+    }
+    /// Emits `jump` with a placeholder distance, returning its offset for
+    /// `patch_jump`.
+    fn emit_jump(&mut self, jump: OpCode) -> usize {
+        let offset = self.state().chunk.code().len();
+        self.emit(jump);
+        offset
+    }
+    /// Makes the jump at `offset` land on the next instruction emitted.
+    fn patch_jump(&mut self, offset: usize) {
+        let chunk = &self.state().chunk;
+        let jump = chunk.decode(offset);
+        let distance = chunk.code().len() - offset - jump.size();
+        let jump = match jump {
+            OpCode::Jump(_) => OpCode::Jump(distance),
+            OpCode::JumpIfFalse(_) => OpCode::JumpIfFalse(distance),
+            _ => unreachable!("only jumps are patched"),
+        };
+        if !jump.fits() {
+            self.error("Too much code to jump over.");
+            return;
+        }
+        let state = self.state();
+        state.chunk.patch(offset, jump);
+        // Anything a jump lands on can run.
+        state.unreachable = false;
+    }
+    fn emit_loop(&mut self, start: usize) {
+        let end = self.state().chunk.code().len() + OpCode::Loop(0).size();
+        let jump = OpCode::Loop(end - start);
+        if !jump.fits() {
+            self.error("Loop body too large.");
+            return;
+        }
+        self.emit(jump);
+    }
+    fn emit_constant(&mut self, value: Value) {
+        let span = self.span(self.previous());
+        self.emit_value(value, span);
+    }
+    fn make_constant(&mut self, value: Value) -> usize {
+        let index = self.state().chunk.add_constant(value);
+        if index >= MAX_CONSTANTS {
+            self.error("Too many constants in one chunk.");
+            return 0;
+        }
+        index
+    }
+    fn identifier_constant(&mut self, name: &str) -> usize {
+        self.make_constant(Value::String(Rc::from(name)))
+    }
+
+    fn declaration(&mut self) {
+        if self.state().unreachable {
+            self.dead_code(Self::live_declaration);
+        } else {
+            self.live_declaration();
+        }
+        if self.panic_mode {
+            self.synchronize();
+        }
+    }
+    fn live_declaration(&mut self) {
+        let named_fn = self.check(TokenType::Fn)
+            && self.tokens[self.current + 1].token_type == TokenType::Identifier;
+        if named_fn {
+            self.advance();
+            self.fn_declaration();
+        } else if self.match_token(TokenType::Let) {
+            self.let_declaration();
+        } else {
+            self.statement();
+        }
+    }
+    fn let_declaration(&mut self) {
+        let global = self.parse_variable("Expect variable name.");
+        if self.match_token(TokenType::Equal) {
+            self.expression();
+        } else {
+            self.emit(OpCode::Null);
+        }
+        self.consume(
+            TokenType::Semicolon,
+            "Expect ';' after variable declaration.",
+        );
+        self.define_variable(global);
+    }
+    fn fn_declaration(&mut self) {
+        let global = self.parse_variable("Expect function name.");
+        let name = self.lexeme(self.previous());
+        // Initialized right away, so the function can call itself.
+        self.mark_initialized();
+        self.function(Rc::from(name));
+        self.define_variable(global);
+    }
+    fn function(&mut self, name: Rc<str>) {
+        self.functions.push(FunctionState::new(Some(name)));
+        self.begin_scope();
+        self.consume(TokenType::LeftParen, "Expect '(' before parameters.");
+        if !self.check(TokenType::RightParen) {
+            loop {
+                self.state().arity += 1;
+                if self.state().arity > MAX_ARGUMENTS {
+                    self.error_at(self.current, "Can't have more than 255 parameters.");
+                }
+                let constant = self.parse_variable("Expect parameter name.");
+                self.define_variable(constant);
+                if !self.match_token(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightParen, "Expect ')' after parameters.");
+        self.consume(TokenType::LeftBrace, "Expect '{' before function body.");
+        self.block();
+        self.emit(OpCode::Null);
+        self.emit(OpCode::Return);
+        let mut state = self.functions.pop().expect("pushed above");
+        if self.errors.is_empty() {
+            peephole::optimize(&mut state.chunk);
+        }
+        let function = Function {
+            name: state.name,
+            arity: state.arity,
+            chunk: state.chunk,
+            captures: state.captures,
+        };
+        let index = self.make_constant(Value::Function(Rc::new(function)));
+        self.emit(OpCode::Closure(index));
+    }
+    fn statement(&mut self) {
+        if self.match_token(TokenType::Print) {
+            self.print_statement();
+        } else if self.match_token(TokenType::Return) {
+            self.return_statement();
+        } else if self.match_token(TokenType::If) {
+            self.if_statement();
+        } else if self.match_token(TokenType::While) {
+            self.while_statement();
+        } else if self.match_token(TokenType::LeftBrace) {
+            self.scoped_block();
+        } else {
+            self.expression_statement();
+        }
+    }
+    fn print_statement(&mut self) {
+        self.expression();
+        self.consume(TokenType::Semicolon, "Expect ';' after value.");
+        self.emit(OpCode::Print);
+    }
+    fn return_statement(&mut self) {
+        if self.functions.len() == 1 {
+            self.error("Can't return from top-level code.");
+        }
+        if self.match_token(TokenType::Semicolon) {
+            self.emit(OpCode::Null);
+        } else {
+            self.expression();
+            self.consume(TokenType::Semicolon, "Expect ';' after return value.");
+        }
+        self.emit(OpCode::Return);
+        self.state().unreachable = true;
+    }
+    fn if_statement(&mut self) {
+        let start = self.state().chunk.code().len();
+        self.expression();
+        if let Some(condition) = self.constant_condition(start) {
+            // Only the branch taken is kept.
+            self.truncate(start);
+            self.consume(TokenType::LeftBrace, "Expect '{' after condition.");
+            if condition.is_falsey() {
+                self.dead_code(Self::scoped_block);
+                self.else_branch();
+            } else {
+                self.scoped_block();
+                self.dead_code(Self::else_branch);
+            }
+            return;
+        }
+        let then_jump = self.emit_jump(OpCode::JumpIfFalse(0));
+        self.emit(OpCode::Pop);
+        self.consume(TokenType::LeftBrace, "Expect '{' after condition.");
+        self.scoped_block();
+        let else_jump = self.emit_jump(OpCode::Jump(0));
+        self.patch_jump(then_jump);
+        self.emit(OpCode::Pop);
+        self.else_branch();
+        self.patch_jump(else_jump);
+    }
+    fn else_branch(&mut self) {
+        if self.match_token(TokenType::Else) {
+            if self.match_token(TokenType::If) {
+                self.if_statement();
+            } else {
+                self.consume(TokenType::LeftBrace, "Expect '{' after 'else'.");
+                self.scoped_block();
+            }
+        }
+    }
+    fn while_statement(&mut self) {
+        let start = self.state().chunk.code().len();
+        self.expression();
+        if self
+            .constant_condition(start)
+            .is_some_and(|condition| condition.is_falsey())
+        {
+            self.truncate(start);
+            self.consume(TokenType::LeftBrace, "Expect '{' after condition.");
+            self.dead_code(Self::scoped_block);
+            return;
+        }
+        let exit_jump = self.emit_jump(OpCode::JumpIfFalse(0));
+        self.emit(OpCode::Pop);
+        self.consume(TokenType::LeftBrace, "Expect '{' after condition.");
+        self.scoped_block();
+        self.emit_loop(start);
+        self.patch_jump(exit_jump);
+        self.emit(OpCode::Pop);
+    }
+    fn scoped_block(&mut self) {
+        self.begin_scope();
+        self.block();
+        self.end_scope();
+    }
+    fn block(&mut self) {
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::Eof) {
+            self.declaration();
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after block.");
+    }
+    fn expression_statement(&mut self) {
+        self.expression();
+        if self.echo && self.functions.len() == 1 && self.state().scope_depth == 0 {
+            self.match_token(TokenType::Semicolon);
+            if self.check(TokenType::Eof) {
+                // Left on the stack for the final `Return` to echo.
+                return;
+            }
+            if self.previous().token_type == TokenType::Semicolon {
+                self.emit(OpCode::Pop);
+                return;
+            }
+        }
+        self.consume(TokenType::Semicolon, "Expect ';' after expression.");
+        self.emit(OpCode::Pop);
+    }
+
+    fn begin_scope(&mut self) {
+        self.state().scope_depth += 1;
+    }
+    fn end_scope(&mut self) {
+        let state = self.state();
+        state.scope_depth -= 1;
+        let depth = state.scope_depth;
+        while let Some(local) = self
+            .state()
+            .locals
+            .pop_if(|local| local.depth.is_some_and(|d| d > depth))
+        {
+            if local.captured {
+                self.emit(OpCode::CloseUpvalue);
+            } else {
+                self.emit(OpCode::Pop);
+            }
+        }
+    }
+    fn parse_variable(&mut self, message: &str) -> usize {
+        self.consume(TokenType::Identifier, message);
+        let name = self.lexeme(self.previous());
+        if self.state().scope_depth > 0 {
+            self.declare_local(name);
+            0
+        } else {
+            self.identifier_constant(name)
+        }
+    }
+    fn declare_local(&mut self, name: &'a str) {
+        if self.state().locals.len() == MAX_LOCALS {
+            self.error("Too many local variables in function.");
+            return;
+        }
+        let state = self.state();
+        let already_declared = state
+            .locals
+            .iter()
+            .rev()
+            .take_while(|local| local.depth.is_none_or(|d| d >= state.scope_depth))
+            .any(|local| local.name == name);
+        state.locals.push(Local {
+            name,
+            depth: None,
+            captured: false,
+        });
+        if already_declared {
+            self.error("Already a variable with this name in this scope.");
+        }
+    }
+    fn mark_initialized(&mut self) {
+        let state = self.state();
+        if state.scope_depth > 0 {
+            if let Some(local) = state.locals.last_mut() {
+                local.depth = Some(state.scope_depth);
+            }
+        }
+    }
+    fn define_variable(&mut self, global: usize) {
+        if self.state().scope_depth > 0 {
+            self.mark_initialized();
+        } else {
+            self.emit(OpCode::DefineGlobal(global));
+        }
+    }
+    /// Looks `name` up among the locals of the function at `depth` in
+    /// `functions`.
+    fn resolve_local(&mut self, depth: usize, name: &str) -> Option<usize> {
+        let (slot, local) = self.functions[depth]
+            .locals
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|(_, local)| local.name == name)?;
+        if local.depth.is_none() {
+            self.error("Can't read local variable in its own initializer.");
+        }
+        Some(slot)
+    }
+    /// Looks `name` up in the functions enclosing the one at `depth`,
+    /// capturing it in every function in between.
+    fn resolve_upvalue(&mut self, depth: usize, name: &str) -> Option<usize> {
+        if depth == 0 {
+            return None;
+        }
+        let capture = match self.resolve_local(depth - 1, name) {
+            Some(slot) => {
+                self.functions[depth - 1].locals[slot].captured = true;
+                Capture::Local(slot)
+            }
+            None => Capture::Upvalue(self.resolve_upvalue(depth - 1, name)?),
+        };
+        let captures = &mut self.functions[depth].captures;
+        match captures.iter().position(|&c| c == capture) {
+            Some(index) => Some(index),
+            None if captures.len() == MAX_LOCALS => {
+                self.error("Too many closure variables in function.");
+                Some(0)
+            }
+            None => {
+                captures.push(capture);
+                Some(captures.len() - 1)
+            }
+        }
+    }
+
+    fn expression(&mut self) {
+        self.parse_precedence(Precedence::Assignment);
+    }
+    fn parse_precedence(&mut self, precedence: Precedence) {
+        self.advance();
+        let Some(prefix) = Self::rule(self.previous().token_type).prefix else {
+            self.error("Expect expression.");
+            return;
+        };
+        let can_assign = precedence <= Precedence::Assignment;
+        prefix(self, can_assign);
+        while precedence <= Self::rule(self.tokens[self.current].token_type).precedence {
+            self.advance();
+            if let Some(infix) = Self::rule(self.previous().token_type).infix {
+                infix(self, can_assign);
+            }
+        }
+        if can_assign && self.match_token(TokenType::Equal) {
+            self.error("Invalid assignment target.");
+        }
+    }
+    fn rule(token_type: TokenType) -> ParseRule<'a> {
+        let (prefix, infix, precedence): (Option<ParseFn<'a>>, Option<ParseFn<'a>>, _) =
+            match token_type {
+                TokenType::LeftParen => (Some(Self::grouping), Some(Self::call), Precedence::Call),
+                TokenType::LeftBracket => (Some(Self::list), Some(Self::index), Precedence::Call),
+                TokenType::Dot => (None, Some(Self::dot), Precedence::Call),
+                TokenType::Minus => (Some(Self::unary), Some(Self::binary), Precedence::Term),
+                TokenType::Plus => (None, Some(Self::binary), Precedence::Term),
+                TokenType::Slash | TokenType::Star | TokenType::Percent => {
+                    (None, Some(Self::binary), Precedence::Factor)
+                }
+                TokenType::Bang => (Some(Self::unary), None, Precedence::None),
+                TokenType::BangEqual | TokenType::EqualEqual => {
+                    (None, Some(Self::binary), Precedence::Equality)
+                }
+                TokenType::Greater
+                | TokenType::GreaterEqual
+                | TokenType::Less
+                | TokenType::LessEqual => (None, Some(Self::binary), Precedence::Comparison),
+                TokenType::Identifier => (Some(Self::variable), None, Precedence::None),
+                TokenType::Fn => (Some(Self::lambda), None, Precedence::None),
+                TokenType::String => (Some(Self::string), None, Precedence::None),
+                TokenType::Number => (Some(Self::number), None, Precedence::None),
+                TokenType::False | TokenType::True | TokenType::Null => {
+                    (Some(Self::literal), None, Precedence::None)
+                }
+                _ => (None, None, Precedence::None),
+            };
+        ParseRule {
+            prefix,
+            infix,
+            precedence,
+        }
+    }
+    fn grouping(&mut self, _can_assign: bool) {
+        self.expression();
+        self.consume(TokenType::RightParen, "Expect ')' after expression.");
+    }
+    fn call(&mut self, _can_assign: bool) {
+        let paren = self.previous().clone();
+        let mut count = 0;
+        if !self.check(TokenType::RightParen) {
+            loop {
+                self.expression();
+                if count == MAX_ARGUMENTS {
+                    self.error("Can't have more than 255 arguments.");
+                }
+                count += 1;
+                if !self.match_token(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightParen, "Expect ')' after arguments.");
+        let span = self.span(&paren);
+        self.emit_at(OpCode::Call(count), span);
+    }
+    fn dot(&mut self, can_assign: bool) {
+        self.consume(TokenType::Identifier, "Expect property name after '.'.");
+        let name = self.identifier_constant(self.lexeme(self.previous()));
+        if can_assign && self.match_token(TokenType::Equal) {
+            self.expression();
+            self.emit(OpCode::SetProperty(name));
+        } else {
+            self.emit(OpCode::GetProperty(name));
+        }
+    }
+    fn lambda(&mut self, _can_assign: bool) {
+        self.function(Rc::from("anonymous"));
+    }
+    fn list(&mut self, _can_assign: bool) {
+        let mut count = 0;
+        if !self.check(TokenType::RightBracket) {
+            loop {
+                self.expression();
+                count += 1;
+                if !self.match_token(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightBracket, "Expect ']' after list items.");
+        if !OpCode::BuildList(count).fits() {
+            self.error("Too many items in a list literal.");
+            return;
+        }
+        self.emit(OpCode::BuildList(count));
+    }
+    fn index(&mut self, _can_assign: bool) {
+        let bracket = self.previous().clone();
+        self.expression();
+        self.consume(TokenType::RightBracket, "Expect ']' after index.");
+        let span = self.span(&bracket);
+        self.emit_at(OpCode::Index, span);
+    }
+    fn number(&mut self, _can_assign: bool) {
+        let value = self.lexeme(self.previous()).parse().unwrap();
+        self.emit_constant(Value::Number(value));
+    }
+    fn string(&mut self, _can_assign: bool) {
+        let lexeme = self.lexeme(self.previous());
+        self.emit_constant(Value::String(Rc::from(&lexeme[1..lexeme.len() - 1])));
+    }
+    fn literal(&mut self, _can_assign: bool) {
+        match self.previous().token_type {
+            TokenType::False => self.emit(OpCode::False),
+            TokenType::True => self.emit(OpCode::True),
+            TokenType::Null => self.emit(OpCode::Null),
+            _ => unreachable!(),
+        }
+    }
+    fn unary(&mut self, _can_assign: bool) {
+        let operator = self.previous().clone();
+        self.parse_precedence(Precedence::Unary);
+        let span = self.span(&operator);
+        match operator.token_type {
+            TokenType::Minus => self.emit_at(OpCode::Negate, span),
+            TokenType::Bang => self.emit_at(OpCode::Not, span),
+            _ => unreachable!(),
+        }
+    }
+    fn binary(&mut self, _can_assign: bool) {
+        let operator = self.previous().clone();
+        let precedence = Self::rule(operator.token_type).precedence;
+        self.parse_precedence(precedence.next());
+        let span = self.span(&operator);
+        let opcodes: &[OpCode] = match operator.token_type {
+            TokenType::BangEqual => &[OpCode::Equal, OpCode::Not],
+            TokenType::EqualEqual => &[OpCode::Equal],
+            TokenType::Greater => &[OpCode::Greater],
+            TokenType::GreaterEqual => &[OpCode::Less, OpCode::Not],
+            TokenType::Less => &[OpCode::Less],
+            TokenType::LessEqual => &[OpCode::Greater, OpCode::Not],
+            TokenType::Plus => &[OpCode::Add],
+            TokenType::Minus => &[OpCode::Subtract],
+            TokenType::Star => &[OpCode::Multiply],
+            TokenType::Slash => &[OpCode::Divide],
+            TokenType::Percent => &[OpCode::Modulo],
+            _ => unreachable!(),
+        };
+        for &opcode in opcodes {
+            self.emit_at(opcode, span);
+        }
+    }
+    fn variable(&mut self, can_assign: bool) {
+        let name = self.lexeme(self.previous());
+        let depth = self.functions.len() - 1;
+        let (get, set) = if let Some(slot) = self.resolve_local(depth, name) {
+            (OpCode::GetLocal(slot), OpCode::SetLocal(slot))
+        } else if let Some(index) = self.resolve_upvalue(depth, name) {
+            (OpCode::GetUpvalue(index), OpCode::SetUpvalue(index))
+        } else {
+            let index = self.identifier_constant(name);
+            (OpCode::GetGlobal(index), OpCode::SetGlobal(index))
+        };
+        let compound = match self.tokens[self.current].token_type {
+            TokenType::PlusEqual => Some(OpCode::Add),
+            TokenType::MinusEqual => Some(OpCode::Subtract),
+            TokenType::StarEqual => Some(OpCode::Multiply),
+            TokenType::SlashEqual => Some(OpCode::Divide),
+            TokenType::PercentEqual => Some(OpCode::Modulo),
+            _ => None,
+        };
+        if can_assign && self.match_token(TokenType::Equal) {
+            self.expression();
+            self.emit(set);
+        } else if let (true, Some(operator)) = (can_assign, compound) {
+            self.emit(get);
+            self.advance();
+            let span = self.span(self.previous());
+            self.expression();
+            self.emit_at(operator, span);
+            self.emit(set);
+        } else {
+            self.emit(get);
+        }
+    }
+}
+
+/// The value the instruction at `offset` pushes, if it loads a constant.
+fn loaded_constant(chunk: &Chunk, offset: usize) -> Option<Value> {
+    match chunk.decode(offset) {
+        OpCode::Constant(index) | OpCode::ConstantLong(index) => {
+            Some(chunk.constants()[index].clone())
+        }
+        OpCode::Null => Some(Value::Null),
+        OpCode::True => Some(Value::Bool(true)),
+        OpCode::False => Some(Value::Bool(false)),
+        _ => None,
+    }
+}
+
+/// What `opcode` computes from `operands`, as the VM would. Operations
+/// that would fail are left for the VM, to report with their span.
+fn fold(opcode: OpCode, operands: &[Value]) -> Option<Value> {
+    use ValueKind::{Bool, Number};
+    let operands: Vec<ValueKind> = operands.iter().map(Value::kind).collect();
+    let value = match (opcode, operands.as_slice()) {
+        (OpCode::Negate, [Number(a)]) => Number(-a),
+        (OpCode::Not, [a]) => Bool(a.is_falsey()),
+        (OpCode::Equal, [a, b]) => Bool(a == b),
+        (OpCode::Greater, [Number(a), Number(b)]) => Bool(a > b),
+        (OpCode::Less, [Number(a), Number(b)]) => Bool(a < b),
+        (OpCode::Add, [Number(a), Number(b)]) => Number(a + b),
+        (OpCode::Add, [ValueKind::String(a), ValueKind::String(b)]) => {
+            ValueKind::String(Rc::from(format!("{a}{b}")))
+        }
+        (OpCode::Subtract, [Number(a), Number(b)]) => Number(a - b),
+        (OpCode::Multiply, [Number(a), Number(b)]) => Number(a * b),
+        (OpCode::Divide, [Number(a), Number(b)]) => Number(a / b),
+        (OpCode::Modulo, [Number(a), Number(b)]) => Number(a % b),
+        _ => return None,
+    };
+    Some(value.into_value())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn opcodes(source: &str) -> Vec<OpCode> {
+        let tokens = Scanner::new(source).scan_tokens().unwrap();
+        let (chunk, errors) = Compiler::new(source, tokens, false).compile();
+        assert!(errors.is_empty());
+        instructions(&chunk)
+    }
+
+    fn instructions(chunk: &Chunk) -> Vec<OpCode> {
+        chunk.instructions().map(|(_, opcode)| opcode).collect()
+    }
+
+    fn errors(source: &str) -> Vec<(String, String)> {
+        check(source)
+            .into_iter()
+            .map(|e| (e.message, e.at))
+            .collect()
+    }
+
+    #[test]
+    fn precedence_works() {
+        assert_eq!(
+            opcodes("-a + b * c <= d;"),
+            vec![
+                OpCode::GetGlobal(0),
+                OpCode::Negate,
+                OpCode::GetGlobal(1),
+                OpCode::GetGlobal(2),
+                OpCode::Multiply,
+                OpCode::Add,
+                OpCode::GetGlobal(3),
+                OpCode::Greater,
+                OpCode::Not,
+                OpCode::Pop,
+                OpCode::Return,
+            ]
+        );
+    }
+
+    #[test]
+    fn control_flow_compiles_to_jumps() {
+        assert_eq!(
+            opcodes("if a { print 1; } else { print 2; }"),
+            vec![
+                OpCode::GetGlobal(0),
+                OpCode::JumpIfFalse(7),
+                OpCode::Pop,
+                OpCode::Constant(1),
+                OpCode::Print,
+                OpCode::Jump(4),
+                OpCode::Pop,
+                OpCode::Constant(2),
+                OpCode::Print,
+                OpCode::Return,
+            ]
+        );
+        assert_eq!(
+            opcodes("while a { print 1; }"),
+            vec![
+                OpCode::GetGlobal(0),
+                OpCode::JumpIfFalse(7),
+                OpCode::Pop,
+                OpCode::Constant(1),
+                OpCode::Print,
+                OpCode::Loop(14),
+                OpCode::Pop,
+                OpCode::Return,
+            ]
+        );
+        assert_eq!(
+            errors("while a 1;")[0],
+            (
+                String::from("Expect '{' after condition."),
+                String::from(" at '1'")
+            )
+        );
+    }
+
+    #[test]
+    fn lists_and_indexing_compile() {
+        assert_eq!(
+            opcodes("[1, [], 2][0];"),
+            vec![
+                OpCode::Constant(0),
+                OpCode::BuildList(0),
+                OpCode::Constant(1),
+                OpCode::BuildList(3),
+                OpCode::Constant(2),
+                OpCode::Index,
+                OpCode::Pop,
+                OpCode::Return,
+            ]
+        );
+    }
+
+    #[test]
+    fn calls_compile() {
+        assert_eq!(
+            opcodes("f(1, g())(2);"),
+            vec![
+                OpCode::GetGlobal(0),
+                OpCode::Constant(1),
+                OpCode::GetGlobal(2),
+                OpCode::Call(0),
+                OpCode::Call(2),
+                OpCode::Constant(3),
+                OpCode::Call(1),
+                OpCode::Pop,
+                OpCode::Return,
+            ]
+        );
+        assert_eq!(
+            errors("f(1;"),
+            vec![(
+                String::from("Expect ')' after arguments."),
+                String::from(" at ';'")
+            )]
+        );
+    }
+
+    #[test]
+    fn properties_compile() {
+        assert_eq!(
+            opcodes("a.b.c = a.d(1);"),
+            vec![
+                OpCode::GetGlobal(0),
+                OpCode::GetProperty(1),
+                OpCode::GetGlobal(0),
+                OpCode::GetProperty(3),
+                OpCode::Constant(4),
+                OpCode::Call(1),
+                OpCode::SetProperty(2),
+                OpCode::Pop,
+                OpCode::Return,
+            ]
+        );
+        assert_eq!(
+            errors("a.1;"),
+            vec![(
+                String::from("Expect property name after '.'."),
+                String::from(" at '1'")
+            )]
+        );
+    }
+
+    #[test]
+    fn constant_expressions_are_folded() {
+        let folded = |source: &str| {
+            let chunk = compile(source).unwrap();
+            assert_eq!(instructions(&chunk).len(), 3, "{source}");
+            loaded_constant(&chunk, 0).unwrap()
+        };
+        assert_eq!(folded("print -(1 + 2) * 3;"), Value::Number(-9.0));
+        assert_eq!(
+            folded("print \"a\" + \"b\";"),
+            Value::String(Rc::from("ab"))
+        );
+        assert_eq!(folded("print !(1 < 2) == (1 != 1);"), Value::Bool(true));
+        assert_eq!(folded("print 2 >= 3;"), Value::Bool(false));
+        // Left for the VM to report.
+        assert_eq!(
+            opcodes("1 + \"a\";"),
+            [
+                OpCode::Constant(0),
+                OpCode::Constant(1),
+                OpCode::Add,
+                OpCode::Pop,
+                OpCode::Return,
+            ]
+        );
+    }
+
+    #[test]
+    fn unreachable_code_is_dropped() {
+        let chunk = compile("fn f() { return 1; print 2; }").unwrap();
+        let ValueKind::Function(f) = chunk.constants()[1].kind() else {
+            panic!("expected a function");
+        };
+        assert_eq!(
+            instructions(&f.chunk),
+            [OpCode::Constant(0), OpCode::Return]
+        );
+        assert_eq!(
+            opcodes("if false { print 1; } else { print 2; }"),
+            [OpCode::Constant(1), OpCode::Print, OpCode::Return]
+        );
+        assert_eq!(
+            opcodes("if 1 { print 1; } else if x { print 2; }"),
+            [OpCode::Constant(0), OpCode::Print, OpCode::Return]
+        );
+        assert_eq!(opcodes("while null { print 1; }"), [OpCode::Return]);
+        let chunk = compile("fn f(c) { if c { return 1; } return 2; }").unwrap();
+        let ValueKind::Function(f) = chunk.constants()[1].kind() else {
+            panic!("expected a function");
+        };
+        let returns = instructions(&f.chunk)
+            .into_iter()
+            .filter(|&opcode| opcode == OpCode::Return)
+            .count();
+        assert_eq!(returns, 2);
+        assert_eq!(
+            errors("fn f() { return; print; }")[0].0,
+            "Expect expression."
+        );
+    }
+
+    #[test]
+    fn constants_are_deduplicated() {
+        // The global `a` is named by the same string as the literal.
+        let chunk = compile("print x + 1 + 1 + \"a\" + a + 2;").unwrap();
+        assert_eq!(chunk.constants().len(), 4);
+        assert_eq!(chunk.reused_constants(), 2);
+        let fns = compile("let f = fn() {}; let g = fn() {};").unwrap();
+        assert_eq!(fns.constants().len(), 4);
+    }
+
+    #[test]
+    fn operands_are_kept_within_their_encoding() {
+        let source: String = (0..300).map(|i| format!("print {i};")).collect();
+        let code = opcodes(&source);
+        assert_eq!(code[0], OpCode::Constant(0));
+        assert_eq!(code[2 * 299], OpCode::ConstantLong(299));
+        let arguments = vec!["1"; 256].join(", ");
+        assert_eq!(
+            errors(&format!("f({arguments});"))[0].0,
+            "Can't have more than 255 arguments."
+        );
+        let locals: String = (0..257).map(|i| format!("let a{i};")).collect();
+        assert_eq!(
+            errors(&format!("{{ {locals} }}"))[0].0,
+            "Too many local variables in function."
+        );
+    }
+
+    #[test]
+    fn closures_capture_enclosing_locals() {
+        let source = "{ let a = 1; fn f() { fn g() { return a; } } }";
+        let chunk = compile(source).unwrap();
+        assert_eq!(
+            instructions(&chunk),
+            [
+                OpCode::Constant(0),
+                OpCode::Closure(1),
+                OpCode::Pop,
+                OpCode::CloseUpvalue,
+                OpCode::Return,
+            ]
+        );
+        let ValueKind::Function(f) = chunk.constants()[1].kind() else {
+            panic!("expected a function");
+        };
+        assert_eq!(f.captures, [Capture::Local(0)]);
+        let ValueKind::Function(g) = f.chunk.constants()[0].kind() else {
+            panic!("expected a function");
+        };
+        assert_eq!(g.captures, [Capture::Upvalue(0)]);
+        assert_eq!(g.chunk.decode(0), OpCode::GetUpvalue(0));
+    }
+
+    #[test]
+    fn returns_are_checked() {
+        assert_eq!(
+            errors("return 1;"),
+            vec![(
+                String::from("Can't return from top-level code."),
+                String::from(" at 'return'")
+            )]
+        );
+        assert_eq!(
+            errors("fn f(a b) {}")[0],
+            (
+                String::from("Expect ')' after parameters."),
+                String::from(" at 'b'")
+            )
+        );
+    }
+
+    #[test]
+    fn locals_are_resolved_to_slots() {
+        assert_eq!(
+            opcodes("let a = 1; { let b = a; b -= 2; }"),
+            vec![
+                OpCode::Constant(1),
+                OpCode::DefineGlobal(0),
+                OpCode::GetGlobal(0),
+                OpCode::GetLocal(0),
+                OpCode::Constant(2),
+                OpCode::Subtract,
+                OpCode::SetLocal(0),
+                OpCode::Pop,
+                OpCode::Pop,
+                OpCode::Return,
+            ]
+        );
+    }
+
+    #[test]
+    fn repl_echoes_final_expression() {
+        let source = "let a = 1; a + 1";
+        let tokens = Scanner::new(source).scan_tokens().unwrap();
+        let (chunk, diagnostics) = Compiler::new(source, tokens, true).compile();
+        assert!(diagnostics.is_empty());
+        let code = instructions(&chunk);
+        assert_eq!(code[code.len() - 2..], [OpCode::Add, OpCode::Return]);
+        assert!(!errors("a + 1").is_empty());
+    }
+
+    #[test]
+    fn errors_are_reported_and_recovered_from() {
+        assert_eq!(
+            errors("1 +; let = 2; { let a = a; }\nprint 1"),
+            vec![
+                (String::from("Expect expression."), String::from(" at ';'")),
+                (
+                    String::from("Expect variable name."),
+                    String::from(" at '='")
+                ),
+                (
+                    String::from("Can't read local variable in its own initializer."),
+                    String::from(" at 'a'")
+                ),
+                (
+                    String::from("Expect ';' after value."),
+                    String::from(" at end")
+                ),
+            ]
+        );
+        assert_eq!(
+            errors("1 = 2;"),
+            vec![(
+                String::from("Invalid assignment target."),
+                String::from(" at '='")
+            )]
+        );
+    }
+
+    #[test]
+    fn spans_point_at_operators() {
+        let source = "a +\n  -b;";
+        let tokens = Scanner::new(source).scan_tokens().unwrap();
+        let (chunk, _) = Compiler::new(source, tokens, false).compile();
+        assert_eq!(chunk.span_at(8), Span { line: 2, column: 3 });
+        assert_eq!(chunk.span_at(9), Span { line: 1, column: 3 });
+    }
+}
@@ -1,33 +1,219 @@
-use crate::chunk::{Chunk, OpCode};
+use std::io::{self, Write};
+
+use crate::{
+    chunk::{Chunk, OpCode, MAX_CONSTANTS},
+    function::Capture,
+    value::{Value, ValueKind},
+};
 
 impl Chunk {
-    pub fn disassemble_chunk(&self, name: &str) {
-        println!("== {name} ==");
-        for (i, instruction) in self.code.iter().enumerate() {
-            self.disassemble_instruction(i, instruction);
+    /// Writes every instruction under a `== name ==` header, in the format
+    /// used by `--disassemble`, then the constant pool and the functions
+    /// declared in the chunk. `assembler::assemble` reads it back, skipping
+    /// the pool statistics, which are a comment.
+    pub fn disassemble_chunk(&self, name: &str, out: &mut dyn Write) -> io::Result<()> {
+        writeln!(out, "== {name} ==")?;
+        for (offset, instruction) in self.instructions() {
+            self.disassemble_instruction(offset, &instruction, out)?;
         }
-    }
-    pub fn disassemble_instruction(&self, offset: usize, instruction: &OpCode) {
-        print!("{:04} ", offset);
-        if offset > 0 && self.spans[offset].line == self.spans[offset - 1].line {
-            print!("   | ");
-        } else {
-            print!("{:4} ", self.spans[offset].line);
+        if !self.constants().is_empty() {
+            writeln!(out, "-- constants --")?;
+            for (i, constant) in self.constants().iter().enumerate() {
+                writeln!(out, "{:4} {}", i, constant_literal(constant))?;
+            }
+            let reused = self.reused_constants();
+            writeln!(
+                out,
+                "; pool: {} of {MAX_CONSTANTS} constants, {reused} duplicate{} merged",
+                self.constants().len(),
+                if reused == 1 { "" } else { "s" }
+            )?;
+        }
+        for constant in self.constants() {
+            if let ValueKind::Function(function) = constant.kind() {
+                function
+                    .chunk
+                    .disassemble_chunk(&function.to_string(), out)?;
+            }
         }
+        Ok(())
+    }
+    /// Writes one line for `instruction`, found at `offset`.
+    pub fn disassemble_instruction(
+        &self,
+        offset: usize,
+        instruction: &OpCode,
+        out: &mut dyn Write,
+    ) -> io::Result<()> {
+        let span = self.span_at(offset);
+        let next = offset + instruction.size();
+        write!(out, "{:04} {:>4}:{:<4} ", offset, span.line, span.column)?;
         match instruction {
-            OpCode::Constant(index) => self.constant_instruction("OP_CONSTANT", *index),
-            OpCode::Negate => self.simple_instruction("OP_NEGATE"),
-            OpCode::Add => self.simple_instruction("OP_ADD"),
-            OpCode::Subtract => self.simple_instruction("OP_SUBTRACT"),
-            OpCode::Multiply => self.simple_instruction("OP_MULTIPLY"),
-            OpCode::Divide => self.simple_instruction("OP_DIVIDE"),
-            OpCode::Return => self.simple_instruction("OP_RETURN"),
+            OpCode::Constant(index) => self.constant_instruction(out, "OP_CONSTANT", *index),
+            OpCode::ConstantLong(index) => {
+                self.constant_instruction(out, "OP_CONSTANT_LONG", *index)
+            }
+            OpCode::Null => self.simple_instruction(out, "OP_NULL"),
+            OpCode::True => self.simple_instruction(out, "OP_TRUE"),
+            OpCode::False => self.simple_instruction(out, "OP_FALSE"),
+            OpCode::Pop => self.simple_instruction(out, "OP_POP"),
+            OpCode::GetLocal(slot) => self.slot_instruction(out, "OP_GET_LOCAL", *slot),
+            OpCode::SetLocal(slot) => self.slot_instruction(out, "OP_SET_LOCAL", *slot),
+            OpCode::GetGlobal(index) => self.constant_instruction(out, "OP_GET_GLOBAL", *index),
+            OpCode::DefineGlobal(index) => {
+                self.constant_instruction(out, "OP_DEFINE_GLOBAL", *index)
+            }
+            OpCode::SetGlobal(index) => self.constant_instruction(out, "OP_SET_GLOBAL", *index),
+            OpCode::GetUpvalue(index) => self.slot_instruction(out, "OP_GET_UPVALUE", *index),
+            OpCode::SetUpvalue(index) => self.slot_instruction(out, "OP_SET_UPVALUE", *index),
+            OpCode::GetProperty(index) => self.constant_instruction(out, "OP_GET_PROPERTY", *index),
+            OpCode::SetProperty(index) => self.constant_instruction(out, "OP_SET_PROPERTY", *index),
+            OpCode::Equal => self.simple_instruction(out, "OP_EQUAL"),
+            OpCode::Greater => self.simple_instruction(out, "OP_GREATER"),
+            OpCode::Less => self.simple_instruction(out, "OP_LESS"),
+            OpCode::Add => self.simple_instruction(out, "OP_ADD"),
+            OpCode::Subtract => self.simple_instruction(out, "OP_SUBTRACT"),
+            OpCode::Multiply => self.simple_instruction(out, "OP_MULTIPLY"),
+            OpCode::Divide => self.simple_instruction(out, "OP_DIVIDE"),
+            OpCode::Modulo => self.simple_instruction(out, "OP_MODULO"),
+            OpCode::BuildList(count) => self.slot_instruction(out, "OP_BUILD_LIST", *count),
+            OpCode::Index => self.simple_instruction(out, "OP_INDEX"),
+            OpCode::Jump(distance) => {
+                self.jump_instruction(out, "OP_JUMP", *distance, next + distance)
+            }
+            OpCode::JumpIfFalse(distance) => {
+                self.jump_instruction(out, "OP_JUMP_IF_FALSE", *distance, next + distance)
+            }
+            OpCode::Loop(distance) => {
+                self.jump_instruction(out, "OP_LOOP", *distance, next - distance)
+            }
+            OpCode::Call(count) => self.slot_instruction(out, "OP_CALL", *count),
+            OpCode::Closure(index) => self.closure_instruction(out, *index),
+            OpCode::CloseUpvalue => self.simple_instruction(out, "OP_CLOSE_UPVALUE"),
+            OpCode::Not => self.simple_instruction(out, "OP_NOT"),
+            OpCode::Negate => self.simple_instruction(out, "OP_NEGATE"),
+            OpCode::Print => self.simple_instruction(out, "OP_PRINT"),
+            OpCode::Return => self.simple_instruction(out, "OP_RETURN"),
+            OpCode::AddLocalConst(slot, index) => {
+                let constant = self.constants()[*index].to_string();
+                writeln!(
+                    out,
+                    "{:16} {:4} {:4} '{}' ; fused OP_GET_LOCAL, OP_CONSTANT, OP_ADD",
+                    "OP_ADD_LOCAL_CONST",
+                    slot,
+                    index,
+                    constant.escape_debug()
+                )
+            }
+            OpCode::IncrementLocal(slot) => writeln!(
+                out,
+                "{:16} {:4} ; fused OP_GET_LOCAL, OP_CONSTANT 1, OP_ADD, OP_SET_LOCAL, OP_POP",
+                "OP_INCREMENT_LOCAL", slot
+            ),
+            OpCode::JumpIfNotLess(distance) => writeln!(
+                out,
+                "{:16} {:4} -> {} ; fused OP_LESS, OP_JUMP_IF_FALSE, OP_POP",
+                "OP_JUMP_IF_NOT_LESS",
+                distance,
+                next + distance
+            ),
+        }
+    }
+    fn simple_instruction(&self, out: &mut dyn Write, name: &str) -> io::Result<()> {
+        writeln!(out, "{name}")
+    }
+    fn slot_instruction(&self, out: &mut dyn Write, name: &str, slot: usize) -> io::Result<()> {
+        writeln!(out, "{:16} {:4}", name, slot)
+    }
+    fn jump_instruction(
+        &self,
+        out: &mut dyn Write,
+        name: &str,
+        distance: usize,
+        target: usize,
+    ) -> io::Result<()> {
+        writeln!(out, "{:16} {:4} -> {target}", name, distance)
+    }
+    fn constant_instruction(
+        &self,
+        out: &mut dyn Write,
+        name: &str,
+        index: usize,
+    ) -> io::Result<()> {
+        let constant = self.constants()[index].to_string();
+        writeln!(out, "{:16} {:4} '{}'", name, index, constant.escape_debug())
+    }
+    fn closure_instruction(&self, out: &mut dyn Write, index: usize) -> io::Result<()> {
+        self.constant_instruction(out, "OP_CLOSURE", index)?;
+        let ValueKind::Function(function) = self.constants()[index].kind() else {
+            return Ok(());
+        };
+        for capture in &function.captures {
+            match capture {
+                Capture::Local(slot) => writeln!(out, "{:37}local {slot}", "")?,
+                Capture::Upvalue(index) => writeln!(out, "{:37}upvalue {index}", "")?,
+            }
         }
+        Ok(())
     }
-    fn simple_instruction(&self, name: &str) {
-        println!("{name}");
+}
+
+/// How the constant pool lists `constant`: strings quoted and escaped, and
+/// numbers written so they read back exactly.
+fn constant_literal(constant: &Value) -> String {
+    match constant.kind() {
+        ValueKind::String(string) => format!("{string:?}"),
+        ValueKind::Number(number) => format!("{number:?}"),
+        ValueKind::Function(function) => {
+            let mut literal = format!("{function} arity {}", function.arity);
+            for capture in &function.captures {
+                match capture {
+                    Capture::Local(slot) => literal.push_str(&format!(" local {slot}")),
+                    Capture::Upvalue(index) => literal.push_str(&format!(" upvalue {index}")),
+                }
+            }
+            literal
+        }
+        constant => constant.to_string(),
     }
-    fn constant_instruction(&self, name: &str, index: usize) {
-        println!("{:16} {:4} '{}'", name, index, self.constants[index]);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compiler::compile;
+
+    #[test]
+    fn disassemble_chunk_works() {
+        let chunk = compile("let a = 1;\nprint -a;").unwrap();
+        let mut out = vec![];
+        chunk.disassemble_chunk("script", &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "\
+== script ==
+0000    1:9    OP_CONSTANT         1 '1'
+0002    1:10   OP_DEFINE_GLOBAL    0 'a'
+0006    2:8    OP_GET_GLOBAL       0 'a'
+0010    2:7    OP_NEGATE
+0011    2:9    OP_PRINT
+0012    2:9    OP_RETURN
+-- constants --
+   0 \"a\"
+   1 1.0
+; pool: 2 of 16777216 constants, 1 duplicate merged
+"
+        );
+    }
+
+    #[test]
+    fn fused_instructions_say_what_they_replace() {
+        let chunk = compile("{ let i = 0; while i < 3 { i = i + 1; } }").unwrap();
+        let mut out = vec![];
+        chunk.disassemble_chunk("script", &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains(
+            "0006    1:22   OP_JUMP_IF_NOT_LESS    5 -> 14 ; fused OP_LESS, OP_JUMP_IF_FALSE, OP_POP"
+        ));
+        assert!(out.contains("0009    1:34   OP_INCREMENT_LOCAL    0 ; fused"));
     }
 }
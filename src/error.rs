@@ -0,0 +1,142 @@
+use std::{error::Error, fmt};
+
+use crate::{chunk::Span, scanner::Diagnostic, verifier::VerifyError, vm::RuntimeError};
+
+/// Everything that can go wrong with rabbit code, from scanning it to
+/// running it.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RabbitError {
+    /// The source has characters or literals that can't be scanned.
+    Syntax(Vec<Diagnostic>),
+    /// The source scans, but isn't a valid program.
+    Compile(Vec<Diagnostic>),
+    /// A chunk that didn't come from the compiler, such as one loaded from
+    /// a file, would make the VM misbehave.
+    Verify(VerifyError),
+    Runtime(RuntimeError),
+    /// The script went over one of the `Vm`'s limits. `limit()` on the
+    /// error tells which.
+    LimitExceeded(RuntimeError),
+}
+
+impl RabbitError {
+    /// Every error found in the source. Empty for runtime errors.
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        match self {
+            RabbitError::Syntax(diagnostics) | RabbitError::Compile(diagnostics) => diagnostics,
+            RabbitError::Verify(_) | RabbitError::Runtime(_) | RabbitError::LimitExceeded(_) => &[],
+        }
+    }
+    /// Where the first error is, if known.
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            RabbitError::Syntax(diagnostics) | RabbitError::Compile(diagnostics) => {
+                diagnostics.first().map(|diagnostic| diagnostic.span)
+            }
+            RabbitError::Verify(_) => None,
+            RabbitError::Runtime(error) | RabbitError::LimitExceeded(error) => error.span(),
+        }
+    }
+    /// Formats the error the way the command line reports it, quoting and
+    /// underlining the offending code of `source`.
+    pub fn render(&self, source: &str) -> String {
+        match self {
+            RabbitError::Syntax(diagnostics) | RabbitError::Compile(diagnostics) => diagnostics
+                .iter()
+                .map(|diagnostic| diagnostic.render(source) + "\n")
+                .collect(),
+            RabbitError::Verify(error) => format!("{error}\n"),
+            RabbitError::Runtime(error) | RabbitError::LimitExceeded(error) => {
+                format!("{error}\n")
+            }
+        }
+    }
+}
+
+impl fmt::Display for RabbitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RabbitError::Syntax(diagnostics) | RabbitError::Compile(diagnostics) => {
+                for (i, diagnostic) in diagnostics.iter().enumerate() {
+                    if i > 0 {
+                        writeln!(f)?;
+                    }
+                    write!(f, "{diagnostic}")?;
+                }
+                Ok(())
+            }
+            RabbitError::Verify(error) => write!(f, "{error}"),
+            RabbitError::Runtime(error) | RabbitError::LimitExceeded(error) => {
+                write!(f, "{error}")
+            }
+        }
+    }
+}
+
+impl Error for RabbitError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            RabbitError::Verify(error) => Some(error),
+            RabbitError::Runtime(error) | RabbitError::LimitExceeded(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<VerifyError> for RabbitError {
+    fn from(error: VerifyError) -> RabbitError {
+        RabbitError::Verify(error)
+    }
+}
+
+impl From<RuntimeError> for RabbitError {
+    fn from(error: RuntimeError) -> RabbitError {
+        match error.limit() {
+            Some(_) => RabbitError::LimitExceeded(error),
+            None => RabbitError::Runtime(error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{compiler::compile, vm::Vm};
+
+    #[test]
+    fn compile_errors_carry_diagnostics() {
+        let error = compile("let a = 1;\nprint a +;").unwrap_err();
+        assert!(matches!(error, RabbitError::Compile(_)));
+        assert_eq!(
+            error.span(),
+            Some(Span {
+                line: 2,
+                column: 10
+            })
+        );
+        assert_eq!(
+            error.to_string(),
+            "[line 2:10] Error at ';': Expect expression."
+        );
+        assert_eq!(
+            error.render("let a = 1;\nprint a +;"),
+            "Error at ';': Expect expression.\n  | [2:10] print a +;\n                    ^\n\n"
+        );
+        assert!(matches!(compile("\"a"), Err(RabbitError::Syntax(_))));
+    }
+
+    #[test]
+    fn runtime_errors_carry_the_failing_span() {
+        let mut vm = Vm::new();
+        let error = vm
+            .interpret(compile("let a = 1;\nprint -\"a\";").unwrap())
+            .unwrap_err();
+        assert_eq!(error.span(), Some(Span { line: 2, column: 7 }));
+        assert!(error.diagnostics().is_empty());
+        assert_eq!(
+            error.to_string(),
+            "Operand must be a number.\n[line 2:7] in script"
+        );
+        assert!(error.source().is_some());
+    }
+}
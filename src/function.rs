@@ -0,0 +1,62 @@
+use std::{cell::RefCell, fmt, rc::Rc};
+
+use crate::{chunk::Chunk, value::Value};
+
+/// A compiled rabbit function. Scripts are functions too, without a name.
+#[derive(Debug)]
+pub struct Function {
+    pub name: Option<Rc<str>>,
+    pub arity: usize,
+    pub chunk: Chunk,
+    /// Where each upvalue of a closure over this function comes from.
+    pub captures: Vec<Capture>,
+}
+
+impl PartialEq for Function {
+    fn eq(&self, other: &Function) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+impl fmt::Display for Function {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "<fn {name}>"),
+            None => write!(f, "<script>"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Capture {
+    /// A local variable of the enclosing function, by slot.
+    Local(usize),
+    /// An upvalue of the enclosing function, by index.
+    Upvalue(usize),
+}
+
+/// A function together with the variables it captured.
+pub struct Closure {
+    pub function: Rc<Function>,
+    pub upvalues: Vec<Rc<RefCell<Upvalue>>>,
+}
+
+impl fmt::Debug for Closure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Closure({})", self.function)
+    }
+}
+
+impl PartialEq for Closure {
+    fn eq(&self, other: &Closure) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+/// A captured variable. It stays on the stack while the function that
+/// declared it runs and moves into the upvalue when it goes out of scope.
+#[derive(Debug)]
+pub enum Upvalue {
+    Open(usize),
+    Closed(Value),
+}
@@ -0,0 +1,98 @@
+//! Rabbit is a small scripting language compiled to bytecode and run on a
+//! stack-based virtual machine.
+//!
+//! Running a script takes two steps: [`compile`] turns source code into a
+//! [`Chunk`] of bytecode, and a [`Vm`] executes it. A `Vm` keeps its globals
+//! between chunks, so it can be fed one piece of code after another.
+//!
+//! ```
+//! use rabbit::{compile, Value, Vm};
+//!
+//! let mut vm = Vm::new();
+//! vm.set_global("base", Value::Number(40.0));
+//! let chunk = compile("let answer = base + 2;").unwrap();
+//! assert_eq!(vm.interpret(chunk), Ok(Value::Null));
+//! let answer = vm.globals().find(|(name, _)| *name == "answer");
+//! assert_eq!(answer, Some(("answer", &Value::Number(42.0))));
+//! ```
+//!
+//! Scripts reach into the host through native functions, which take and
+//! return [`Value`]s. The [`FromValue`] and [`IntoValue`] traits convert
+//! between those and common Rust types.
+//!
+//! ```
+//! use rabbit::{compile, FromValue, IntoValue, RuntimeError, Value, Vm};
+//!
+//! fn shout(_vm: &mut Vm, arguments: &[Value]) -> Result<Value, RuntimeError> {
+//!     Ok(String::from_value(&arguments[0])?.to_uppercase().into_value())
+//! }
+//!
+//! let mut vm = Vm::new();
+//! vm.register_native("shout", 1, shout);
+//! vm.interpret(compile("print shout(\"hi\");").unwrap()).unwrap();
+//! ```
+//!
+//! Going the other way, [`Vm::get_global`] and [`Vm::call`] let Rust code
+//! call functions defined by scripts, including from inside a native
+//! function that was itself called by a script.
+//!
+//! ```
+//! use rabbit::{compile, Value, Vm};
+//!
+//! let mut vm = Vm::new();
+//! vm.interpret(compile("fn twice(x) { return x * 2; }").unwrap()).unwrap();
+//! let twice = vm.get_global("twice").unwrap().clone();
+//! assert_eq!(vm.call(&twice, &[Value::Number(21.0)]), Ok(Value::Number(42.0)));
+//! ```
+//!
+//! [`Vm::builder`] adds a standard library whose natives only do what the
+//! host granted them, as [`Capability`] values. Hosts running code they
+//! don't trust can also bound it with [`Limits`], and tell the resulting
+//! errors apart from other runtime errors.
+//!
+//! ```
+//! use rabbit::{compile, Limit, Limits, RabbitError, Vm};
+//!
+//! let mut vm = Vm::new();
+//! vm.set_limits(Limits { instructions: Some(10_000), ..Limits::default() });
+//! let result = vm.interpret(compile("while true {}").unwrap());
+//! let Err(RabbitError::LimitExceeded(error)) = result else { panic!() };
+//! assert_eq!(error.limit(), Some(Limit::Instructions));
+//! ```
+//!
+//! Tools that only need to look at source code can use the [`Scanner`]
+//! directly, [`check`] a script for errors without printing them, or
+//! classify its tokens with [`semantic::semantic_tokens`].
+
+pub mod assembler;
+pub mod chunk;
+pub mod class;
+pub mod compiler;
+mod debug;
+pub mod error;
+pub mod function;
+pub mod json;
+pub mod limits;
+#[cfg(feature = "nan_boxing")]
+pub mod nan_box;
+pub mod peephole;
+pub mod rbc;
+pub mod sandbox;
+pub mod scanner;
+pub mod semantic;
+mod stdlib;
+pub mod value;
+pub mod verifier;
+pub mod vm;
+
+pub use assembler::{assemble, AssembleError};
+pub use chunk::{Chunk, OpCode, Span};
+pub use class::{ClassBuilder, NativeClass, Userdata};
+pub use compiler::{check, compile, compile_repl};
+pub use error::RabbitError;
+pub use limits::{Limit, Limits};
+pub use sandbox::{Capability, VmBuilder};
+pub use scanner::{Diagnostic, Scanner, Token, TokenType};
+pub use value::{FromValue, IntoValue, NativeFn, Value, ValueKind};
+pub use verifier::{verify, VerifyError};
+pub use vm::{RuntimeError, TraceFrame, Vm};
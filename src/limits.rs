@@ -0,0 +1,40 @@
+use std::{fmt, time::Instant};
+
+/// Caps on what scripts run by a `Vm` may use, for hosts running code they
+/// don't trust. They are checked at calls and backward jumps, so every
+/// loop and recursion is bounded. `None` means no limit.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Limits {
+    /// Most instructions a script, or a call made with `Vm::call`, may
+    /// execute.
+    pub instructions: Option<u64>,
+    /// Most bytes a script may allocate for strings, lists and closures.
+    /// Memory freed along the way still counts, so this caps allocation
+    /// rather than what is alive at any one time.
+    pub heap_bytes: Option<usize>,
+    /// Most call frames, the script's own included.
+    pub stack_depth: Option<usize>,
+    /// When running scripts must stop.
+    pub deadline: Option<Instant>,
+}
+
+/// The limit a script went over.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    Instructions,
+    HeapBytes,
+    StackDepth,
+    Deadline,
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            Limit::Instructions => "Instruction limit exceeded.",
+            Limit::HeapBytes => "Memory limit exceeded.",
+            Limit::StackDepth => "Stack depth limit exceeded.",
+            Limit::Deadline => "Time limit exceeded.",
+        };
+        write!(f, "{message}")
+    }
+}
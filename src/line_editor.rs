@@ -0,0 +1,577 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, BufRead, IsTerminal, Read, Write},
+    path::PathBuf,
+    process::{Command, Stdio},
+};
+
+const MAX_HISTORY: usize = 1000;
+
+#[derive(Debug, PartialEq)]
+pub enum ReadLine {
+    Line(String),
+    /// The user pressed Ctrl-C, abandoning the current line.
+    Interrupted,
+    Eof,
+}
+
+/// A small readline replacement: cursor movement, history navigation,
+/// reverse search with Ctrl-R and tab completion. When stdin is not a
+/// terminal, or it can't be put in raw mode, lines are read as they come.
+pub struct LineEditor {
+    history: Vec<String>,
+    history_path: Option<PathBuf>,
+}
+
+impl LineEditor {
+    /// Creates an editor whose history is loaded from, and saved to,
+    /// `history_path`, keeping the last `MAX_HISTORY` entries.
+    pub fn new(history_path: Option<PathBuf>) -> LineEditor {
+        let history = history_path
+            .as_ref()
+            .and_then(|path| fs::read_to_string(path).ok())
+            .map(|contents| contents.lines().map(str::to_string).collect::<Vec<_>>())
+            .unwrap_or_default();
+        let skip = history.len().saturating_sub(MAX_HISTORY);
+        LineEditor {
+            history: history.into_iter().skip(skip).collect(),
+            history_path,
+        }
+    }
+    pub fn add_history(&mut self, line: &str) {
+        let line = line.trim_end_matches(['\r', '\n']);
+        if line.trim().is_empty() || self.history.last().is_some_and(|last| last == line) {
+            return;
+        }
+        self.history.push(line.to_string());
+        let Some(path) = &self.history_path else {
+            return;
+        };
+        // History is a convenience, so failing to save it is not an error.
+        if self.history.len() > MAX_HISTORY {
+            // The file is rewritten, rather than appended to, so it doesn't
+            // outgrow the history that is kept.
+            let skip = self.history.len() - MAX_HISTORY;
+            self.history.drain(..skip);
+            let _ = fs::write(path, self.history.join("\n") + "\n");
+        } else if let Ok(mut file) = OpenOptions::new().create(true).append(true).open(path) {
+            let _ = writeln!(file, "{line}");
+        }
+    }
+    /// Reads a line after printing `prompt`. `complete` receives the word
+    /// before the cursor and returns the candidates it could expand to.
+    pub fn read_line(
+        &mut self,
+        prompt: &str,
+        complete: &dyn Fn(&str) -> Vec<String>,
+    ) -> io::Result<ReadLine> {
+        let mut stdout = io::stdout().lock();
+        write!(stdout, "{prompt}")?;
+        stdout.flush()?;
+        if !io::stdin().is_terminal() || !io::stdout().is_terminal() {
+            return read_plain_line();
+        }
+        // Without `stty` the terminal stays cooked, which still reads lines.
+        let Ok(_raw_mode) = RawMode::enable() else {
+            return read_plain_line();
+        };
+        let mut stdin = io::stdin().lock();
+        let mut state = LineState::new(&self.history);
+        loop {
+            let key = read_key(&mut stdin)?;
+            let outcome = state.handle(key, complete);
+            match outcome {
+                Outcome::Done(result) => {
+                    // The caller ends the line itself at end of input.
+                    let end = if result == ReadLine::Eof { "" } else { "\r\n" };
+                    write!(stdout, "{}{end}", state.render(prompt))?;
+                    stdout.flush()?;
+                    return Ok(result);
+                }
+                Outcome::Candidates(candidates) => {
+                    write!(stdout, "\r\n{}\r\n", candidates.join("  "))?;
+                }
+                Outcome::ClearScreen => write!(stdout, "\x1b[H\x1b[2J")?,
+                Outcome::Continue => {}
+            }
+            write!(stdout, "{}", state.render(prompt))?;
+            stdout.flush()?;
+        }
+    }
+}
+
+/// Reads a line as the terminal, if any, delivers it.
+fn read_plain_line() -> io::Result<ReadLine> {
+    let mut line = String::new();
+    match io::stdin().lock().read_line(&mut line)? {
+        0 => Ok(ReadLine::Eof),
+        _ => Ok(ReadLine::Line(line)),
+    }
+}
+
+/// Puts the terminal in raw mode for as long as it is alive.
+struct RawMode {
+    saved: String,
+}
+
+impl RawMode {
+    fn enable() -> io::Result<RawMode> {
+        let saved = stty(&["-g"])?;
+        stty(&["raw", "-echo"])?;
+        Ok(RawMode { saved })
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        let _ = stty(&[self.saved.trim()]);
+    }
+}
+
+fn stty(args: &[&str]) -> io::Result<String> {
+    let output = Command::new("stty")
+        .args(args)
+        .stdin(Stdio::inherit())
+        .stderr(Stdio::null())
+        .output()?;
+    if !output.status.success() {
+        return Err(io::Error::other("stty failed"));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Key {
+    Char(char),
+    /// A control character, named by its letter.
+    Ctrl(char),
+    Enter,
+    Tab,
+    Backspace,
+    Delete,
+    Left,
+    Right,
+    Up,
+    Down,
+    Home,
+    End,
+    Escape,
+}
+
+fn read_byte(input: &mut impl Read) -> io::Result<u8> {
+    let mut byte = [0];
+    input.read_exact(&mut byte)?;
+    Ok(byte[0])
+}
+
+fn read_key(input: &mut impl Read) -> io::Result<Key> {
+    let key = match read_byte(input)? {
+        b'\r' | b'\n' => Key::Enter,
+        b'\t' => Key::Tab,
+        0x7f | 0x08 => Key::Backspace,
+        0x1b => read_escape(input)?,
+        byte @ 0x01..=0x1a => Key::Ctrl((b'a' + byte - 1) as char),
+        byte if byte < 0x80 => Key::Char(byte as char),
+        byte => {
+            let length = match byte {
+                0xc0..=0xdf => 2,
+                0xe0..=0xef => 3,
+                _ => 4,
+            };
+            let mut bytes = vec![byte];
+            for _ in 1..length {
+                bytes.push(read_byte(input)?);
+            }
+            let decoded = String::from_utf8_lossy(&bytes);
+            Key::Char(
+                decoded
+                    .chars()
+                    .next()
+                    .unwrap_or(char::REPLACEMENT_CHARACTER),
+            )
+        }
+    };
+    Ok(key)
+}
+
+fn read_escape(input: &mut impl Read) -> io::Result<Key> {
+    let introducer = read_byte(input)?;
+    if introducer != b'[' && introducer != b'O' {
+        return Ok(Key::Escape);
+    }
+    let key = match read_byte(input)? {
+        b'A' => Key::Up,
+        b'B' => Key::Down,
+        b'C' => Key::Right,
+        b'D' => Key::Left,
+        b'H' => Key::Home,
+        b'F' => Key::End,
+        digit @ b'0'..=b'9' => {
+            let mut code = vec![digit];
+            loop {
+                match read_byte(input)? {
+                    b'~' => break,
+                    byte if byte.is_ascii_digit() || byte == b';' => code.push(byte),
+                    _ => return Ok(Key::Escape),
+                }
+            }
+            match code.as_slice() {
+                b"1" | b"7" => Key::Home,
+                b"4" | b"8" => Key::End,
+                b"3" => Key::Delete,
+                _ => Key::Escape,
+            }
+        }
+        _ => Key::Escape,
+    };
+    Ok(key)
+}
+
+#[derive(Debug, PartialEq)]
+enum Outcome {
+    Continue,
+    Done(ReadLine),
+    Candidates(Vec<String>),
+    ClearScreen,
+}
+
+struct Search {
+    query: String,
+    /// Index of the history entry currently matching the query.
+    found: Option<usize>,
+}
+
+struct LineState<'a> {
+    buffer: Vec<char>,
+    cursor: usize,
+    history: &'a [String],
+    /// Position in history, `history.len()` being the line being edited.
+    history_index: usize,
+    edited: Vec<char>,
+    search: Option<Search>,
+}
+
+impl<'a> LineState<'a> {
+    fn new(history: &'a [String]) -> LineState<'a> {
+        LineState {
+            buffer: vec![],
+            cursor: 0,
+            history,
+            history_index: history.len(),
+            edited: vec![],
+            search: None,
+        }
+    }
+    fn line(&self) -> String {
+        self.buffer.iter().collect()
+    }
+    fn render(&self, prompt: &str) -> String {
+        let (prompt, before, after) = match &self.search {
+            Some(search) => {
+                let found = search.found.map_or("", |i| self.history[i].as_str());
+                (
+                    format!("(reverse-i-search)`{}': ", search.query),
+                    found.to_string(),
+                    String::new(),
+                )
+            }
+            None => (
+                prompt.to_string(),
+                self.buffer[..self.cursor].iter().collect(),
+                self.buffer[self.cursor..].iter().collect(),
+            ),
+        };
+        let column = prompt.chars().count() + before.chars().count();
+        let mut line = format!("\r{prompt}{before}{after}\x1b[K\r");
+        if column > 0 {
+            line.push_str(&format!("\x1b[{column}C"));
+        }
+        line
+    }
+    fn set_buffer(&mut self, buffer: Vec<char>) {
+        self.cursor = buffer.len();
+        self.buffer = buffer;
+    }
+    fn handle(&mut self, key: Key, complete: &dyn Fn(&str) -> Vec<String>) -> Outcome {
+        if self.search.is_some() {
+            return self.handle_search(key);
+        }
+        match key {
+            Key::Char(c) => {
+                self.buffer.insert(self.cursor, c);
+                self.cursor += 1;
+            }
+            Key::Enter => return Outcome::Done(ReadLine::Line(self.line() + "\n")),
+            Key::Ctrl('c') => return Outcome::Done(ReadLine::Interrupted),
+            Key::Ctrl('d') if self.buffer.is_empty() => return Outcome::Done(ReadLine::Eof),
+            Key::Ctrl('d') | Key::Delete => {
+                if self.cursor < self.buffer.len() {
+                    self.buffer.remove(self.cursor);
+                }
+            }
+            Key::Backspace => {
+                if self.cursor > 0 {
+                    self.cursor -= 1;
+                    self.buffer.remove(self.cursor);
+                }
+            }
+            Key::Left | Key::Ctrl('b') => self.cursor = self.cursor.saturating_sub(1),
+            Key::Right | Key::Ctrl('f') => self.cursor = (self.cursor + 1).min(self.buffer.len()),
+            Key::Home | Key::Ctrl('a') => self.cursor = 0,
+            Key::End | Key::Ctrl('e') => self.cursor = self.buffer.len(),
+            Key::Ctrl('k') => self.buffer.truncate(self.cursor),
+            Key::Ctrl('u') => {
+                self.buffer.drain(..self.cursor);
+                self.cursor = 0;
+            }
+            Key::Ctrl('w') => {
+                let mut start = self.cursor;
+                while start > 0 && self.buffer[start - 1].is_whitespace() {
+                    start -= 1;
+                }
+                while start > 0 && !self.buffer[start - 1].is_whitespace() {
+                    start -= 1;
+                }
+                self.buffer.drain(start..self.cursor);
+                self.cursor = start;
+            }
+            Key::Up | Key::Ctrl('p') => self.history_step(-1),
+            Key::Down | Key::Ctrl('n') => self.history_step(1),
+            Key::Ctrl('r') => {
+                self.search = Some(Search {
+                    query: String::new(),
+                    found: None,
+                })
+            }
+            Key::Ctrl('l') => return Outcome::ClearScreen,
+            Key::Tab => return self.complete(complete),
+            Key::Ctrl(_) | Key::Escape => {}
+        }
+        Outcome::Continue
+    }
+    fn handle_search(&mut self, key: Key) -> Outcome {
+        let Some(search) = &mut self.search else {
+            return Outcome::Continue;
+        };
+        match key {
+            Key::Char(c) => {
+                search.query.push(c);
+                let before = search.found.map_or(self.history.len(), |i| i + 1);
+                search.found = find_before(self.history, &search.query, before);
+            }
+            Key::Backspace => {
+                search.query.pop();
+                search.found = find_before(self.history, &search.query, self.history.len());
+            }
+            Key::Ctrl('r') => {
+                let before = search.found.unwrap_or(self.history.len());
+                if let Some(found) = find_before(self.history, &search.query, before) {
+                    search.found = Some(found);
+                }
+            }
+            Key::Ctrl('c') | Key::Ctrl('g') => self.search = None,
+            Key::Enter => {
+                let found = search.found;
+                self.search = None;
+                if let Some(i) = found {
+                    self.set_buffer(self.history[i].chars().collect());
+                }
+                return Outcome::Done(ReadLine::Line(self.line() + "\n"));
+            }
+            _ => {
+                if let Some(i) = search.found {
+                    self.history_index = i;
+                    self.set_buffer(self.history[i].chars().collect());
+                }
+                self.search = None;
+            }
+        }
+        Outcome::Continue
+    }
+    fn history_step(&mut self, step: isize) {
+        let Some(index) = self.history_index.checked_add_signed(step) else {
+            return;
+        };
+        if index > self.history.len() {
+            return;
+        }
+        if self.history_index == self.history.len() {
+            self.edited = self.buffer.clone();
+        }
+        self.history_index = index;
+        let buffer = match self.history.get(index) {
+            Some(entry) => entry.chars().collect(),
+            None => self.edited.clone(),
+        };
+        self.set_buffer(buffer);
+    }
+    fn word_start(&self, is_word: impl Fn(&char) -> bool) -> usize {
+        self.buffer[..self.cursor]
+            .iter()
+            .rposition(|c| !is_word(c))
+            .map_or(0, |i| i + 1)
+    }
+    fn complete(&mut self, complete: &dyn Fn(&str) -> Vec<String>) -> Outcome {
+        let start = self.word_start(|c| c.is_alphanumeric() || *c == '_' || *c == '.');
+        let word: String = self.buffer[start..self.cursor].iter().collect();
+        let mut candidates: Vec<String> = complete(&word)
+            .into_iter()
+            .filter(|candidate| candidate.starts_with(&word))
+            .collect();
+        candidates.sort();
+        candidates.dedup();
+        let Some(first) = candidates.first() else {
+            return Outcome::Continue;
+        };
+        let common = candidates.iter().fold(first.as_str(), |common, candidate| {
+            let length = common
+                .char_indices()
+                .zip(candidate.chars())
+                .take_while(|((_, a), b)| a == b)
+                .last()
+                .map_or(0, |((i, a), _)| i + a.len_utf8());
+            &common[..length]
+        });
+        let mut insertion: Vec<char> = common[word.len()..].chars().collect();
+        if candidates.len() == 1 {
+            insertion.push(' ');
+        }
+        let inserted = insertion.len();
+        self.buffer.splice(self.cursor..self.cursor, insertion);
+        self.cursor += inserted;
+        if candidates.len() > 1 && inserted == 0 {
+            return Outcome::Candidates(candidates);
+        }
+        Outcome::Continue
+    }
+}
+
+fn find_before(history: &[String], query: &str, before: usize) -> Option<usize> {
+    history[..before.min(history.len())]
+        .iter()
+        .rposition(|entry| entry.contains(query))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(bytes: &[u8]) -> Vec<Key> {
+        let mut input = bytes;
+        let mut keys = vec![];
+        while !input.is_empty() {
+            keys.push(read_key(&mut input).unwrap());
+        }
+        keys
+    }
+
+    fn type_keys(state: &mut LineState, bytes: &[u8]) -> Outcome {
+        let complete = |_: &str| vec![String::from("print"), String::from("pi")];
+        let mut outcome = Outcome::Continue;
+        for key in keys(bytes) {
+            outcome = state.handle(key, &complete);
+        }
+        outcome
+    }
+
+    #[test]
+    fn keys_are_decoded() {
+        assert_eq!(
+            keys(b"a\x1b[D\x1b[3~\x01\x7f\r\x1bOH\x1b[4~\x12\t\xc3\xa9"),
+            vec![
+                Key::Char('a'),
+                Key::Left,
+                Key::Delete,
+                Key::Ctrl('a'),
+                Key::Backspace,
+                Key::Enter,
+                Key::Home,
+                Key::End,
+                Key::Ctrl('r'),
+                Key::Tab,
+                Key::Char('é'),
+            ]
+        );
+    }
+
+    #[test]
+    fn cursor_movement_and_editing_work() {
+        let mut state = LineState::new(&[]);
+        type_keys(&mut state, b"print 1\x1b[D\x1b[D2\x01\x1b[3~P\x05;");
+        assert_eq!(state.line(), "Print2 1;");
+        type_keys(&mut state, b"\x17");
+        assert_eq!(state.line(), "Print2 ");
+        assert_eq!(
+            type_keys(&mut state, b"\x15x\r"),
+            Outcome::Done(ReadLine::Line(String::from("x\n")))
+        );
+    }
+
+    #[test]
+    fn history_navigation_keeps_the_edited_line() {
+        let history = vec![String::from("let a = 1;"), String::from("print a;")];
+        let mut state = LineState::new(&history);
+        type_keys(&mut state, b"draft\x1b[A");
+        assert_eq!(state.line(), "print a;");
+        type_keys(&mut state, b"\x1b[A\x1b[A");
+        assert_eq!(state.line(), "let a = 1;");
+        type_keys(&mut state, b"\x1b[B\x1b[B");
+        assert_eq!(state.line(), "draft");
+    }
+
+    #[test]
+    fn reverse_search_finds_older_matches() {
+        let history = vec![
+            String::from("let a = 1;"),
+            String::from("let b = 2;"),
+            String::from("print b;"),
+        ];
+        let mut state = LineState::new(&history);
+        type_keys(&mut state, b"\x12let");
+        assert_eq!(state.search.as_ref().unwrap().found, Some(1));
+        type_keys(&mut state, b"\x12\x1b[C");
+        assert!(state.search.is_none());
+        assert_eq!(state.line(), "let a = 1;");
+    }
+
+    #[test]
+    fn saved_history_is_capped() {
+        let path = std::env::temp_dir().join(format!("rabbit-history-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let mut editor = LineEditor::new(Some(path.clone()));
+        for i in 0..MAX_HISTORY + 5 {
+            editor.add_history(&format!("print {i};"));
+        }
+        let saved = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let lines: Vec<&str> = saved.lines().collect();
+        assert_eq!(lines.len(), MAX_HISTORY);
+        assert_eq!(lines[0], "print 5;");
+        assert_eq!(editor.history, lines);
+    }
+
+    #[test]
+    fn tab_completes_common_prefix() {
+        let mut state = LineState::new(&[]);
+        assert_eq!(
+            type_keys(&mut state, b"p\t"),
+            Outcome::Candidates(vec![String::from("pi"), String::from("print")])
+        );
+        assert_eq!(state.line(), "p");
+        type_keys(&mut state, b"r\t");
+        assert_eq!(state.line(), "print ");
+    }
+
+    #[test]
+    fn tab_completes_properties_after_a_dot() {
+        let mut state = LineState::new(&[]);
+        let complete = |word: &str| match word {
+            "p.m" => vec![String::from("p.move")],
+            _ => vec![],
+        };
+        for key in keys(b"print p.m\t") {
+            state.handle(key, &complete);
+        }
+        assert_eq!(state.line(), "print p.move ");
+    }
+}
@@ -0,0 +1,691 @@
+use std::{
+    collections::HashMap,
+    io::{self, BufRead, Write},
+    ops::Range,
+};
+
+use rabbit::{
+    compiler,
+    json::Json,
+    scanner::{Diagnostic, Scanner, Token, TokenType, KEYWORDS},
+    semantic::{semantic_tokens, SemanticKind},
+};
+
+/// Runs a language server speaking LSP over stdin/stdout until the client
+/// sends `exit`, returning the process exit code.
+pub fn run() -> io::Result<i32> {
+    let mut stdin = io::stdin().lock();
+    let mut stdout = io::stdout().lock();
+    let mut server = Server::new();
+    while let Some(message) = read_message(&mut stdin)? {
+        let message = match Json::parse(&message) {
+            Ok(message) => message,
+            Err(error) => {
+                let response = error_response(Json::Null, -32700, &error);
+                write_message(&mut stdout, &response)?;
+                continue;
+            }
+        };
+        for outgoing in server.handle(&message) {
+            write_message(&mut stdout, &outgoing)?;
+        }
+        if let Some(code) = server.exit_code {
+            return Ok(code);
+        }
+    }
+    Ok(1)
+}
+
+/// The largest message body accepted, far more than any source file the
+/// server is meant for.
+const MAX_MESSAGE: usize = 64 << 20;
+
+fn read_message(input: &mut impl BufRead) -> io::Result<Option<String>> {
+    let mut content_length = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("Content-Length") {
+                content_length = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
+    let length = content_length.ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidData, "Missing Content-Length header")
+    })?;
+    if length > MAX_MESSAGE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Content-Length {length} is over the limit of {MAX_MESSAGE} bytes"),
+        ));
+    }
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+    String::from_utf8(body)
+        .map(Some)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn write_message(output: &mut impl Write, message: &Json) -> io::Result<()> {
+    let body = message.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{body}", body.len())?;
+    output.flush()
+}
+
+fn response(id: Json, result: Json) -> Json {
+    Json::object([("jsonrpc", "2.0".into()), ("id", id), ("result", result)])
+}
+
+fn error_response(id: Json, code: i32, message: &str) -> Json {
+    Json::object([
+        ("jsonrpc", "2.0".into()),
+        ("id", id),
+        (
+            "error",
+            Json::object([
+                ("code", Json::Number(code as f64)),
+                ("message", message.into()),
+            ]),
+        ),
+    ])
+}
+
+fn notification(method: &str, params: Json) -> Json {
+    Json::object([
+        ("jsonrpc", "2.0".into()),
+        ("method", method.into()),
+        ("params", params),
+    ])
+}
+
+struct Server {
+    documents: HashMap<String, Document>,
+    shutdown_requested: bool,
+    exit_code: Option<i32>,
+}
+
+impl Server {
+    fn new() -> Server {
+        Server {
+            documents: HashMap::new(),
+            shutdown_requested: false,
+            exit_code: None,
+        }
+    }
+    fn handle(&mut self, message: &Json) -> Vec<Json> {
+        let method = message.get("method").and_then(Json::as_str).unwrap_or("");
+        let params = message.get("params").unwrap_or(&Json::Null);
+        let Some(id) = message.get("id").cloned() else {
+            return self.handle_notification(method, params);
+        };
+        if self.shutdown_requested && method != "exit" {
+            return vec![error_response(id, -32600, "Server is shutting down.")];
+        }
+        let result = match method {
+            "initialize" => Some(Self::initialize()),
+            "shutdown" => {
+                self.shutdown_requested = true;
+                Some(Json::Null)
+            }
+            "textDocument/definition" => self.with_document(params, Self::definition),
+            "textDocument/references" => self.with_document(params, Self::references),
+            "textDocument/hover" => self.with_document(params, Self::hover),
+            "textDocument/completion" => self.with_document(params, Self::completion),
+            "textDocument/semanticTokens/full" => params
+                .path(&["textDocument", "uri"])
+                .and_then(Json::as_str)
+                .and_then(|uri| self.documents.get(uri))
+                .map(Document::semantic_tokens),
+            _ => None,
+        };
+        match result {
+            Some(result) => vec![response(id, result)],
+            None if method.starts_with("textDocument/") => vec![response(id, Json::Null)],
+            None => vec![error_response(
+                id,
+                -32601,
+                &format!("Unknown method {method}."),
+            )],
+        }
+    }
+    fn handle_notification(&mut self, method: &str, params: &Json) -> Vec<Json> {
+        let uri = params
+            .path(&["textDocument", "uri"])
+            .and_then(Json::as_str)
+            .map(str::to_string);
+        match (method, uri) {
+            ("exit", _) => {
+                self.exit_code = Some(if self.shutdown_requested { 0 } else { 1 });
+                vec![]
+            }
+            ("textDocument/didOpen", Some(uri)) => {
+                let text = params
+                    .path(&["textDocument", "text"])
+                    .and_then(Json::as_str);
+                self.update(uri, text.unwrap_or(""))
+            }
+            ("textDocument/didChange", Some(uri)) => {
+                // We only advertise full document sync, so the last change
+                // always carries the whole text.
+                let text = params
+                    .get("contentChanges")
+                    .and_then(Json::as_array)
+                    .and_then(|changes| changes.last())
+                    .and_then(|change| change.get("text"))
+                    .and_then(Json::as_str);
+                match text {
+                    Some(text) => self.update(uri, text),
+                    None => vec![],
+                }
+            }
+            ("textDocument/didClose", Some(uri)) => {
+                self.documents.remove(&uri);
+                vec![publish_diagnostics(&uri, Json::Array(vec![]))]
+            }
+            _ => vec![],
+        }
+    }
+    fn initialize() -> Json {
+        Json::object([
+            (
+                "capabilities",
+                Json::object([
+                    ("textDocumentSync", 1.into()),
+                    ("definitionProvider", true.into()),
+                    ("referencesProvider", true.into()),
+                    ("hoverProvider", true.into()),
+                    ("completionProvider", Json::object([])),
+                    (
+                        "semanticTokensProvider",
+                        Json::object([
+                            (
+                                "legend",
+                                Json::object([
+                                    (
+                                        "tokenTypes",
+                                        Json::Array(
+                                            SemanticKind::LEGEND
+                                                .iter()
+                                                .map(|kind| kind.name().into())
+                                                .collect(),
+                                        ),
+                                    ),
+                                    ("tokenModifiers", Json::Array(vec!["declaration".into()])),
+                                ]),
+                            ),
+                            ("full", true.into()),
+                        ]),
+                    ),
+                ]),
+            ),
+            (
+                "serverInfo",
+                Json::object([
+                    ("name", "rabbit".into()),
+                    ("version", env!("CARGO_PKG_VERSION").into()),
+                ]),
+            ),
+        ])
+    }
+    fn update(&mut self, uri: String, text: &str) -> Vec<Json> {
+        let document = Document::new(text.to_string());
+        let diagnostics = document.diagnostics();
+        self.documents.insert(uri.clone(), document);
+        vec![publish_diagnostics(&uri, diagnostics)]
+    }
+    fn with_document(
+        &self,
+        params: &Json,
+        handler: fn(&Document, &str, usize, &Json) -> Json,
+    ) -> Option<Json> {
+        let uri = params.path(&["textDocument", "uri"])?.as_str()?;
+        let document = self.documents.get(uri)?;
+        let line = params.path(&["position", "line"])?.as_usize()?;
+        let character = params.path(&["position", "character"])?.as_usize()?;
+        let offset = document.offset(line, character);
+        Some(handler(document, uri, offset, params))
+    }
+    fn definition(document: &Document, uri: &str, offset: usize, _: &Json) -> Json {
+        match document.definition_at(offset) {
+            Some(declaration) => document.location(uri, &declaration.span),
+            None => Json::Null,
+        }
+    }
+    fn references(document: &Document, uri: &str, offset: usize, params: &Json) -> Json {
+        let include_declaration = params
+            .path(&["context", "includeDeclaration"])
+            .and_then(Json::as_bool)
+            .unwrap_or(true);
+        Json::Array(
+            document
+                .references_at(offset, include_declaration)
+                .iter()
+                .map(|span| document.location(uri, span))
+                .collect(),
+        )
+    }
+    fn hover(document: &Document, _: &str, offset: usize, _: &Json) -> Json {
+        match document.definition_at(offset) {
+            Some(declaration) => Json::object([(
+                "contents",
+                Json::object([
+                    ("kind", "markdown".into()),
+                    (
+                        "value",
+                        format!("```rabbit\n{}\n```", declaration.detail).into(),
+                    ),
+                ]),
+            )]),
+            None => Json::Null,
+        }
+    }
+    fn completion(document: &Document, _: &str, _: usize, _: &Json) -> Json {
+        Json::Array(
+            document
+                .completions()
+                .into_iter()
+                .map(|(label, kind)| Json::object([("label", label.into()), ("kind", kind.into())]))
+                .collect(),
+        )
+    }
+}
+
+fn publish_diagnostics(uri: &str, diagnostics: Json) -> Json {
+    notification(
+        "textDocument/publishDiagnostics",
+        Json::object([("uri", uri.into()), ("diagnostics", diagnostics)]),
+    )
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum DeclarationKind {
+    Variable,
+    Function,
+    Class,
+}
+
+impl DeclarationKind {
+    /// The matching LSP `CompletionItemKind`.
+    fn completion_kind(self) -> usize {
+        match self {
+            DeclarationKind::Variable => 6,
+            DeclarationKind::Function => 3,
+            DeclarationKind::Class => 7,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Declaration {
+    kind: DeclarationKind,
+    name: String,
+    span: Range<usize>,
+    detail: String,
+}
+
+/// An open text document along with everything we know about it. Symbols
+/// are resolved by name only, ignoring scopes.
+struct Document {
+    source: String,
+    line_starts: Vec<usize>,
+    tokens: Vec<Token>,
+    errors: Vec<Diagnostic>,
+    declarations: Vec<Declaration>,
+}
+
+impl Document {
+    fn new(source: String) -> Document {
+        let line_starts = std::iter::once(0)
+            .chain(source.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+        let (tokens, _) = Scanner::new(&source).scan();
+        let errors = compiler::check(&source);
+        let declarations = declarations(&source, &tokens);
+        Document {
+            source,
+            line_starts,
+            tokens,
+            errors,
+            declarations,
+        }
+    }
+    fn lexeme(&self, token: &Token) -> &str {
+        &self.source[token.span.clone()]
+    }
+    /// Converts a byte offset into an LSP position, whose character is
+    /// counted in UTF-16 code units. Offsets inside a character count as
+    /// its start.
+    fn line_character(&self, offset: usize) -> (usize, usize) {
+        let mut offset = offset.min(self.source.len());
+        while !self.source.is_char_boundary(offset) {
+            offset -= 1;
+        }
+        let line = self.line_starts.partition_point(|&start| start <= offset) - 1;
+        let start = self.line_starts[line];
+        let character = self.source[start..offset]
+            .chars()
+            .map(char::len_utf16)
+            .sum();
+        (line, character)
+    }
+    fn position(&self, offset: usize) -> Json {
+        let (line, character) = self.line_character(offset);
+        Json::object([("line", line.into()), ("character", character.into())])
+    }
+    fn offset(&self, line: usize, character: usize) -> usize {
+        let Some(&start) = self.line_starts.get(line) else {
+            return self.source.len();
+        };
+        let mut units = 0;
+        for (i, c) in self.source[start..].char_indices() {
+            if units >= character || c == '\n' {
+                return start + i;
+            }
+            units += c.len_utf16();
+        }
+        self.source.len()
+    }
+    fn range(&self, span: &Range<usize>) -> Json {
+        Json::object([
+            ("start", self.position(span.start)),
+            ("end", self.position(span.end)),
+        ])
+    }
+    fn location(&self, uri: &str, span: &Range<usize>) -> Json {
+        Json::object([("uri", uri.into()), ("range", self.range(span))])
+    }
+    fn diagnostics(&self) -> Json {
+        Json::Array(
+            self.errors
+                .iter()
+                .map(|error| {
+                    Json::object([
+                        ("range", self.range(&error.token.span)),
+                        ("severity", 1.into()),
+                        ("source", "rabbit".into()),
+                        ("message", error.message.as_str().into()),
+                    ])
+                })
+                .collect(),
+        )
+    }
+    fn identifier_at(&self, offset: usize) -> Option<&Token> {
+        self.tokens.iter().find(|token| {
+            token.token_type == TokenType::Identifier
+                && token.span.start <= offset
+                && offset <= token.span.end
+        })
+    }
+    /// Resolves the identifier under the cursor to the closest preceding
+    /// declaration of that name, falling back to a later one so functions
+    /// and classes can be used before they are declared.
+    fn definition_at(&self, offset: usize) -> Option<&Declaration> {
+        let token = self.identifier_at(offset)?;
+        let name = self.lexeme(token);
+        let mut candidates = self.declarations.iter().filter(|d| d.name == name);
+        let first = candidates.next()?;
+        Some(
+            std::iter::once(first)
+                .chain(candidates)
+                .take_while(|d| d.span.start <= token.span.start)
+                .last()
+                .unwrap_or(first),
+        )
+    }
+    fn references_at(&self, offset: usize, include_declaration: bool) -> Vec<Range<usize>> {
+        let Some(token) = self.identifier_at(offset) else {
+            return vec![];
+        };
+        let name = self.lexeme(token);
+        self.tokens
+            .iter()
+            .filter(|t| t.token_type == TokenType::Identifier && self.lexeme(t) == name)
+            .filter(|t| include_declaration || !self.declarations.iter().any(|d| d.span == t.span))
+            .map(|t| t.span.clone())
+            .collect()
+    }
+    /// Encodes the document's semantic tokens relative to each other, as
+    /// LSP expects. Tokens spanning several lines are cut at the first one.
+    fn semantic_tokens(&self) -> Json {
+        let (tokens, _) = semantic_tokens(&self.source);
+        let mut data = vec![];
+        let (mut previous_line, mut previous_character) = (0, 0);
+        for token in tokens {
+            let Some(kind) = token.kind.legend_index() else {
+                continue;
+            };
+            let (line, character) = self.line_character(token.span.start);
+            let text = &self.source[token.span.clone()];
+            let text = text.split('\n').next().unwrap_or(text);
+            let length: usize = text.chars().map(char::len_utf16).sum();
+            let delta_character = if line == previous_line {
+                character - previous_character
+            } else {
+                character
+            };
+            data.extend([
+                line - previous_line,
+                delta_character,
+                length,
+                kind,
+                token.declaration as usize,
+            ]);
+            (previous_line, previous_character) = (line, character);
+        }
+        Json::object([(
+            "data",
+            Json::Array(data.into_iter().map(Json::from).collect()),
+        )])
+    }
+    fn completions(&self) -> Vec<(String, usize)> {
+        // 14 is the LSP `CompletionItemKind` for keywords.
+        let mut items: Vec<(String, usize)> = KEYWORDS
+            .iter()
+            .map(|(keyword, _)| (keyword.to_string(), 14))
+            .collect();
+        for declaration in &self.declarations {
+            if !items.iter().any(|(label, _)| *label == declaration.name) {
+                items.push((declaration.name.clone(), declaration.kind.completion_kind()));
+            }
+        }
+        items
+    }
+}
+
+fn declarations(source: &str, tokens: &[Token]) -> Vec<Declaration> {
+    let lexeme = |token: &Token| &source[token.span.clone()];
+    let token_type = |i: usize| tokens.get(i).map(|t| t.token_type);
+    let mut declarations = vec![];
+    for (i, window) in tokens.windows(2).enumerate() {
+        let (keyword, name) = (&window[0], &window[1]);
+        if name.token_type != TokenType::Identifier {
+            continue;
+        }
+        let (kind, detail) = match keyword.token_type {
+            TokenType::Let => {
+                let inferred = match (token_type(i + 2), token_type(i + 3)) {
+                    (Some(TokenType::Equal), Some(TokenType::Number)) => ": number",
+                    (Some(TokenType::Equal), Some(TokenType::String)) => ": string",
+                    (Some(TokenType::Equal), Some(TokenType::True | TokenType::False)) => ": bool",
+                    (Some(TokenType::Equal), Some(TokenType::Null)) => ": null",
+                    (Some(TokenType::Equal), Some(TokenType::Fn)) => ": fn",
+                    _ => "",
+                };
+                let detail = format!("let {}{inferred}", lexeme(name));
+                (DeclarationKind::Variable, detail)
+            }
+            TokenType::Fn => {
+                let parameters: Vec<&str> = tokens[i + 2..]
+                    .iter()
+                    .skip_while(|t| t.token_type == TokenType::LeftParen)
+                    .take_while(|t| t.token_type != TokenType::RightParen)
+                    .filter(|t| t.token_type == TokenType::Identifier)
+                    .map(lexeme)
+                    .collect();
+                let detail = format!("fn {}({})", lexeme(name), parameters.join(", "));
+                (DeclarationKind::Function, detail)
+            }
+            TokenType::Class => {
+                let detail = match (token_type(i + 2), tokens.get(i + 3)) {
+                    (Some(TokenType::Extends), Some(base))
+                        if base.token_type == TokenType::Identifier =>
+                    {
+                        format!("class {} extends {}", lexeme(name), lexeme(base))
+                    }
+                    _ => format!("class {}", lexeme(name)),
+                };
+                (DeclarationKind::Class, detail)
+            }
+            _ => continue,
+        };
+        declarations.push(Declaration {
+            kind,
+            name: lexeme(name).to_string(),
+            span: name.span.clone(),
+            detail,
+        });
+    }
+    declarations
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open(server: &mut Server, text: &str) -> Vec<Json> {
+        server.handle(&Json::object([
+            ("jsonrpc", "2.0".into()),
+            ("method", "textDocument/didOpen".into()),
+            (
+                "params",
+                Json::object([(
+                    "textDocument",
+                    Json::object([("uri", "file:///a.rb".into()), ("text", text.into())]),
+                )]),
+            ),
+        ]))
+    }
+
+    fn request(server: &mut Server, method: &str, line: usize, character: usize) -> Json {
+        let mut responses = server.handle(&Json::object([
+            ("jsonrpc", "2.0".into()),
+            ("id", 1.into()),
+            ("method", method.into()),
+            (
+                "params",
+                Json::object([
+                    (
+                        "textDocument",
+                        Json::object([("uri", "file:///a.rb".into())]),
+                    ),
+                    (
+                        "position",
+                        Json::object([("line", line.into()), ("character", character.into())]),
+                    ),
+                ]),
+            ),
+        ]));
+        responses.pop().unwrap().get("result").unwrap().clone()
+    }
+
+    #[test]
+    fn diagnostics_are_published_on_open() {
+        let mut server = Server::new();
+        let messages = open(&mut server, "let a = 1;\n  #");
+        let diagnostics = messages[0].path(&["params", "diagnostics"]).unwrap();
+        assert_eq!(
+            diagnostics.to_string(),
+            r#"[{"range":{"start":{"line":1,"character":2},"end":{"line":1,"character":3}},"severity":1,"source":"rabbit","message":"Unexpected character #."}]"#
+        );
+    }
+
+    #[test]
+    fn multibyte_characters_are_counted_in_utf16() {
+        let mut server = Server::new();
+        let messages = open(&mut server, "let é = 1;\nlet 😀x = 2;");
+        let diagnostics = messages[0].path(&["params", "diagnostics"]).unwrap();
+        let ranges: Vec<String> = diagnostics
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|diagnostic| diagnostic.get("range").unwrap().to_string())
+            .collect();
+        assert_eq!(
+            ranges,
+            vec![
+                r#"{"start":{"line":0,"character":4},"end":{"line":0,"character":5}}"#,
+                r#"{"start":{"line":1,"character":4},"end":{"line":1,"character":6}}"#,
+            ]
+        );
+        let document = Document::new(String::from("é"));
+        assert_eq!(document.line_character(1), (0, 0));
+    }
+
+    #[test]
+    fn definition_and_references_work() {
+        let mut server = Server::new();
+        open(&mut server, "fn add(a, b) {}\nlet x = 1;\nadd(x, x);");
+        let definition = request(&mut server, "textDocument/definition", 2, 1);
+        assert_eq!(
+            definition.get("range").unwrap().to_string(),
+            r#"{"start":{"line":0,"character":3},"end":{"line":0,"character":6}}"#
+        );
+        let references = request(&mut server, "textDocument/references", 1, 4);
+        assert_eq!(references.as_array().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn hover_infers_kind() {
+        let document = Document::new(String::from(
+            "let s = \"hi\";\nclass B extends A {}\nfn f(a, b) {}\nprint s;",
+        ));
+        let detail = |offset| document.definition_at(offset).unwrap().detail.as_str();
+        assert_eq!(detail(document.offset(3, 6)), "let s: string");
+        assert_eq!(detail(document.offset(1, 6)), "class B extends A");
+        assert_eq!(detail(document.offset(2, 3)), "fn f(a, b)");
+    }
+
+    #[test]
+    fn completion_includes_keywords_and_declarations() {
+        let document = Document::new(String::from("let total = 0;"));
+        let completions = document.completions();
+        assert!(completions.contains(&("while".to_string(), 14)));
+        assert!(completions.contains(&("total".to_string(), 6)));
+    }
+
+    #[test]
+    fn semantic_tokens_are_delta_encoded() {
+        let document = Document::new(String::from("let a;\n  fn f() {}"));
+        assert_eq!(
+            document.semantic_tokens().to_string(),
+            r#"{"data":[0,0,3,0,0,0,4,1,9,1,1,2,2,0,0,0,3,1,5,1]}"#
+        );
+    }
+
+    #[test]
+    fn oversized_messages_are_rejected() {
+        let header = format!("Content-Length: {}\r\n\r\n", usize::MAX);
+        let error = read_message(&mut header.as_bytes()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        let message = read_message(&mut "Content-Length: 2\r\n\r\n{}".as_bytes()).unwrap();
+        assert_eq!(message.as_deref(), Some("{}"));
+    }
+
+    #[test]
+    fn exit_code_depends_on_shutdown() {
+        let mut server = Server::new();
+        server.handle(&Json::object([
+            ("id", 1.into()),
+            ("method", "shutdown".into()),
+        ]));
+        server.handle(&Json::object([("method", "exit".into())]));
+        assert_eq!(server.exit_code, Some(0));
+    }
+}
@@ -1,66 +1,186 @@
 use std::{
-    env, fs,
-    io::{self, BufRead, Write},
+    env,
+    fs::{self, File},
+    io::{self, Read, Write},
     process,
+    rc::Rc,
 };
 
-use chunk::Chunk;
-use vm::Vm;
+use cli::{Command, DebugOptions, Source, TokensFormat};
+use rabbit::{compiler, json, rbc, semantic, verify, Chunk, RabbitError, Value, Vm};
 
-use crate::scanner::Scanner;
-
-mod chunk;
-mod debug;
-mod scanner;
-mod value;
-mod vm;
+mod cli;
+mod line_editor;
+mod lsp;
+mod repl;
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
-    if args.len() == 1 {
-        repl();
-    } else if args.len() == 2 {
-        run_file(&args[1]);
-    } else {
-        eprintln!("Usage: clox [path]");
-        process::exit(64);
+    let args: Vec<String> = env::args().skip(1).collect();
+    let (command, options) = match cli::parse(&args) {
+        Ok(parsed) => parsed,
+        Err(message) => {
+            eprintln!("{message}\n\n{}", cli::USAGE);
+            process::exit(64);
+        }
+    };
+    match command {
+        Command::Run { source, args } => run(&source, args, &options),
+        Command::Check(source) => check(&source, &options),
+        Command::Compile { source, output } => compile(&source, &output, &options),
+        Command::Repl => repl::run(&options),
+        Command::Tokens { format, source } => tokens(format, &source),
+        Command::Lsp => match lsp::run() {
+            Ok(code) => process::exit(code),
+            Err(error) => {
+                eprintln!("Language server error: {error}");
+                process::exit(74);
+            }
+        },
+        Command::Help => println!("{}", cli::USAGE),
+        Command::Version => println!("rabbit {}", env!("CARGO_PKG_VERSION")),
     }
 }
 
-fn repl() {
-    let mut stdin = io::stdin().lock();
-    let chunk = Chunk::new();
-    let mut vm = Vm::new(chunk);
-    loop {
-        print!(">> ");
-        io::stdout().flush().unwrap();
+fn source_name(source: &Source) -> &str {
+    match source {
+        Source::File(path) => path,
+        Source::Stdin => "<stdin>",
+        Source::Code(_) => "<code>",
+    }
+}
 
-        let mut line = String::new();
-        match stdin.read_line(&mut line) {
-            Ok(_) => {
-                let scanner = Scanner::new(&line);
-                match scanner.scan_tokens() {
-                    Ok(tokens) => println!("{:?}", tokens),
-                    Err(_) => process::exit(65),
-                }
-            }
-            Err(_) => {
-                eprintln!("Error reading line");
-                process::exit(74);
-            }
+fn read_bytes(source: &Source) -> Vec<u8> {
+    let result = match source {
+        Source::File(path) => fs::read(path),
+        Source::Stdin => {
+            let mut bytes = vec![];
+            io::stdin().read_to_end(&mut bytes).map(|_| bytes)
+        }
+        Source::Code(code) => return code.clone().into_bytes(),
+    };
+    result.unwrap_or_else(|error| {
+        eprintln!("Could not read '{}': {error}.", source_name(source));
+        process::exit(66);
+    })
+}
+
+fn read_source(source: &Source) -> String {
+    into_text(source, read_bytes(source))
+}
+
+fn into_text(source: &Source, bytes: Vec<u8>) -> String {
+    String::from_utf8(bytes).unwrap_or_else(|error| {
+        eprintln!("Could not read '{}': {error}.", source_name(source));
+        process::exit(66);
+    })
+}
+
+/// Prints the error of a failed `result` and exits with the matching
+/// code, or exits successfully.
+fn exit<T>(result: Result<T, RabbitError>, source: &str) -> ! {
+    let Err(error) = result else {
+        process::exit(0);
+    };
+    eprint!("{}", error.render(source));
+    process::exit(match error {
+        RabbitError::Syntax(_) | RabbitError::Compile(_) | RabbitError::Verify(_) => 65,
+        RabbitError::Runtime(_) | RabbitError::LimitExceeded(_) => 70,
+    });
+}
+
+/// Where `--trace` output goes: the trace file if one was given, stdout
+/// otherwise.
+fn trace_output(options: &DebugOptions) -> Option<Box<dyn Write>> {
+    if !options.trace {
+        return None;
+    }
+    let Some(path) = &options.trace_file else {
+        return Some(Box::new(io::stdout()));
+    };
+    match File::create(path) {
+        Ok(file) => Some(Box::new(io::BufWriter::new(file))),
+        Err(error) => {
+            eprintln!("Could not create '{path}': {error}.");
+            process::exit(73);
         }
     }
 }
 
-fn run_file(path: &str) {
-    let source = fs::read_to_string(path).unwrap();
-    let chunk = Chunk::new();
-    let mut vm = Vm::new(chunk);
-    let result = vm.interpret();
-    match result {
-        vm::InterpretResult::Ok => process::exit(0),
-        vm::InterpretResult::SyntaxError => process::exit(65),
-        vm::InterpretResult::CompileError => process::exit(65),
-        vm::InterpretResult::RuntimeError => process::exit(70),
+fn disassemble(chunk: &Chunk, options: &DebugOptions) {
+    if options.disassemble {
+        let _ = chunk.disassemble_chunk("script", &mut io::stdout());
+    }
+}
+
+fn run(source: &Source, args: Vec<String>, options: &DebugOptions) {
+    let bytes = read_bytes(source);
+    let (chunk, text) = if rbc::is_bytecode(&bytes) {
+        let (chunk, _) = rbc::load(&bytes).unwrap_or_else(|error| {
+            eprintln!("Could not load '{}': {error}", source_name(source));
+            process::exit(65);
+        });
+        // Checked before it's disassembled, which trusts its operands.
+        let chunk = verify(&chunk).map(|()| chunk).map_err(RabbitError::from);
+        (chunk, String::new())
+    } else {
+        let text = into_text(source, bytes);
+        (compiler::compile(&text), text)
+    };
+    let result = chunk.and_then(|chunk| {
+        disassemble(&chunk, options);
+        let mut vm = Vm::builder().grant_all().build();
+        vm.set_trace(trace_output(options));
+        let args = args.into_iter().map(|arg| Value::String(arg.into()));
+        vm.set_global("args", Value::List(Rc::new(args.collect())));
+        vm.interpret(chunk)
+    });
+    exit(result, &text);
+}
+
+fn check(source: &Source, options: &DebugOptions) {
+    let source = read_source(source);
+    let result = compiler::compile(&source).map(|chunk| disassemble(&chunk, options));
+    exit(result, &source);
+}
+
+fn compile(source: &Source, output: &str, options: &DebugOptions) {
+    let text = read_source(source);
+    let result = compiler::compile(&text).map(|chunk| {
+        disassemble(&chunk, options);
+        let bytes = rbc::save(&chunk, source_name(source))
+            .expect("compiled chunks only hold constants that can be saved");
+        if let Err(error) = fs::write(output, bytes) {
+            eprintln!("Could not create '{output}': {error}.");
+            process::exit(73);
+        }
+    });
+    exit(result, &text);
+}
+
+fn tokens(format: TokensFormat, source: &Source) {
+    let source = read_source(source);
+    let (tokens, errors) = semantic::semantic_tokens(&source);
+    match format {
+        TokensFormat::Json => {
+            let tokens = json::Json::Array(tokens.iter().map(|t| t.to_json()).collect());
+            println!("{tokens}");
+        }
+        TokensFormat::Text => {
+            for token in &tokens {
+                println!(
+                    "{}:{} {} {:?}",
+                    token.line,
+                    token.column,
+                    token.kind,
+                    &source[token.span.clone()]
+                );
+            }
+        }
+    }
+    for error in &errors {
+        eprintln!("{}", error.render(&source));
+    }
+    if !errors.is_empty() {
+        process::exit(65);
     }
 }
@@ -0,0 +1,354 @@
+use std::{collections::BTreeMap, fmt, marker::PhantomData, mem::ManuallyDrop, rc::Rc};
+
+use crate::{
+    class::{NativeClass, Userdata},
+    function::{Closure, Function},
+    value::{Native, Value, ValueKind},
+};
+
+/// A value packed into 64 bits, which is what `Value` is with the
+/// `nan_boxing` feature. Numbers are stored as themselves, and everything
+/// else in the payload of a quiet NaN that arithmetic never produces:
+/// `null`, `true` and `false` as small integers, and objects as a pointer
+/// to their reference-counted data, tagged with its type.
+///
+/// A string's `Rc<str>` is a fat pointer, so it is kept behind one more
+/// `Rc`, made once with the value. Classes and bound methods, which
+/// scripts rarely copy around, share a box holding their whole
+/// `ValueKind`. Every NaN number becomes the same NaN. Like those `Rc`s,
+/// it can't be sent to or shared with other threads.
+pub struct NanBox(u64, PhantomData<Rc<()>>);
+
+/// The bits of a quiet NaN, plus one more so the float indefinite NaN of
+/// x86 is still a number.
+const QNAN: u64 = 0x7ffc_0000_0000_0000;
+const SIGN: u64 = 1 << 63;
+const NAN: u64 = 0x7ff8_0000_0000_0000;
+
+const NULL: u64 = QNAN | 1;
+const FALSE: u64 = QNAN | 2;
+const TRUE: u64 = QNAN | 3;
+
+/// Objects set the sign bit too. `Rc` data is aligned to at least 8 bytes,
+/// leaving the low 3 bits of the 48 bit address for the type.
+const OBJECT: u64 = SIGN | QNAN;
+const ADDRESS: u64 = 0x0000_ffff_ffff_fff8;
+const KIND: u64 = 0b111;
+
+const LIST: u64 = 0;
+const MAP: u64 = 1;
+const FUNCTION: u64 = 2;
+const CLOSURE: u64 = 3;
+/// An `Rc<Rc<str>>`.
+const STRING: u64 = 4;
+const USERDATA: u64 = 5;
+const NATIVE: u64 = 6;
+/// An `Rc<ValueKind>`, for the rest.
+const BOXED: u64 = 7;
+
+/// Constructors named after the variants of `ValueKind`, so values are
+/// made the same way with or without the feature.
+#[allow(non_snake_case, non_upper_case_globals)]
+impl NanBox {
+    pub const Null: NanBox = NanBox::from_bits(NULL);
+    pub fn Bool(value: bool) -> NanBox {
+        NanBox::from_bits(if value { TRUE } else { FALSE })
+    }
+    pub fn Number(number: f64) -> NanBox {
+        if number.is_nan() {
+            NanBox::from_bits(NAN)
+        } else {
+            NanBox::from_bits(number.to_bits())
+        }
+    }
+    pub fn String(string: Rc<str>) -> NanBox {
+        NanBox::object(Rc::new(string), STRING)
+    }
+    pub fn List(items: Rc<Vec<Value>>) -> NanBox {
+        NanBox::object(items, LIST)
+    }
+    pub fn Map(entries: Rc<BTreeMap<Rc<str>, Value>>) -> NanBox {
+        NanBox::object(entries, MAP)
+    }
+    pub fn Function(function: Rc<Function>) -> NanBox {
+        NanBox::object(function, FUNCTION)
+    }
+    pub fn Closure(closure: Rc<Closure>) -> NanBox {
+        NanBox::object(closure, CLOSURE)
+    }
+    pub fn Native(native: Rc<Native>) -> NanBox {
+        NanBox::object(native, NATIVE)
+    }
+    pub fn Class(class: Rc<NativeClass>) -> NanBox {
+        NanBox::object(Rc::new(ValueKind::Class(class)), BOXED)
+    }
+    pub fn Userdata(object: Rc<Userdata>) -> NanBox {
+        NanBox::object(object, USERDATA)
+    }
+    pub fn BoundMethod(object: Rc<Userdata>, method: Rc<str>) -> NanBox {
+        NanBox::object(Rc::new(ValueKind::BoundMethod(object, method)), BOXED)
+    }
+}
+
+impl NanBox {
+    /// The value, sharing its data.
+    pub fn kind(&self) -> ValueKind {
+        if !self.is_object() {
+            return self.immediate();
+        }
+        let address = self.address();
+        // SAFETY: the box owns one reference to data of the type its kind
+        // says, which stays alive while it is borrowed here.
+        unsafe {
+            match self.0 & KIND {
+                LIST => ValueKind::List(shared(address)),
+                MAP => ValueKind::Map(shared(address)),
+                FUNCTION => ValueKind::Function(shared(address)),
+                CLOSURE => ValueKind::Closure(shared(address)),
+                STRING => ValueKind::String(Rc::clone(&*(address as *const Rc<str>))),
+                USERDATA => ValueKind::Userdata(shared(address)),
+                NATIVE => ValueKind::Native(shared(address)),
+                _ => ValueKind::clone(&*(address as *const ValueKind)),
+            }
+        }
+    }
+    /// The value, moving its reference out of the box.
+    pub fn into_kind(self) -> ValueKind {
+        let value = ManuallyDrop::new(self);
+        if !value.is_object() {
+            return value.immediate();
+        }
+        let address = value.address();
+        // SAFETY: the box owned one reference to data of the type its kind
+        // says, and isn't dropped.
+        unsafe {
+            match value.0 & KIND {
+                LIST => ValueKind::List(Rc::from_raw(address as *const _)),
+                MAP => ValueKind::Map(Rc::from_raw(address as *const _)),
+                FUNCTION => ValueKind::Function(Rc::from_raw(address as *const _)),
+                CLOSURE => ValueKind::Closure(Rc::from_raw(address as *const _)),
+                STRING => {
+                    ValueKind::String(Rc::unwrap_or_clone(Rc::from_raw(address as *const Rc<str>)))
+                }
+                USERDATA => ValueKind::Userdata(Rc::from_raw(address as *const _)),
+                NATIVE => ValueKind::Native(Rc::from_raw(address as *const _)),
+                _ => Rc::unwrap_or_clone(Rc::from_raw(address as *const ValueKind)),
+            }
+        }
+    }
+    pub fn as_number(&self) -> Option<f64> {
+        (self.0 & QNAN != QNAN).then(|| f64::from_bits(self.0))
+    }
+    pub fn as_str(&self) -> Option<&str> {
+        // SAFETY: as in `kind`, and the string lives as long as the box.
+        (self.is_object() && self.0 & KIND == STRING)
+            .then(|| unsafe { &**(self.address() as *const Rc<str>) })
+    }
+    pub fn is_falsey(&self) -> bool {
+        self.0 == NULL || self.0 == FALSE
+    }
+    pub fn type_name(&self) -> &'static str {
+        match self.as_number() {
+            Some(_) => "number",
+            None => self.kind().type_name(),
+        }
+    }
+    fn is_object(&self) -> bool {
+        self.0 & OBJECT == OBJECT
+    }
+    fn address(&self) -> usize {
+        (self.0 & ADDRESS) as usize
+    }
+    const fn from_bits(bits: u64) -> NanBox {
+        NanBox(bits, PhantomData)
+    }
+    fn immediate(&self) -> ValueKind {
+        match self.0 {
+            NULL => ValueKind::Null,
+            FALSE => ValueKind::Bool(false),
+            TRUE => ValueKind::Bool(true),
+            bits => ValueKind::Number(f64::from_bits(bits)),
+        }
+    }
+    fn object<T>(data: Rc<T>, kind: u64) -> NanBox {
+        let address = Rc::into_raw(data) as usize as u64;
+        assert!(
+            address & !ADDRESS == 0,
+            "address {address:#x} doesn't fit in a NaN box"
+        );
+        NanBox::from_bits(OBJECT | address | kind)
+    }
+}
+
+/// Another reference to the `Rc<T>` data at `address`.
+///
+/// # Safety
+///
+/// `address` must come from `Rc::<T>::into_raw` and still be owned.
+unsafe fn shared<T>(address: usize) -> Rc<T> {
+    let data = ManuallyDrop::new(Rc::from_raw(address as *const T));
+    Rc::clone(&data)
+}
+
+impl From<ValueKind> for NanBox {
+    fn from(value: ValueKind) -> NanBox {
+        match value {
+            ValueKind::Null => NanBox::Null,
+            ValueKind::Bool(value) => NanBox::Bool(value),
+            ValueKind::Number(number) => NanBox::Number(number),
+            ValueKind::String(string) => NanBox::String(string),
+            ValueKind::List(items) => NanBox::List(items),
+            ValueKind::Map(entries) => NanBox::Map(entries),
+            ValueKind::Function(function) => NanBox::Function(function),
+            ValueKind::Closure(closure) => NanBox::Closure(closure),
+            ValueKind::Native(native) => NanBox::Native(native),
+            ValueKind::Userdata(object) => NanBox::Userdata(object),
+            value @ (ValueKind::Class(_) | ValueKind::BoundMethod(..)) => {
+                NanBox::object(Rc::new(value), BOXED)
+            }
+        }
+    }
+}
+
+impl From<NanBox> for ValueKind {
+    fn from(value: NanBox) -> ValueKind {
+        value.into_kind()
+    }
+}
+
+impl Clone for NanBox {
+    fn clone(&self) -> NanBox {
+        if !self.is_object() {
+            return NanBox::from_bits(self.0);
+        }
+        let address = self.address() as *const ();
+        // SAFETY: as in `kind`, the box owns a reference of this type.
+        unsafe {
+            match self.0 & KIND {
+                LIST => Rc::increment_strong_count(address as *const Vec<Value>),
+                MAP => Rc::increment_strong_count(address as *const BTreeMap<Rc<str>, Value>),
+                FUNCTION => Rc::increment_strong_count(address as *const Function),
+                CLOSURE => Rc::increment_strong_count(address as *const Closure),
+                STRING => Rc::increment_strong_count(address as *const Rc<str>),
+                USERDATA => Rc::increment_strong_count(address as *const Userdata),
+                NATIVE => Rc::increment_strong_count(address as *const Native),
+                _ => Rc::increment_strong_count(address as *const ValueKind),
+            }
+        }
+        NanBox::from_bits(self.0)
+    }
+}
+
+impl Drop for NanBox {
+    fn drop(&mut self) {
+        if self.is_object() {
+            drop(NanBox::from_bits(self.0).into_kind());
+        }
+    }
+}
+
+impl PartialEq for NanBox {
+    fn eq(&self, other: &NanBox) -> bool {
+        match (self.as_number(), other.as_number()) {
+            (Some(a), Some(b)) => a == b,
+            _ => self.0 == other.0 || self.kind() == other.kind(),
+        }
+    }
+}
+
+impl fmt::Debug for NanBox {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.kind())
+    }
+}
+
+impl fmt::Display for NanBox {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.kind())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::mem::size_of;
+
+    use super::*;
+    use crate::compiler::compile;
+
+    #[test]
+    fn values_round_trip() {
+        let chunk = compile("fn f() {}").unwrap();
+        let kinds = vec![
+            ValueKind::Null,
+            ValueKind::Bool(true),
+            ValueKind::Bool(false),
+            ValueKind::Number(0.0),
+            ValueKind::Number(-0.0),
+            ValueKind::Number(-1.5e300),
+            ValueKind::Number(f64::INFINITY),
+            ValueKind::Number(f64::NEG_INFINITY),
+            ValueKind::Number(f64::MIN_POSITIVE / 2.0),
+            ValueKind::String(Rc::from("text")),
+            ValueKind::List(Rc::new(vec![
+                NanBox::Number(1.0),
+                NanBox::String(Rc::from("a")),
+            ])),
+            ValueKind::Map(Rc::new(BTreeMap::from([(Rc::from("k"), NanBox::Null)]))),
+            chunk.constants()[1].kind(),
+        ];
+        for kind in kinds {
+            let boxed = NanBox::from(kind.clone());
+            assert_eq!(boxed.kind(), kind);
+            assert_eq!(boxed.clone(), boxed);
+            assert_eq!(boxed.into_kind(), kind);
+        }
+        let ValueKind::Number(zero) = NanBox::Number(-0.0).kind() else {
+            panic!("expected a number");
+        };
+        assert!(zero.is_sign_negative());
+        assert!(NanBox::Number(f64::NAN).as_number().unwrap().is_nan());
+        assert!(NanBox::Number(-f64::NAN).as_number().unwrap().is_nan());
+        assert_eq!(size_of::<NanBox>(), 8);
+    }
+
+    #[test]
+    fn only_numbers_are_numbers() {
+        assert_eq!(NanBox::Number(2.5).as_number(), Some(2.5));
+        for value in [
+            NanBox::Null,
+            NanBox::Bool(false),
+            NanBox::String(Rc::from("")),
+        ] {
+            assert_eq!(value.as_number(), None);
+        }
+        assert!(NanBox::Null.is_falsey());
+        assert!(NanBox::Bool(false).is_falsey());
+        assert!(!NanBox::Number(0.0).is_falsey());
+        assert!(!NanBox::String(Rc::from("")).is_falsey());
+        assert_eq!(NanBox::String(Rc::from("s")).as_str(), Some("s"));
+        assert_eq!(NanBox::Number(1.0).as_str(), None);
+    }
+
+    #[test]
+    fn references_are_counted() {
+        let list = Rc::new(vec![NanBox::Null]);
+        let boxed = NanBox::List(list.clone());
+        assert_eq!(Rc::strong_count(&list), 2);
+        let copy = boxed.clone();
+        let kind = copy.kind();
+        assert_eq!(Rc::strong_count(&list), 4);
+        drop((boxed, copy, kind));
+        assert_eq!(Rc::strong_count(&list), 1);
+
+        // Copies of a string value share one box, and give out the same
+        // string.
+        let string: Rc<str> = Rc::from("shared");
+        let boxed = NanBox::String(string.clone());
+        let copy = boxed.clone();
+        drop(boxed);
+        assert_eq!(copy.kind(), ValueKind::String(string.clone()));
+        assert_eq!(Rc::strong_count(&string), 2);
+        drop(copy);
+        assert_eq!(Rc::strong_count(&string), 1);
+    }
+}
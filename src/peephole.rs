@@ -0,0 +1,298 @@
+use crate::{
+    chunk::{Chunk, OpCode, Span},
+    value::Value,
+};
+
+/// Rewrites a finished chunk, fusing instruction sequences common in hot
+/// loops into superinstructions and dropping values that are pushed only
+/// to be popped. Jumps are retargeted to match. Constants, including
+/// nested functions, are left alone, and chunks whose jumps land outside
+/// of instructions are left as they are.
+pub fn optimize(chunk: &mut Chunk) {
+    let Some(mut instructions) = decode(chunk) else {
+        return;
+    };
+    while rewrite(&mut instructions, chunk.constants()) {}
+    encode(chunk, &instructions);
+}
+
+/// An instruction whose jump target, if it has one, is the index of
+/// another instruction, so it stays right as the code around it changes.
+#[derive(Debug, Clone, Copy)]
+struct Instruction {
+    opcode: OpCode,
+    span: Span,
+    target: Option<usize>,
+}
+
+fn decode(chunk: &Chunk) -> Option<Vec<Instruction>> {
+    let offsets: Vec<usize> = chunk.instructions().map(|(offset, _)| offset).collect();
+    chunk
+        .instructions()
+        .map(|(offset, opcode)| {
+            let next = offset + opcode.size();
+            let target = match opcode {
+                OpCode::Jump(distance)
+                | OpCode::JumpIfFalse(distance)
+                | OpCode::JumpIfNotLess(distance) => Some(next + distance),
+                OpCode::Loop(distance) => Some(next.checked_sub(distance)?),
+                _ => None,
+            };
+            Some(Instruction {
+                opcode,
+                span: chunk.span_at(offset),
+                target: match target {
+                    Some(target) => Some(offsets.binary_search(&target).ok()?),
+                    None => None,
+                },
+            })
+        })
+        .collect()
+}
+
+/// Makes one pass over `instructions`, returning whether anything changed.
+/// Sequences are only rewritten when nothing jumps into their middle.
+fn rewrite(instructions: &mut Vec<Instruction>, constants: &[Value]) -> bool {
+    let old = std::mem::take(instructions);
+    let mut jumps_to = vec![0; old.len()];
+    for target in old.iter().filter_map(|instruction| instruction.target) {
+        jumps_to[target] += 1;
+    }
+    // Where each old instruction went. Removed ones map to whatever comes
+    // after them, so jumps to them land there.
+    let mut moved = Vec::with_capacity(old.len() + 1);
+    let mut removed = vec![false; old.len()];
+    let mut changed = false;
+    let mut i = 0;
+    while i < old.len() {
+        if removed[i] {
+            moved.push(instructions.len());
+            i += 1;
+            continue;
+        }
+        let window = &old[i..];
+        let fusion = fuse(window, constants).filter(|(_, length)| {
+            (i + 1..i + length).all(|inner| jumps_to[inner] == 0 && !removed[inner])
+        });
+        let fusion = fusion.or_else(|| {
+            let end = fuse_comparison(window)?;
+            let pop = old[i + 1].target?;
+            let only_way_in = jumps_to[pop] == 1
+                && matches!(old[pop].opcode, OpCode::Pop)
+                && matches!(
+                    old[pop - 1].opcode,
+                    OpCode::Jump(_) | OpCode::Loop(_) | OpCode::Return
+                )
+                && jumps_to[i + 1] == 0
+                && jumps_to[i + 2] == 0;
+            if !only_way_in {
+                return None;
+            }
+            // The jump lands past the `Pop` now, which nothing else reaches.
+            removed[pop] = true;
+            Some((
+                Some(Instruction {
+                    target: Some(pop),
+                    ..end
+                }),
+                3,
+            ))
+        });
+        match fusion {
+            Some((replacement, length)) => {
+                changed = true;
+                moved.extend(std::iter::repeat_n(instructions.len(), length));
+                instructions.extend(replacement);
+                i += length;
+            }
+            None => {
+                moved.push(instructions.len());
+                instructions.push(old[i]);
+                i += 1;
+            }
+        }
+    }
+    moved.push(instructions.len());
+    for instruction in instructions.iter_mut() {
+        if let Some(target) = &mut instruction.target {
+            *target = moved[*target];
+        }
+    }
+    changed
+}
+
+/// The instruction replacing the start of `window`, if any, and how many
+/// instructions it replaces. `None` drops them all.
+fn fuse(window: &[Instruction], constants: &[Value]) -> Option<(Option<Instruction>, usize)> {
+    let opcode = |i: usize| window.get(i).map(|instruction| instruction.opcode);
+    let fused = |opcode: OpCode, span: Span| Instruction {
+        opcode,
+        span,
+        target: None,
+    };
+    match (opcode(0)?, opcode(1), opcode(2)) {
+        (OpCode::GetLocal(slot), Some(OpCode::Constant(index)), Some(OpCode::Add)) => {
+            // Errors are reported where the `+` is.
+            let add = fused(OpCode::AddLocalConst(slot, index), window[2].span);
+            Some((Some(add), 3))
+        }
+        (OpCode::AddLocalConst(slot, index), Some(OpCode::SetLocal(set)), Some(OpCode::Pop))
+            if set == slot && constants.get(index).and_then(Value::as_number) == Some(1.0) =>
+        {
+            let increment = fused(OpCode::IncrementLocal(slot), window[0].span);
+            Some((Some(increment), 3))
+        }
+        (OpCode::SetLocal(slot), Some(OpCode::Pop), Some(OpCode::GetLocal(get))) if get == slot => {
+            Some((Some(window[0]), 3))
+        }
+        (
+            OpCode::Constant(_)
+            | OpCode::ConstantLong(_)
+            | OpCode::Null
+            | OpCode::True
+            | OpCode::False
+            | OpCode::GetLocal(_)
+            | OpCode::GetUpvalue(_),
+            Some(OpCode::Pop),
+            _,
+        ) => Some((None, 2)),
+        _ => None,
+    }
+}
+
+/// `JumpIfNotLess`, when `window` starts with a comparison whose result is
+/// only tested and popped. The caller checks the other `Pop`, on the path
+/// jumped to.
+fn fuse_comparison(window: &[Instruction]) -> Option<Instruction> {
+    match window {
+        [less, jump, pop, ..]
+            if matches!(less.opcode, OpCode::Less)
+                && matches!(jump.opcode, OpCode::JumpIfFalse(_))
+                && matches!(pop.opcode, OpCode::Pop) =>
+        {
+            Some(Instruction {
+                opcode: OpCode::JumpIfNotLess(0),
+                span: less.span,
+                target: None,
+            })
+        }
+        _ => None,
+    }
+}
+
+/// Writes `instructions` back over the code of `chunk`, turning jump
+/// targets into distances again. Code only ever shrinks, so they still fit.
+fn encode(chunk: &mut Chunk, instructions: &[Instruction]) {
+    let mut offsets = Vec::with_capacity(instructions.len() + 1);
+    let mut offset = 0;
+    for instruction in instructions {
+        offsets.push(offset);
+        offset += instruction.opcode.size();
+    }
+    offsets.push(offset);
+    chunk.truncate(0);
+    for (i, instruction) in instructions.iter().enumerate() {
+        let next = offsets[i + 1];
+        let opcode = match (instruction.opcode, instruction.target) {
+            (OpCode::Jump(_), Some(target)) => OpCode::Jump(offsets[target] - next),
+            (OpCode::JumpIfFalse(_), Some(target)) => OpCode::JumpIfFalse(offsets[target] - next),
+            (OpCode::JumpIfNotLess(_), Some(target)) => {
+                OpCode::JumpIfNotLess(offsets[target] - next)
+            }
+            (OpCode::Loop(_), Some(target)) => OpCode::Loop(next - offsets[target]),
+            (opcode, _) => opcode,
+        };
+        chunk.write(opcode, instruction.span);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assembler::assemble, compiler::compile, verifier::verify, vm::Vm};
+
+    fn opcodes(chunk: &Chunk) -> Vec<OpCode> {
+        chunk.instructions().map(|(_, opcode)| opcode).collect()
+    }
+
+    #[test]
+    fn loops_use_superinstructions() {
+        use OpCode::*;
+        let chunk =
+            compile("{ let i = 0; let sum = 0; while i < 10 { sum = sum + 2; i = i + 1; } }")
+                .unwrap();
+        assert_eq!(
+            opcodes(&chunk),
+            vec![
+                Constant(0),
+                Constant(0),
+                GetLocal(0),
+                Constant(1),
+                JumpIfNotLess(11),
+                AddLocalConst(1, 2),
+                SetLocal(1),
+                Pop,
+                IncrementLocal(0),
+                Loop(18),
+                Pop,
+                Pop,
+                Return,
+            ]
+        );
+        assert_eq!(verify(&chunk), Ok(()));
+    }
+
+    #[test]
+    fn fused_code_behaves_the_same() {
+        let source = "
+            fn count(n) {
+                let i = 0;
+                let s = \"\";
+                while i < n { s = s + \"x\"; i = i + 1; }
+                if i < 3 { return 0; } else { return s + 1; }
+            }
+            let a = count(2);
+            let b = count(4);
+        ";
+        let mut vm = Vm::new();
+        let error = vm.interpret(compile(source).unwrap()).unwrap_err();
+        assert_eq!(error.to_string(), "Operands must be two numbers or two strings.\n[line 6:56] in fn count()\n[line 9:26] in script");
+        assert_eq!(vm.get_global("a"), Some(&Value::Number(0.0)));
+    }
+
+    #[test]
+    fn values_pushed_only_to_be_popped_are_dropped() {
+        use OpCode::*;
+        let chunk = compile("{ let a = 1; a; a = 2; print a; }").unwrap();
+        assert_eq!(
+            opcodes(&chunk),
+            vec![Constant(0), Constant(1), SetLocal(0), Print, Pop, Return]
+        );
+    }
+
+    #[test]
+    fn code_reached_another_way_is_kept() {
+        // The `Pop` after `done` is both jumped to and fallen into, so
+        // neither the comparison nor the `Null` before it can go.
+        let source = "
+            == script ==
+            OP_CONSTANT 0
+            OP_CONSTANT 1
+            OP_LESS
+            OP_JUMP_IF_FALSE done
+            OP_POP
+            OP_NULL
+            done:
+            OP_POP
+            OP_RETURN
+            -- constants --
+               0 1.0
+               1 2.0
+        ";
+        let mut chunk = assemble(source).unwrap();
+        let before = opcodes(&chunk);
+        optimize(&mut chunk);
+        assert_eq!(opcodes(&chunk), before);
+        assert_eq!(verify(&chunk), Ok(()));
+    }
+}
@@ -0,0 +1,452 @@
+use std::{error::Error, fmt, rc::Rc};
+
+use crate::{
+    chunk::{Chunk, OpCode, Span},
+    function::{Capture, Function},
+    value::{Value, ValueKind},
+};
+
+/// The first bytes of every `.rbc` file.
+pub const MAGIC: &[u8; 4] = b"RBC\0";
+
+/// The format version written, and the only one read. Bump it whenever the
+/// layout or the meaning of any opcode changes.
+pub const VERSION: u16 = 3;
+
+/// How deeply functions may be nested in a file. Loading, verifying and
+/// disassembling all recurse into nested functions, so a file nesting them
+/// deeper is rejected rather than overflowing the stack.
+const MAX_NESTING: usize = 256;
+
+/// Serializes `chunk`, compiled from the file `source_name`, into the
+/// `.rbc` format: the magic bytes, the version, a CRC-32 of the rest, then
+/// the source name and the script function. All integers are little endian.
+pub fn save(chunk: &Chunk, source_name: &str) -> Result<Vec<u8>, RbcError> {
+    let mut payload = vec![];
+    write_str(&mut payload, source_name);
+    write_chunk(&mut payload, chunk)?;
+    let mut bytes = MAGIC.to_vec();
+    bytes.extend_from_slice(&VERSION.to_le_bytes());
+    bytes.extend_from_slice(&checksum(&payload).to_le_bytes());
+    bytes.extend(payload);
+    Ok(bytes)
+}
+
+/// Reads back what `save` wrote, returning the chunk and the name of the
+/// source it was compiled from.
+pub fn load(bytes: &[u8]) -> Result<(Chunk, String), RbcError> {
+    if !is_bytecode(bytes) {
+        return Err(RbcError::NotBytecode);
+    }
+    let mut reader = Reader {
+        bytes,
+        position: 4,
+        depth: 0,
+    };
+    let version = reader.u16()?;
+    if version != VERSION {
+        return Err(RbcError::Version(version));
+    }
+    let expected = reader.u32()?;
+    if checksum(&bytes[reader.position..]) != expected {
+        return Err(RbcError::Checksum);
+    }
+    let source_name = reader.string()?.to_string();
+    let chunk = reader.chunk()?;
+    if reader.position != bytes.len() {
+        return Err(RbcError::Malformed("trailing bytes"));
+    }
+    Ok((chunk, source_name))
+}
+
+/// Whether `bytes` start like a `.rbc` file, as opposed to source code.
+pub fn is_bytecode(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RbcError {
+    NotBytecode,
+    /// The file was written by a rabbit using another format version.
+    Version(u16),
+    Checksum,
+    Malformed(&'static str),
+    /// Only constants the compiler makes can be saved, not natives or
+    /// lists put in a chunk by hand.
+    Unsupported(&'static str),
+}
+
+impl fmt::Display for RbcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RbcError::NotBytecode => write!(f, "Not a rabbit bytecode file."),
+            RbcError::Version(version) => write!(
+                f,
+                "Bytecode version {version} is not supported, expected version {VERSION}. \
+                 Recompile the script."
+            ),
+            RbcError::Checksum => write!(f, "Bytecode checksum mismatch, the file is corrupt."),
+            RbcError::Malformed(what) => write!(f, "Malformed bytecode: {what}."),
+            RbcError::Unsupported(type_name) => {
+                write!(f, "Can't save a {type_name} constant as bytecode.")
+            }
+        }
+    }
+}
+
+impl Error for RbcError {}
+
+/// CRC-32, as used by zip and PNG.
+fn checksum(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn write_u32(out: &mut Vec<u8>, value: usize) {
+    out.extend_from_slice(&(value as u32).to_le_bytes());
+}
+
+fn write_str(out: &mut Vec<u8>, value: &str) {
+    write_u32(out, value.len());
+    out.extend_from_slice(value.as_bytes());
+}
+
+fn write_chunk(out: &mut Vec<u8>, chunk: &Chunk) -> Result<(), RbcError> {
+    write_u32(out, chunk.instructions().count());
+    for (offset, opcode) in chunk.instructions() {
+        let (tag, operand) = encode(opcode);
+        out.push(tag);
+        if let Some(operand) = operand {
+            write_u32(out, operand);
+        }
+        if let OpCode::AddLocalConst(_, index) = opcode {
+            write_u32(out, index);
+        }
+        let span = chunk.span_at(offset);
+        write_u32(out, span.line);
+        write_u32(out, span.column);
+    }
+    write_u32(out, chunk.constants().len());
+    for constant in chunk.constants() {
+        match constant.kind() {
+            ValueKind::Null => out.push(0),
+            ValueKind::Bool(value) => out.extend([1, value as u8]),
+            ValueKind::Number(value) => {
+                out.push(2);
+                out.extend_from_slice(&value.to_le_bytes());
+            }
+            ValueKind::String(value) => {
+                out.push(3);
+                write_str(out, &value);
+            }
+            ValueKind::Function(function) => {
+                out.push(4);
+                write_function(out, &function)?;
+            }
+            value => return Err(RbcError::Unsupported(value.type_name())),
+        }
+    }
+    Ok(())
+}
+
+fn write_function(out: &mut Vec<u8>, function: &Function) -> Result<(), RbcError> {
+    match &function.name {
+        Some(name) => {
+            out.push(1);
+            write_str(out, name);
+        }
+        None => out.push(0),
+    }
+    write_u32(out, function.arity);
+    write_u32(out, function.captures.len());
+    for capture in &function.captures {
+        match capture {
+            Capture::Local(slot) => {
+                out.push(0);
+                write_u32(out, *slot);
+            }
+            Capture::Upvalue(index) => {
+                out.push(1);
+                write_u32(out, *index);
+            }
+        }
+    }
+    write_chunk(out, &function.chunk)
+}
+
+/// The tag byte of `opcode` in `.rbc` files, and its operand if it has one.
+/// `AddLocalConst` has its constant index written after that.
+fn encode(opcode: OpCode) -> (u8, Option<usize>) {
+    match opcode {
+        OpCode::Constant(index) => (0, Some(index)),
+        OpCode::Null => (1, None),
+        OpCode::True => (2, None),
+        OpCode::False => (3, None),
+        OpCode::Pop => (4, None),
+        OpCode::GetLocal(slot) => (5, Some(slot)),
+        OpCode::SetLocal(slot) => (6, Some(slot)),
+        OpCode::GetGlobal(index) => (7, Some(index)),
+        OpCode::DefineGlobal(index) => (8, Some(index)),
+        OpCode::SetGlobal(index) => (9, Some(index)),
+        OpCode::GetUpvalue(index) => (10, Some(index)),
+        OpCode::SetUpvalue(index) => (11, Some(index)),
+        OpCode::GetProperty(index) => (12, Some(index)),
+        OpCode::SetProperty(index) => (13, Some(index)),
+        OpCode::Equal => (14, None),
+        OpCode::Greater => (15, None),
+        OpCode::Less => (16, None),
+        OpCode::Add => (17, None),
+        OpCode::Subtract => (18, None),
+        OpCode::Multiply => (19, None),
+        OpCode::Divide => (20, None),
+        OpCode::Modulo => (21, None),
+        OpCode::BuildList(count) => (22, Some(count)),
+        OpCode::Index => (23, None),
+        OpCode::Jump(distance) => (24, Some(distance)),
+        OpCode::JumpIfFalse(distance) => (25, Some(distance)),
+        OpCode::Loop(distance) => (26, Some(distance)),
+        OpCode::Call(count) => (27, Some(count)),
+        OpCode::Closure(index) => (28, Some(index)),
+        OpCode::CloseUpvalue => (29, None),
+        OpCode::Not => (30, None),
+        OpCode::Negate => (31, None),
+        OpCode::Print => (32, None),
+        OpCode::Return => (33, None),
+        OpCode::ConstantLong(index) => (34, Some(index)),
+        OpCode::AddLocalConst(slot, _) => (35, Some(slot)),
+        OpCode::IncrementLocal(slot) => (36, Some(slot)),
+        OpCode::JumpIfNotLess(distance) => (37, Some(distance)),
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+    /// How many functions enclose the one being read.
+    depth: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, count: usize) -> Result<&'a [u8], RbcError> {
+        let end = self
+            .position
+            .checked_add(count)
+            .filter(|&end| end <= self.bytes.len())
+            .ok_or(RbcError::Malformed("unexpected end of file"))?;
+        let bytes = &self.bytes[self.position..end];
+        self.position = end;
+        Ok(bytes)
+    }
+    fn u8(&mut self) -> Result<u8, RbcError> {
+        Ok(self.take(1)?[0])
+    }
+    fn u16(&mut self) -> Result<u16, RbcError> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+    fn u32(&mut self) -> Result<u32, RbcError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+    fn usize(&mut self) -> Result<usize, RbcError> {
+        Ok(self.u32()? as usize)
+    }
+    fn string(&mut self) -> Result<&'a str, RbcError> {
+        let length = self.usize()?;
+        std::str::from_utf8(self.take(length)?).map_err(|_| RbcError::Malformed("invalid UTF-8"))
+    }
+    fn chunk(&mut self) -> Result<Chunk, RbcError> {
+        let mut chunk = Chunk::new();
+        for _ in 0..self.usize()? {
+            let opcode = self.opcode()?;
+            let span = Span {
+                line: self.usize()?,
+                column: self.usize()?,
+            };
+            if !opcode.fits() {
+                return Err(RbcError::Malformed("operand out of range"));
+            }
+            chunk.write(opcode, span);
+        }
+        for _ in 0..self.usize()? {
+            let constant = match self.u8()? {
+                0 => Value::Null,
+                1 => Value::Bool(self.u8()? != 0),
+                2 => Value::Number(f64::from_le_bytes(self.take(8)?.try_into().unwrap())),
+                3 => Value::String(Rc::from(self.string()?)),
+                4 => Value::Function(Rc::new(self.function()?)),
+                _ => return Err(RbcError::Malformed("unknown constant type")),
+            };
+            chunk.push_constant(constant);
+        }
+        Ok(chunk)
+    }
+    fn function(&mut self) -> Result<Function, RbcError> {
+        if self.depth == MAX_NESTING {
+            return Err(RbcError::Malformed("functions are nested too deeply"));
+        }
+        let name = match self.u8()? {
+            0 => None,
+            _ => Some(Rc::from(self.string()?)),
+        };
+        let arity = self.usize()?;
+        let mut captures = vec![];
+        for _ in 0..self.usize()? {
+            captures.push(match self.u8()? {
+                0 => Capture::Local(self.usize()?),
+                1 => Capture::Upvalue(self.usize()?),
+                _ => return Err(RbcError::Malformed("unknown capture")),
+            });
+        }
+        self.depth += 1;
+        let chunk = self.chunk()?;
+        self.depth -= 1;
+        Ok(Function {
+            name,
+            arity,
+            chunk,
+            captures,
+        })
+    }
+    fn opcode(&mut self) -> Result<OpCode, RbcError> {
+        let tag = self.u8()?;
+        let opcode = match tag {
+            1 => OpCode::Null,
+            2 => OpCode::True,
+            3 => OpCode::False,
+            4 => OpCode::Pop,
+            14 => OpCode::Equal,
+            15 => OpCode::Greater,
+            16 => OpCode::Less,
+            17 => OpCode::Add,
+            18 => OpCode::Subtract,
+            19 => OpCode::Multiply,
+            20 => OpCode::Divide,
+            21 => OpCode::Modulo,
+            23 => OpCode::Index,
+            29 => OpCode::CloseUpvalue,
+            30 => OpCode::Not,
+            31 => OpCode::Negate,
+            32 => OpCode::Print,
+            33 => OpCode::Return,
+            _ => {
+                let operand = self.usize()?;
+                match tag {
+                    0 => OpCode::Constant(operand),
+                    5 => OpCode::GetLocal(operand),
+                    6 => OpCode::SetLocal(operand),
+                    7 => OpCode::GetGlobal(operand),
+                    8 => OpCode::DefineGlobal(operand),
+                    9 => OpCode::SetGlobal(operand),
+                    10 => OpCode::GetUpvalue(operand),
+                    11 => OpCode::SetUpvalue(operand),
+                    12 => OpCode::GetProperty(operand),
+                    13 => OpCode::SetProperty(operand),
+                    22 => OpCode::BuildList(operand),
+                    24 => OpCode::Jump(operand),
+                    25 => OpCode::JumpIfFalse(operand),
+                    26 => OpCode::Loop(operand),
+                    27 => OpCode::Call(operand),
+                    28 => OpCode::Closure(operand),
+                    34 => OpCode::ConstantLong(operand),
+                    35 => OpCode::AddLocalConst(operand, self.usize()?),
+                    36 => OpCode::IncrementLocal(operand),
+                    37 => OpCode::JumpIfNotLess(operand),
+                    _ => return Err(RbcError::Malformed("unknown opcode")),
+                }
+            }
+        };
+        Ok(opcode)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{compiler::compile, vm::Vm};
+
+    /// The disassembly without the pool statistics, which describe how
+    /// the chunk was compiled rather than what it is.
+    fn disassembly(chunk: &Chunk) -> String {
+        let mut out = vec![];
+        chunk.disassemble_chunk("script", &mut out).unwrap();
+        String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .filter(|line| !line.starts_with(';'))
+            .map(|line| format!("{line}\n"))
+            .collect()
+    }
+
+    #[test]
+    fn chunks_round_trip() {
+        let source = "
+            fn counter(step) {
+                let count = 0;
+                return fn() { count += step; return count; };
+            }
+            let c = counter(2);
+            c();
+            let n = c();
+            if n > 3 { print \"big\"; } else { print null; }
+        ";
+        let chunk = compile(source).unwrap();
+        let bytes = save(&chunk, "counter.rb").unwrap();
+        let (loaded, name) = load(&bytes).unwrap();
+        assert_eq!(name, "counter.rb");
+        assert_eq!(disassembly(&loaded), disassembly(&chunk));
+        let mut vm = Vm::new();
+        assert!(vm.interpret(loaded).is_ok());
+        assert_eq!(vm.get_global("n"), Some(&Value::Number(4.0)));
+    }
+
+    #[test]
+    fn bad_files_are_rejected() {
+        let bytes = save(&compile("print 1;").unwrap(), "a.rb").unwrap();
+        assert_eq!(load(b"print 1;").unwrap_err(), RbcError::NotBytecode);
+
+        let mut other_version = bytes.clone();
+        other_version[4..6].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        let error = load(&other_version).unwrap_err();
+        assert_eq!(error, RbcError::Version(VERSION + 1));
+        assert!(error.to_string().contains("Recompile"));
+
+        let mut corrupt = bytes.clone();
+        *corrupt.last_mut().unwrap() ^= 1;
+        assert_eq!(load(&corrupt).unwrap_err(), RbcError::Checksum);
+        assert!(load(&bytes[..bytes.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn deeply_nested_functions_are_rejected() {
+        let nested = |depth: usize| {
+            let mut chunk = compile("").unwrap();
+            for _ in 0..depth {
+                let function = Function {
+                    name: None,
+                    arity: 0,
+                    chunk,
+                    captures: vec![],
+                };
+                chunk = Chunk::new();
+                chunk.push_constant(Value::Function(Rc::new(function)));
+                chunk.write(OpCode::Null, Span { line: 1, column: 1 });
+                chunk.write(OpCode::Return, Span { line: 1, column: 1 });
+            }
+            save(&chunk, "deep.rb").unwrap()
+        };
+        assert!(load(&nested(MAX_NESTING)).is_ok());
+        assert_eq!(
+            load(&nested(MAX_NESTING + 1)).unwrap_err(),
+            RbcError::Malformed("functions are nested too deeply")
+        );
+    }
+}
@@ -0,0 +1,401 @@
+use std::{env, fs, io, mem, path::PathBuf, process, time::Instant};
+
+use rabbit::{
+    compiler,
+    scanner::{Scanner, TokenType, KEYWORDS},
+    Chunk, OpCode, RabbitError, Value, ValueKind, Vm,
+};
+
+use crate::{
+    cli::DebugOptions,
+    line_editor::{LineEditor, ReadLine},
+};
+
+const HELP: &str = "\
+:tokens <code>    show the tokens the scanner produces
+:bytecode <code>  disassemble the compiled code
+:ast <code>       show the syntax tree recovered from the bytecode
+:globals          list global variables and their values
+:type <expr>      evaluate an expression and show its type
+:load <file>      run a script in this session
+:time <code>      run code and report how long it took
+:reset            forget every global
+:help             show this message";
+
+/// Runs an interactive session on a single `Vm`, so globals persist from
+/// one entry to the next. Entries with unbalanced brackets or an open
+/// string keep reading lines after a `..` prompt.
+pub fn run(options: &DebugOptions) {
+    let history_path = env::var_os("HOME").map(|home| PathBuf::from(home).join(".rabbit_history"));
+    let mut editor = LineEditor::new(history_path);
+    let mut vm = Vm::builder().grant_all().build();
+    vm.set_trace(crate::trace_output(options));
+    let mut entry = String::new();
+    loop {
+        let prompt = if entry.is_empty() { ">> " } else { ".. " };
+        let complete = |word: &str| completions(&vm, word);
+        match editor.read_line(prompt, &complete) {
+            Ok(ReadLine::Eof) => {
+                println!();
+                return;
+            }
+            Ok(ReadLine::Interrupted) => entry.clear(),
+            Ok(ReadLine::Line(line)) => {
+                editor.add_history(&line);
+                entry.push_str(&line);
+                if is_incomplete(&entry) {
+                    continue;
+                }
+                let source = mem::take(&mut entry);
+                match source.trim_start().strip_prefix(':') {
+                    Some(command) => meta_command(&mut vm, command),
+                    None => {
+                        if options.disassemble {
+                            meta_command(&mut vm, &format!("bytecode {source}"));
+                        }
+                        evaluate(&mut vm, &source)
+                    }
+                }
+            }
+            Err(_) => {
+                eprintln!("Error reading line");
+                process::exit(74);
+            }
+        }
+    }
+}
+
+fn evaluate(vm: &mut Vm, source: &str) {
+    let result = compiler::compile_repl(source).and_then(|chunk| vm.evaluate(chunk));
+    if let Some(Some(value)) = report(result, source) {
+        println!("{value}");
+    }
+}
+
+/// Prints the error of a failed `result`, found in `source`.
+fn report<T>(result: Result<T, RabbitError>, source: &str) -> Option<T> {
+    result
+        .map_err(|error| print!("{}", error.render(source)))
+        .ok()
+}
+
+fn meta_command(vm: &mut Vm, command: &str) {
+    let (name, argument) = command
+        .trim()
+        .split_once(char::is_whitespace)
+        .unwrap_or((command.trim(), ""));
+    let argument = argument.trim();
+    match name {
+        "tokens" => {
+            if let Some(tokens) = report(Scanner::new(argument).scan_tokens(), argument) {
+                for token in tokens {
+                    println!("{:?} '{}'", token.token_type, &argument[token.span]);
+                }
+            }
+        }
+        "bytecode" => {
+            if let Some(chunk) = report(compiler::compile_repl(argument), argument) {
+                let _ = chunk.disassemble_chunk(argument, &mut io::stdout());
+            }
+        }
+        "ast" => {
+            if let Some(chunk) = report(compiler::compile_repl(argument), argument) {
+                for tree in syntax_tree(&chunk) {
+                    println!("{tree}");
+                }
+            }
+        }
+        "globals" => {
+            let mut globals: Vec<_> = vm.globals().collect();
+            globals.sort_by_key(|&(name, _)| name);
+            for (name, value) in globals {
+                println!("{name} = {value}");
+            }
+        }
+        "type" => {
+            let result = compiler::compile_repl(argument).and_then(|chunk| vm.evaluate(chunk));
+            match report(result, argument) {
+                Some(Some(value)) => println!("{}", value.type_name()),
+                Some(None) => println!("Expect an expression."),
+                None => {}
+            }
+        }
+        "load" => match fs::read_to_string(argument) {
+            Ok(source) => {
+                let result = compiler::compile(&source).and_then(|chunk| vm.interpret(chunk));
+                report(result, &source);
+            }
+            Err(error) => println!("Could not read '{argument}': {error}."),
+        },
+        "time" => {
+            let start = Instant::now();
+            evaluate(vm, argument);
+            println!("Took {:?}.", start.elapsed());
+        }
+        "reset" => vm.reset(),
+        "help" => println!("{HELP}"),
+        _ => println!("Unknown command ':{name}'. Type :help to list commands."),
+    }
+}
+
+/// Rebuilds S-expressions from a compiled chunk by running it symbolically.
+/// The compiler is single pass, so this is the closest thing we have to a
+/// syntax tree.
+fn syntax_tree(chunk: &Chunk) -> Vec<String> {
+    let constant = |index: usize| {
+        let value = &chunk.constants()[index];
+        match value.as_str() {
+            Some(s) => format!("{s:?}"),
+            None => value.to_string(),
+        }
+    };
+    let name = |index: usize| chunk.constants()[index].to_string();
+    let mut stack: Vec<String> = vec![];
+    let mut trees = vec![];
+    for (_, instruction) in chunk.instructions() {
+        let operator = match instruction {
+            OpCode::Constant(index) | OpCode::ConstantLong(index) => {
+                stack.push(constant(index));
+                continue;
+            }
+            OpCode::Null => "null",
+            OpCode::True => "true",
+            OpCode::False => "false",
+            OpCode::GetLocal(slot) => {
+                stack.push(format!("local{slot}"));
+                continue;
+            }
+            OpCode::GetUpvalue(index) => {
+                stack.push(format!("upvalue{index}"));
+                continue;
+            }
+            OpCode::SetUpvalue(index) => {
+                let value = stack.pop().unwrap_or_default();
+                stack.push(format!("(= upvalue{index} {value})"));
+                continue;
+            }
+            OpCode::Closure(index) => {
+                stack.push(constant(index));
+                continue;
+            }
+            OpCode::GetGlobal(index) => {
+                stack.push(name(index));
+                continue;
+            }
+            OpCode::SetLocal(slot) => {
+                let value = stack.pop().unwrap_or_default();
+                stack.push(format!("(= local{slot} {value})"));
+                continue;
+            }
+            OpCode::SetGlobal(index) => {
+                let value = stack.pop().unwrap_or_default();
+                stack.push(format!("(= {} {value})", name(index)));
+                continue;
+            }
+            OpCode::GetProperty(index) => {
+                let object = stack.pop().unwrap_or_default();
+                stack.push(format!("{object}.{}", name(index)));
+                continue;
+            }
+            OpCode::SetProperty(index) => {
+                let value = stack.pop().unwrap_or_default();
+                let object = stack.pop().unwrap_or_default();
+                stack.push(format!("(= {object}.{} {value})", name(index)));
+                continue;
+            }
+            OpCode::DefineGlobal(index) => {
+                let value = stack.pop().unwrap_or_default();
+                trees.push(format!("(let {} {value})", name(index)));
+                continue;
+            }
+            OpCode::Print => {
+                let value = stack.pop().unwrap_or_default();
+                trees.push(format!("(print {value})"));
+                continue;
+            }
+            OpCode::BuildList(count) => {
+                let items = stack.split_off(stack.len().saturating_sub(count));
+                stack.push(format!("[{}]", items.join(", ")));
+                continue;
+            }
+            OpCode::JumpIfFalse(distance) => {
+                let condition = stack.pop().unwrap_or_default();
+                trees.push(format!("(jump-if-false {condition} {distance})"));
+                continue;
+            }
+            OpCode::JumpIfNotLess(distance) => {
+                let b = stack.pop().unwrap_or_default();
+                let a = stack.pop().unwrap_or_default();
+                trees.push(format!("(jump-if-false (< {a} {b}) {distance})"));
+                continue;
+            }
+            OpCode::AddLocalConst(slot, index) => {
+                stack.push(format!("(+ local{slot} {})", constant(index)));
+                continue;
+            }
+            OpCode::IncrementLocal(slot) => {
+                trees.push(format!("(= local{slot} (+ local{slot} 1))"));
+                continue;
+            }
+            OpCode::Jump(distance) => {
+                trees.push(format!("(jump {distance})"));
+                continue;
+            }
+            OpCode::Loop(distance) => {
+                trees.push(format!("(loop {distance})"));
+                continue;
+            }
+            OpCode::Call(count) => {
+                let arguments = stack.split_off(stack.len().saturating_sub(count));
+                let callee = stack.pop().unwrap_or_default();
+                stack.push(format!(
+                    "({})",
+                    [vec![callee], arguments].concat().join(" ")
+                ));
+                continue;
+            }
+            OpCode::Pop | OpCode::CloseUpvalue | OpCode::Return => {
+                trees.extend(stack.pop());
+                continue;
+            }
+            OpCode::Not => "!",
+            OpCode::Negate => "-",
+            OpCode::Equal => "==",
+            OpCode::Greater => ">",
+            OpCode::Less => "<",
+            OpCode::Add => "+",
+            OpCode::Subtract => "-",
+            OpCode::Multiply => "*",
+            OpCode::Divide => "/",
+            OpCode::Modulo => "%",
+            OpCode::Index => "index",
+        };
+        let tree = match instruction {
+            OpCode::Null | OpCode::True | OpCode::False => operator.to_string(),
+            OpCode::Not | OpCode::Negate => {
+                format!("({operator} {})", stack.pop().unwrap_or_default())
+            }
+            _ => {
+                let b = stack.pop().unwrap_or_default();
+                let a = stack.pop().unwrap_or_default();
+                format!("({operator} {a} {b})")
+            }
+        };
+        stack.push(tree);
+    }
+    trees
+}
+
+/// Keywords and globals starting with `word`, or if it is `name.prefix`,
+/// the getters and methods starting with `prefix` of the object `name`.
+fn completions(vm: &Vm, word: &str) -> Vec<String> {
+    if let Some((name, prefix)) = word.rsplit_once('.') {
+        let Some(ValueKind::Userdata(object)) = vm.get_global(name).map(Value::kind) else {
+            return vec![];
+        };
+        return object
+            .class()
+            .members()
+            .filter(|member| member.starts_with(prefix))
+            .map(|member| format!("{name}.{member}"))
+            .collect();
+    }
+    KEYWORDS
+        .iter()
+        .map(|&(keyword, _)| keyword)
+        .chain(vm.globals().map(|(name, _)| name))
+        .filter(|name| name.starts_with(word))
+        .map(str::to_string)
+        .collect()
+}
+
+fn is_incomplete(source: &str) -> bool {
+    let (tokens, errors) = Scanner::new(source).scan();
+    if errors
+        .iter()
+        .any(|error| error.message == "Unterminated string.")
+    {
+        return true;
+    }
+    let depth = tokens
+        .iter()
+        .fold(0, |depth, token| match token.token_type {
+            TokenType::LeftParen | TokenType::LeftBrace | TokenType::LeftBracket => depth + 1,
+            TokenType::RightParen | TokenType::RightBrace | TokenType::RightBracket => depth - 1,
+            _ => depth,
+        });
+    depth > 0
+}
+
+#[cfg(test)]
+mod tests {
+    use rabbit::ClassBuilder;
+
+    use super::*;
+
+    #[test]
+    fn incomplete_entries_are_detected() {
+        assert!(is_incomplete("{ let a = 1;\n"));
+        assert!(is_incomplete("print (1 +\n"));
+        assert!(is_incomplete("print \"abc\n"));
+        assert!(!is_incomplete("{ print 1; }\n"));
+        assert!(!is_incomplete("print 1; }\n"));
+        assert!(!is_incomplete("print #;\n"));
+    }
+
+    #[test]
+    fn syntax_tree_is_recovered_from_bytecode() {
+        let chunk = compiler::compile_repl(
+            "let a = -(x + 2) * 3; print !a; f(a, g()); a.b = c.d; a = \"s\"",
+        )
+        .unwrap();
+        assert_eq!(
+            syntax_tree(&chunk),
+            vec![
+                "(let a (* (- (+ x 2)) 3))",
+                "(print (! a))",
+                "(f a (g))",
+                "(= a.b c.d)",
+                "(= a \"s\")",
+            ]
+        );
+    }
+
+    #[test]
+    fn meta_commands_use_the_session() {
+        let mut vm = Vm::new();
+        evaluate(&mut vm, "let a = 1;");
+        meta_command(&mut vm, "reset");
+        assert_eq!(vm.globals().count(), 0);
+    }
+
+    #[test]
+    fn completions_include_keywords_and_globals() {
+        let mut vm = Vm::new();
+        let chunk = compiler::compile("let pear = 1; let apple = 2;").unwrap();
+        vm.interpret(chunk).unwrap();
+        let mut candidates = completions(&vm, "p");
+        candidates.sort();
+        assert_eq!(candidates, vec!["pear", "print"]);
+    }
+
+    #[test]
+    fn completions_after_a_dot_are_the_objects_members() {
+        let mut vm = Vm::new();
+        vm.register_class(
+            ClassBuilder::new("Point")
+                .constructor(0, |_, _| Ok(0.0))
+                .getter("x", |x| Value::Number(*x))
+                .method("move", 1, |_, _, _| Ok(Value::Null))
+                .method("mirror", 0, |_, _, _| Ok(Value::Null)),
+        );
+        let chunk = compiler::compile("let p = Point(); let n = 1;").unwrap();
+        vm.interpret(chunk).unwrap();
+        let mut candidates = completions(&vm, "p.m");
+        candidates.sort();
+        assert_eq!(candidates, vec!["p.mirror", "p.move"]);
+        assert_eq!(completions(&vm, "p.x"), vec!["p.x"]);
+        assert!(completions(&vm, "n.").is_empty());
+        assert!(completions(&vm, "missing.").is_empty());
+    }
+}
@@ -0,0 +1,241 @@
+use std::{
+    collections::hash_map::RandomState,
+    fmt, fs,
+    hash::{BuildHasher, Hasher},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    stdlib,
+    vm::{RuntimeError, Vm},
+};
+
+/// Something the standard library can do on a script's behalf, which the
+/// host has to grant with `VmBuilder::grant` first.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Capability {
+    /// Reading files in a directory or any of its subdirectories.
+    Read(PathBuf),
+    /// Creating and writing files in a directory or its subdirectories.
+    Write(PathBuf),
+    Env,
+    Clock,
+    Random,
+    Spawn,
+}
+
+impl Capability {
+    /// Whether granting `self` allows `requested`, as granting a directory
+    /// allows every path under it.
+    fn covers(&self, requested: &Capability) -> bool {
+        match (self, requested) {
+            (Capability::Read(dir), Capability::Read(path))
+            | (Capability::Write(dir), Capability::Write(path)) => is_inside(path, dir),
+            (granted, requested) => granted == requested,
+        }
+    }
+}
+
+impl fmt::Display for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Capability::Read(path) => write!(f, "read '{}'", path.display()),
+            Capability::Write(path) => write!(f, "write '{}'", path.display()),
+            Capability::Env => write!(f, "read environment variables"),
+            Capability::Clock => write!(f, "read the clock"),
+            Capability::Random => write!(f, "generate random numbers"),
+            Capability::Spawn => write!(f, "run other programs"),
+        }
+    }
+}
+
+/// `path` with `..` and symbolic links resolved. Paths that don't exist
+/// yet are resolved through their parent, so new files can be written, and
+/// a bare file name's parent is the current directory. Anything else that
+/// can't be resolved, like a symbolic link to a missing file, isn't.
+pub(crate) fn resolve(path: &Path) -> Option<PathBuf> {
+    if let Ok(path) = path.canonicalize() {
+        return Some(path);
+    }
+    if fs::symlink_metadata(path).is_ok() {
+        return None;
+    }
+    let parent = match path.parent()? {
+        parent if parent.as_os_str().is_empty() => Path::new("."),
+        parent => parent,
+    };
+    let parent = parent.canonicalize().ok()?;
+    Some(parent.join(path.file_name()?))
+}
+
+/// Whether `path` is `dir` or somewhere under it, once both are resolved.
+fn is_inside(path: &Path, dir: &Path) -> bool {
+    match (resolve(path), dir.canonicalize()) {
+        (Some(path), Ok(dir)) => path.starts_with(dir),
+        _ => false,
+    }
+}
+
+/// Builds a `Vm` with the standard library, whose natives can only do what
+/// the host granted. Without any grant, scripts can compute and print but
+/// not touch the machine they run on.
+///
+/// ```
+/// use rabbit::{compile, sandbox::Capability, Vm};
+///
+/// let mut vm = Vm::builder().grant(Capability::Clock).build();
+/// assert!(vm.interpret(compile("let now = clock();").unwrap()).is_ok());
+/// let error = vm.interpret(compile("env(\"HOME\");").unwrap()).unwrap_err();
+/// assert!(error.to_string().starts_with("Permission denied"));
+/// ```
+#[derive(Debug, Default)]
+pub struct VmBuilder {
+    capabilities: Vec<Capability>,
+}
+
+impl VmBuilder {
+    pub fn new() -> VmBuilder {
+        VmBuilder::default()
+    }
+    pub fn grant(mut self, capability: Capability) -> VmBuilder {
+        self.capabilities.push(capability);
+        self
+    }
+    /// Grants everything, for scripts trusted as much as the host itself.
+    pub fn grant_all(self) -> VmBuilder {
+        let root = PathBuf::from("/");
+        self.grant(Capability::Read(root.clone()))
+            .grant(Capability::Write(root))
+            .grant(Capability::Env)
+            .grant(Capability::Clock)
+            .grant(Capability::Random)
+            .grant(Capability::Spawn)
+    }
+    pub fn build(self) -> Vm {
+        let mut vm = Vm::new();
+        vm.capabilities = self.capabilities;
+        vm.stdlib = true;
+        vm.rng = RandomState::new().build_hasher().finish() | 1;
+        stdlib::register(&mut vm);
+        vm
+    }
+}
+
+impl Vm {
+    pub fn builder() -> VmBuilder {
+        VmBuilder::new()
+    }
+    /// Fails with a permission error unless the host granted `capability`.
+    /// Natives doing anything the standard library guards should call this
+    /// first.
+    pub fn require(&self, capability: Capability) -> Result<(), RuntimeError> {
+        if self
+            .capabilities
+            .iter()
+            .any(|granted| granted.covers(&capability))
+        {
+            Ok(())
+        } else {
+            Err(RuntimeError::new(format!(
+                "Permission denied: this script can't {capability}."
+            )))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::{compiler::compile, error::RabbitError, value::Value};
+
+    fn run(vm: &mut Vm, source: &str) -> Result<Value, RabbitError> {
+        vm.interpret(compile(source).unwrap())
+    }
+
+    #[test]
+    fn nothing_is_granted_by_default() {
+        let mut vm = Vm::builder().build();
+        for source in [
+            "read_file(\"Cargo.toml\");",
+            "write_file(\"out.txt\", \"\");",
+            "env(\"HOME\");",
+            "clock();",
+            "random();",
+            "spawn(\"true\", []);",
+        ] {
+            let error = run(&mut vm, source).unwrap_err();
+            assert!(
+                error.to_string().starts_with("Permission denied"),
+                "{source}"
+            );
+        }
+        assert!(run(&mut vm, "print 1 + 2;").is_ok());
+    }
+
+    #[test]
+    fn files_are_only_reachable_inside_granted_directories() {
+        let dir = std::env::temp_dir().join(format!("rabbit-sandbox-{}", std::process::id()));
+        let data = dir.join("data");
+        fs::create_dir_all(&data).unwrap();
+        fs::write(dir.join("secret.txt"), "secret").unwrap();
+        let mut vm = Vm::builder()
+            .grant(Capability::Read(data.clone()))
+            .grant(Capability::Write(data.clone()))
+            .build();
+        let path = |name: &str| Value::String(dir.join(name).to_string_lossy().into());
+        vm.set_global("inside", path("data/notes.txt"));
+        vm.set_global("outside", path("secret.txt"));
+        vm.set_global("escape", path("data/../secret.txt"));
+
+        assert!(run(&mut vm, "write_file(inside, \"hi\");").is_ok());
+        assert!(run(&mut vm, "let text = read_file(inside);").is_ok());
+        assert_eq!(vm.get_global("text"), Some(&Value::String("hi".into())));
+        assert_eq!(
+            run(&mut vm, "read_file(outside);")
+                .unwrap_err()
+                .to_string()
+                .lines()
+                .next()
+                .unwrap(),
+            format!(
+                "Permission denied: this script can't read '{}'.",
+                dir.join("secret.txt").display()
+            )
+        );
+        assert!(run(&mut vm, "read_file(escape);").is_err());
+        assert!(run(&mut vm, "write_file(outside, \"x\");").is_err());
+        assert_eq!(
+            fs::read_to_string(dir.join("secret.txt")).unwrap(),
+            "secret"
+        );
+        // A new file named without a directory is in the current one.
+        let bare = Path::new("rabbit-sandbox-new.txt");
+        assert!(is_inside(bare, Path::new(".")));
+        assert!(!is_inside(bare, &data));
+        // A link to a file that doesn't exist yet is followed, not trusted.
+        #[cfg(unix)]
+        {
+            let link = data.join("link");
+            std::os::unix::fs::symlink(dir.join("pwned.txt"), &link).unwrap();
+            vm.set_global("link", Value::String(link.to_string_lossy().into()));
+            assert!(run(&mut vm, "write_file(link, \"x\");").is_err());
+            assert!(!dir.join("pwned.txt").exists());
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn granted_capabilities_work() {
+        let mut vm = Vm::builder().grant_all().build();
+        let source = "let r = random(); let t = clock(); let p = env(\"RABBIT_UNSET_VARIABLE\");";
+        assert!(run(&mut vm, source).is_ok());
+        let Some(r) = vm.get_global("r").and_then(Value::as_number) else {
+            panic!("random() returns a number");
+        };
+        assert!((0.0..1.0).contains(&r));
+        assert!(vm.get_global("t").and_then(Value::as_number) > Some(0.0));
+        assert_eq!(vm.get_global("p"), Some(&Value::Null));
+    }
+}
@@ -1,36 +1,80 @@
 use std::{iter::Peekable, ops::Range, str::CharIndices};
 
-use crate::vm::InterpretResult;
+use std::fmt;
 
+use crate::{chunk::Span, error::RabbitError};
+
+/// Every reserved word with the token it scans to.
+pub const KEYWORDS: [(&str, TokenType); 18] = [
+    ("and", TokenType::And),
+    ("class", TokenType::Class),
+    ("else", TokenType::Else),
+    ("extends", TokenType::Extends),
+    ("false", TokenType::False),
+    ("for", TokenType::For),
+    ("fn", TokenType::Fn),
+    ("in", TokenType::In),
+    ("if", TokenType::If),
+    ("let", TokenType::Let),
+    ("null", TokenType::Null),
+    ("or", TokenType::Or),
+    ("print", TokenType::Print),
+    ("return", TokenType::Return),
+    ("super", TokenType::Super),
+    ("this", TokenType::This),
+    ("true", TokenType::True),
+    ("while", TokenType::While),
+];
+
+/// Splits source code into tokens.
 pub struct Scanner<'a> {
-    source: &'a String,
+    source: &'a str,
     char_indices: Peekable<CharIndices<'a>>,
     tokens: Vec<Token>,
-    had_error: bool,
+    errors: Vec<Diagnostic>,
+    keep_comments: bool,
 }
 
 impl<'a> Scanner<'a> {
-    pub fn new(source: &String) -> Scanner {
+    pub fn new(source: &'a str) -> Scanner<'a> {
         Scanner {
             source,
             char_indices: source.char_indices().peekable(),
             tokens: vec![],
-            had_error: false,
+            errors: vec![],
+            keep_comments: false,
         }
     }
-    pub fn scan_tokens(mut self) -> Result<Vec<Token>, InterpretResult> {
-        while let Some((start, c)) = self.char_indices.next() {
-            self.scan_token(c, start);
+    /// Like `new`, but also emits `Comment` tokens, which tools such as
+    /// syntax highlighters care about and the compiler does not.
+    pub fn with_comments(source: &'a str) -> Scanner<'a> {
+        Scanner {
+            keep_comments: true,
+            ..Scanner::new(source)
         }
-        if !self.had_error {
-            self.tokens.push(Token {
-                token_type: TokenType::Eof,
-                span: (self.source.len() - 1)..(self.source.len() - 1),
-            });
-            Ok(self.tokens)
+    }
+    /// Scans the whole source, failing with every error found if there
+    /// was any.
+    pub fn scan_tokens(self) -> Result<Vec<Token>, RabbitError> {
+        let (tokens, errors) = self.scan();
+        if errors.is_empty() {
+            Ok(tokens)
         } else {
-            Err(InterpretResult::SyntaxError)
+            Err(RabbitError::Syntax(errors))
+        }
+    }
+    /// Scans the whole source without printing anything, returning every
+    /// token alongside the errors found on the way.
+    pub fn scan(mut self) -> (Vec<Token>, Vec<Diagnostic>) {
+        while let Some((start, c)) = self.char_indices.next() {
+            self.scan_token(c, start);
         }
+        let end = self.source.len().saturating_sub(1);
+        self.tokens.push(Token {
+            token_type: TokenType::Eof,
+            span: end..end,
+        });
+        (self.tokens, self.errors)
     }
     fn scan_token(&mut self, c: char, start: usize) {
         match c {
@@ -46,65 +90,65 @@ impl<'a> Scanner<'a> {
             '?' => self.add_token(TokenType::Question, start, start + 1),
             ':' => self.add_token(TokenType::Colon, start, start + 1),
             '!' => {
-                if let Some(_) = self.char_indices.next_if(|&(_, c)| c == '=') {
+                if self.char_indices.next_if(|&(_, c)| c == '=').is_some() {
                     self.add_token(TokenType::BangEqual, start, start + 2);
                 } else {
                     self.add_token(TokenType::Bang, start, start + 1);
                 }
             }
             '=' => {
-                if let Some(_) = self.char_indices.next_if(|&(_, c)| c == '=') {
+                if self.char_indices.next_if(|&(_, c)| c == '=').is_some() {
                     self.add_token(TokenType::EqualEqual, start, start + 2);
                 } else {
                     self.add_token(TokenType::Equal, start, start + 1);
                 }
             }
             '<' => {
-                if let Some(_) = self.char_indices.next_if(|&(_, c)| c == '=') {
+                if self.char_indices.next_if(|&(_, c)| c == '=').is_some() {
                     self.add_token(TokenType::LessEqual, start, start + 2);
                 } else {
                     self.add_token(TokenType::Less, start, start + 1);
                 }
             }
             '>' => {
-                if let Some(_) = self.char_indices.next_if(|&(_, c)| c == '=') {
+                if self.char_indices.next_if(|&(_, c)| c == '=').is_some() {
                     self.add_token(TokenType::GreaterEqual, start, start + 2);
                 } else {
                     self.add_token(TokenType::Greater, start, start + 1);
                 }
             }
             '+' => {
-                if let Some(_) = self.char_indices.next_if(|&(_, c)| c == '=') {
+                if self.char_indices.next_if(|&(_, c)| c == '=').is_some() {
                     self.add_token(TokenType::PlusEqual, start, start + 2);
                 } else {
                     self.add_token(TokenType::Plus, start, start + 1);
                 }
             }
             '-' => {
-                if let Some(_) = self.char_indices.next_if(|&(_, c)| c == '=') {
+                if self.char_indices.next_if(|&(_, c)| c == '=').is_some() {
                     self.add_token(TokenType::MinusEqual, start, start + 2);
                 } else {
                     self.add_token(TokenType::Minus, start, start + 1);
                 }
             }
             '*' => {
-                if let Some(_) = self.char_indices.next_if(|&(_, c)| c == '=') {
+                if self.char_indices.next_if(|&(_, c)| c == '=').is_some() {
                     self.add_token(TokenType::StarEqual, start, start + 2);
                 } else {
                     self.add_token(TokenType::Star, start, start + 1);
                 }
             }
             '/' => {
-                if let Some(_) = self.char_indices.next_if(|&(_, c)| c == '=') {
+                if self.char_indices.next_if(|&(_, c)| c == '=').is_some() {
                     self.add_token(TokenType::SlashEqual, start, start + 2);
-                } else if let Some(_) = self.char_indices.next_if(|&(_, c)| c == '/') {
-                    self.comment();
+                } else if self.char_indices.next_if(|&(_, c)| c == '/').is_some() {
+                    self.comment(start);
                 } else {
                     self.add_token(TokenType::Slash, start, start + 1);
                 }
             }
             '%' => {
-                if let Some(_) = self.char_indices.next_if(|&(_, c)| c == '=') {
+                if self.char_indices.next_if(|&(_, c)| c == '=').is_some() {
                     self.add_token(TokenType::PercentEqual, start, start + 2);
                 } else {
                     self.add_token(TokenType::Percent, start, start + 1);
@@ -119,7 +163,7 @@ impl<'a> Scanner<'a> {
             c => {
                 let token = Token {
                     token_type: TokenType::Error,
-                    span: start..start + 1,
+                    span: start..start + c.len_utf8(),
                 };
                 self.error(&format!("Unexpected character {c}."), "", token);
             }
@@ -131,11 +175,17 @@ impl<'a> Scanner<'a> {
             span: start..end,
         })
     }
-    fn comment(&mut self) {
-        while let Some(_) = self.char_indices.next_if(|&(_, c)| c != '\n') {}
+    fn comment(&mut self, start: usize) {
+        let mut end = start + 2;
+        while let Some((j, c)) = self.char_indices.next_if(|&(_, c)| c != '\n') {
+            end = j + c.len_utf8();
+        }
+        if self.keep_comments {
+            self.add_token(TokenType::Comment, start, end);
+        }
     }
     fn identifier(&mut self, start: usize) {
-        let mut end = 0;
+        let mut end = start + 1;
         while let Some((j, _)) = self
             .char_indices
             .next_if(|&(_, c)| c.is_ascii_alphanumeric() || c == '_')
@@ -143,31 +193,14 @@ impl<'a> Scanner<'a> {
             end = j + 1;
         }
         let lexeme = &self.source[start..end];
-        let token_type = match lexeme {
-            "and" => TokenType::And,
-            "class" => TokenType::Class,
-            "else" => TokenType::Else,
-            "extends" => TokenType::Extends,
-            "false" => TokenType::False,
-            "for" => TokenType::For,
-            "fn" => TokenType::Fn,
-            "in" => TokenType::In,
-            "if" => TokenType::If,
-            "let" => TokenType::Let,
-            "null" => TokenType::Null,
-            "or" => TokenType::Or,
-            "print" => TokenType::Print,
-            "return" => TokenType::Return,
-            "super" => TokenType::Super,
-            "this" => TokenType::This,
-            "true" => TokenType::True,
-            "while" => TokenType::While,
-            _ => TokenType::Identifier,
-        };
+        let token_type = KEYWORDS
+            .iter()
+            .find(|(keyword, _)| *keyword == lexeme)
+            .map_or(TokenType::Identifier, |&(_, token_type)| token_type);
         self.add_token(token_type, start, end);
     }
     fn string(&mut self, start: usize) {
-        let mut end = 0;
+        let mut end = start + 1;
         while let Some((j, _)) = self.char_indices.next_if(|&(_, c)| c != '"') {
             end = j + 1;
         }
@@ -183,7 +216,7 @@ impl<'a> Scanner<'a> {
         }
     }
     fn number(&mut self, start: usize) {
-        let mut end = 0;
+        let mut end = start + 1;
         while let Some((j, _)) = self.char_indices.next_if(|&(_, c)| c.is_ascii_digit()) {
             end = j + 1;
         }
@@ -201,40 +234,75 @@ impl<'a> Scanner<'a> {
         self.add_token(TokenType::Number, start, end);
     }
     fn error(&mut self, message: &str, at: &str, token: Token) {
-        self.had_error = true;
-        token.print_error(self.source, message, at);
+        self.errors.push(Diagnostic {
+            message: message.to_string(),
+            at: at.to_string(),
+            span: Span::at(self.source, token.span.start),
+            token,
+        });
+    }
+}
+
+/// An error found while scanning or compiling, pointing at the offending
+/// token.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub message: String,
+    pub at: String,
+    pub token: Token,
+    pub span: Span,
+}
+
+impl Diagnostic {
+    /// Formats the error with the line of `source` it is on, underlining
+    /// the offending token.
+    pub fn render(&self, source: &str) -> String {
+        self.token.error(source, &self.message, &self.at)
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Span { line, column } = self.span;
+        write!(
+            f,
+            "[line {line}:{column}] Error{}: {}",
+            self.at, self.message
+        )
     }
 }
 
-#[derive(Debug)]
+/// A token of `token_type`, covering the `span` byte range of the source.
+#[derive(Debug, Clone, PartialEq)]
 pub struct Token {
-    token_type: TokenType,
-    span: Range<usize>,
+    pub token_type: TokenType,
+    pub span: Range<usize>,
 }
 
 impl Token {
-    fn error(&self, source: &String, message: &str, at: &str) -> String {
-        let lines: Vec<&str> = source[..=self.span.start].lines().collect();
-        let line = lines.len();
-        let column = lines.last().unwrap().len();
+    fn error(&self, source: &str, message: &str, at: &str) -> String {
+        // Up to and including the first character of the token, counting
+        // columns in characters so the caret lines up.
+        let first = source[self.span.start..].chars().next();
+        let end = self.span.start + first.map_or(0, char::len_utf8);
+        let lines: Vec<&str> = source[..end].lines().collect();
+        let line = lines.len().max(1);
+        let column = lines.last().map_or(0, |line| line.chars().count());
         let header = format!("Error{at}: {message}\n");
         let body = format!(
             "  | [{line}:{column}] {}\n",
-            source.lines().nth(line - 1).unwrap()
+            source.lines().nth(line - 1).unwrap_or("")
         );
         let footer = format!(
             "{}{}\n",
             " ".repeat(line.to_string().len() + column.to_string().len() + 8 + column - 1),
-            "^".repeat(self.span.end - self.span.start)
+            "^".repeat(source[self.span.clone()].chars().count())
         );
         header + &body + &footer
     }
-    pub fn print_error(&self, source: &String, message: &str, at: &str) {
-        println!("{}", self.error(source, message, at));
-    }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum TokenType {
     // Single-character tokens.
     LeftParen,
@@ -294,6 +362,7 @@ pub enum TokenType {
     True,
     While,
 
+    Comment,
     Error,
     Eof,
 }
@@ -309,7 +378,7 @@ mod tests {
 
     fn tokens_to_tokens_with_lexeme<'a>(
         tokens: Vec<Token>,
-        source: &'a String,
+        source: &'a str,
     ) -> Vec<TokenWithLexeme<'a>> {
         tokens
             .into_iter()
@@ -564,6 +633,34 @@ mod tests {
         )
     }
 
+    #[test]
+    fn single_char_literals_scan_works() {
+        let source = String::from("a 1 b");
+        let scanner = Scanner::new(&source);
+        let tokens = tokens_to_tokens_with_lexeme(scanner.scan_tokens().unwrap(), &source);
+        assert_eq!(
+            tokens,
+            vec![
+                TokenWithLexeme {
+                    token_type: TokenType::Identifier,
+                    lexeme: "a"
+                },
+                TokenWithLexeme {
+                    token_type: TokenType::Number,
+                    lexeme: "1"
+                },
+                TokenWithLexeme {
+                    token_type: TokenType::Identifier,
+                    lexeme: "b"
+                },
+                TokenWithLexeme {
+                    token_type: TokenType::Eof,
+                    lexeme: ""
+                },
+            ]
+        )
+    }
+
     #[test]
     fn comments_and_whitespace_scan_works() {
         let source = String::from(
@@ -588,6 +685,34 @@ mod tests {
         )
     }
 
+    #[test]
+    fn comments_are_kept_on_request() {
+        let source = String::from("1 // one é\n// two");
+        let scanner = Scanner::with_comments(&source);
+        let tokens = tokens_to_tokens_with_lexeme(scanner.scan_tokens().unwrap(), &source);
+        assert_eq!(
+            tokens,
+            vec![
+                TokenWithLexeme {
+                    token_type: TokenType::Number,
+                    lexeme: "1"
+                },
+                TokenWithLexeme {
+                    token_type: TokenType::Comment,
+                    lexeme: "// one é"
+                },
+                TokenWithLexeme {
+                    token_type: TokenType::Comment,
+                    lexeme: "// two"
+                },
+                TokenWithLexeme {
+                    token_type: TokenType::Eof,
+                    lexeme: ""
+                },
+            ]
+        )
+    }
+
     #[test]
     fn unterminated_string_fails() {
         let source = String::from("\"hello world");
@@ -604,6 +729,36 @@ mod tests {
         assert!(tokens.is_err());
     }
 
+    #[test]
+    fn scan_collects_errors() {
+        let source = String::from("# 1 @");
+        let (tokens, errors) = Scanner::new(&source).scan();
+        assert_eq!(tokens.len(), 2);
+        assert_eq!(
+            errors
+                .iter()
+                .map(|e| (e.message.as_str(), e.token.span.clone()))
+                .collect::<Vec<_>>(),
+            vec![
+                ("Unexpected character #.", 0..1),
+                ("Unexpected character @.", 4..5)
+            ]
+        );
+    }
+
+    #[test]
+    fn unexpected_multibyte_characters_are_reported_whole() {
+        let source = String::from("let é = 1;");
+        let (_, errors) = Scanner::new(&source).scan();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].message, "Unexpected character é.");
+        assert_eq!(errors[0].token.span, 4..6);
+        assert_eq!(
+            errors[0].token.error(&source, &errors[0].message, ""),
+            "Error: Unexpected character é.\n  | [1:5] let é = 1;\n              ^\n",
+        );
+    }
+
     #[test]
     fn error_reporting_string_works() {
         let source = String::from("\n\n\n  \"hello world");
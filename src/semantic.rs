@@ -0,0 +1,307 @@
+use std::{collections::HashSet, fmt, ops::Range};
+
+use crate::{
+    json::Json,
+    scanner::{Diagnostic, Scanner, Token, TokenType, KEYWORDS},
+};
+
+/// The role of a token, for syntax highlighting.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SemanticKind {
+    Keyword,
+    Operator,
+    Punctuation,
+    String,
+    Number,
+    Comment,
+    Function,
+    Class,
+    Parameter,
+    Property,
+    Variable,
+}
+
+impl SemanticKind {
+    /// Every kind that has a standard LSP semantic token type, in the order
+    /// used for the server's token legend.
+    pub const LEGEND: [SemanticKind; 10] = [
+        SemanticKind::Keyword,
+        SemanticKind::Operator,
+        SemanticKind::String,
+        SemanticKind::Number,
+        SemanticKind::Comment,
+        SemanticKind::Function,
+        SemanticKind::Class,
+        SemanticKind::Parameter,
+        SemanticKind::Property,
+        SemanticKind::Variable,
+    ];
+    pub fn name(self) -> &'static str {
+        match self {
+            SemanticKind::Keyword => "keyword",
+            SemanticKind::Operator => "operator",
+            SemanticKind::Punctuation => "punctuation",
+            SemanticKind::String => "string",
+            SemanticKind::Number => "number",
+            SemanticKind::Comment => "comment",
+            SemanticKind::Function => "function",
+            SemanticKind::Class => "class",
+            SemanticKind::Parameter => "parameter",
+            SemanticKind::Property => "property",
+            SemanticKind::Variable => "variable",
+        }
+    }
+    /// The position of this kind in `LEGEND`, if it has one.
+    pub fn legend_index(self) -> Option<usize> {
+        Self::LEGEND.iter().position(|&kind| kind == self)
+    }
+}
+
+impl fmt::Display for SemanticKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SemanticToken {
+    pub kind: SemanticKind,
+    pub span: Range<usize>,
+    /// 1-based, like the positions in error reports.
+    pub line: usize,
+    /// 1-based byte column.
+    pub column: usize,
+    /// Whether this identifier is the name being declared.
+    pub declaration: bool,
+}
+
+impl SemanticToken {
+    pub fn to_json(&self) -> Json {
+        Json::object([
+            ("kind", self.kind.name().into()),
+            ("start", self.span.start.into()),
+            ("end", self.span.end.into()),
+            ("line", self.line.into()),
+            ("column", self.column.into()),
+            ("declaration", self.declaration.into()),
+        ])
+    }
+}
+
+/// Classifies every token of `source`, comments included. Identifiers are
+/// given a role from their surroundings: names of declared functions and
+/// classes keep their role wherever they are used, parameters are
+/// recognised inside the body of their function and names after a `.`
+/// are properties.
+pub fn semantic_tokens(source: &str) -> (Vec<SemanticToken>, Vec<Diagnostic>) {
+    let (tokens, errors) = Scanner::with_comments(source).scan();
+    let line_starts: Vec<usize> = std::iter::once(0)
+        .chain(source.match_indices('\n').map(|(i, _)| i + 1))
+        .collect();
+    let lexeme = |token: &Token| &source[token.span.clone()];
+
+    let mut functions = HashSet::new();
+    let mut classes = HashSet::new();
+    let code: Vec<&Token> = tokens
+        .iter()
+        .filter(|t| t.token_type != TokenType::Comment)
+        .collect();
+    for pair in code.windows(2) {
+        if pair[1].token_type != TokenType::Identifier {
+            continue;
+        }
+        match pair[0].token_type {
+            TokenType::Fn => functions.insert(lexeme(pair[1])),
+            TokenType::Class => classes.insert(lexeme(pair[1])),
+            _ => false,
+        };
+    }
+
+    let mut semantic_tokens = vec![];
+    // Parameters of the functions we are currently in, with the brace depth
+    // their body starts at.
+    let mut scopes: Vec<(usize, Vec<&str>)> = vec![];
+    let mut pending_parameters: Option<Vec<&str>> = None;
+    let mut in_parameter_list = false;
+    let mut depth = 0;
+    let mut previous: Option<TokenType> = None;
+    for (i, token) in code.iter().enumerate() {
+        let next = code.get(i + 1).map(|t| t.token_type);
+        let mut declaration = false;
+        let kind = match token.token_type {
+            TokenType::Identifier => {
+                let name = lexeme(token);
+                match previous {
+                    Some(TokenType::Fn) => {
+                        declaration = true;
+                        SemanticKind::Function
+                    }
+                    Some(TokenType::Class) => {
+                        declaration = true;
+                        SemanticKind::Class
+                    }
+                    Some(TokenType::Let) => {
+                        declaration = true;
+                        SemanticKind::Variable
+                    }
+                    Some(TokenType::Extends) => SemanticKind::Class,
+                    Some(TokenType::Dot) => SemanticKind::Property,
+                    _ if in_parameter_list => {
+                        declaration = true;
+                        if let Some(parameters) = &mut pending_parameters {
+                            parameters.push(name);
+                        }
+                        SemanticKind::Parameter
+                    }
+                    _ if scopes.iter().any(|(_, p)| p.contains(&name)) => SemanticKind::Parameter,
+                    _ if classes.contains(name) => SemanticKind::Class,
+                    _ if functions.contains(name) || next == Some(TokenType::LeftParen) => {
+                        SemanticKind::Function
+                    }
+                    _ => SemanticKind::Variable,
+                }
+            }
+            TokenType::Fn => {
+                pending_parameters = Some(vec![]);
+                SemanticKind::Keyword
+            }
+            TokenType::LeftParen => {
+                if pending_parameters.is_some() && !in_parameter_list {
+                    in_parameter_list = true;
+                }
+                SemanticKind::Punctuation
+            }
+            TokenType::RightParen => {
+                in_parameter_list = false;
+                SemanticKind::Punctuation
+            }
+            TokenType::LeftBrace => {
+                depth += 1;
+                if let Some(parameters) = pending_parameters.take() {
+                    scopes.push((depth, parameters));
+                }
+                SemanticKind::Punctuation
+            }
+            TokenType::RightBrace => {
+                if scopes.last().is_some_and(|(d, _)| *d == depth) {
+                    scopes.pop();
+                }
+                depth = depth.saturating_sub(1);
+                SemanticKind::Punctuation
+            }
+            TokenType::LeftBracket
+            | TokenType::RightBracket
+            | TokenType::Comma
+            | TokenType::Dot
+            | TokenType::Semicolon => SemanticKind::Punctuation,
+            TokenType::String => SemanticKind::String,
+            TokenType::Number => SemanticKind::Number,
+            TokenType::Eof | TokenType::Error | TokenType::Comment => continue,
+            token_type if is_keyword(token_type) => SemanticKind::Keyword,
+            _ => SemanticKind::Operator,
+        };
+        previous = Some(token.token_type);
+        semantic_tokens.push(SemanticToken {
+            kind,
+            span: token.span.clone(),
+            line: 0,
+            column: 0,
+            declaration,
+        });
+    }
+
+    semantic_tokens.extend(
+        tokens
+            .iter()
+            .filter(|t| t.token_type == TokenType::Comment)
+            .map(|t| SemanticToken {
+                kind: SemanticKind::Comment,
+                span: t.span.clone(),
+                line: 0,
+                column: 0,
+                declaration: false,
+            }),
+    );
+    semantic_tokens.sort_by_key(|t| t.span.start);
+    for token in &mut semantic_tokens {
+        let line = line_starts.partition_point(|&start| start <= token.span.start);
+        token.line = line;
+        token.column = token.span.start - line_starts[line - 1] + 1;
+    }
+    (semantic_tokens, errors)
+}
+
+fn is_keyword(token_type: TokenType) -> bool {
+    KEYWORDS.iter().any(|&(_, keyword)| keyword == token_type)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kinds(source: &str) -> Vec<(&str, SemanticKind)> {
+        let (tokens, errors) = semantic_tokens(source);
+        assert!(errors.is_empty());
+        tokens
+            .into_iter()
+            .map(|t| (&source[t.span], t.kind))
+            .collect()
+    }
+
+    #[test]
+    fn identifier_roles_are_resolved() {
+        use SemanticKind::*;
+        assert_eq!(
+            kinds("class A extends B {}\nfn f(x) { return x.y + f(1); } // done\nlet a = A;"),
+            vec![
+                ("class", Keyword),
+                ("A", Class),
+                ("extends", Keyword),
+                ("B", Class),
+                ("{", Punctuation),
+                ("}", Punctuation),
+                ("fn", Keyword),
+                ("f", Function),
+                ("(", Punctuation),
+                ("x", Parameter),
+                (")", Punctuation),
+                ("{", Punctuation),
+                ("return", Keyword),
+                ("x", Parameter),
+                (".", Punctuation),
+                ("y", Property),
+                ("+", Operator),
+                ("f", Function),
+                ("(", Punctuation),
+                ("1", Number),
+                (")", Punctuation),
+                (";", Punctuation),
+                ("}", Punctuation),
+                ("// done", Comment),
+                ("let", Keyword),
+                ("a", Variable),
+                ("=", Operator),
+                ("A", Class),
+                (";", Punctuation),
+            ]
+        );
+    }
+
+    #[test]
+    fn parameters_are_scoped_to_their_function() {
+        let tokens = kinds("fn f(x) { x; } x;");
+        assert_eq!(tokens[6], ("x", SemanticKind::Parameter));
+        assert_eq!(tokens[9], ("x", SemanticKind::Variable));
+    }
+
+    #[test]
+    fn positions_are_one_based() {
+        let (tokens, _) = semantic_tokens("let a;\n  \"s\"");
+        let last = tokens.last().unwrap();
+        assert_eq!((last.line, last.column, last.span.clone()), (2, 3, 9..12));
+        assert_eq!(
+            last.to_json().to_string(),
+            r#"{"kind":"string","start":9,"end":12,"line":2,"column":3,"declaration":false}"#
+        );
+    }
+}
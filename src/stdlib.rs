@@ -0,0 +1,103 @@
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    process::Command,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    sandbox::{resolve, Capability},
+    value::{FromValue, IntoValue, Value},
+    vm::{RuntimeError, Vm},
+};
+
+/// Defines the natives every `Vm` made with `Vm::builder` has. Each one
+/// checks the capability it needs before doing anything.
+pub(crate) fn register(vm: &mut Vm) {
+    vm.register_native("read_file", 1, read_file);
+    vm.register_native("write_file", 2, write_file);
+    vm.register_native("env", 1, env_var);
+    vm.register_native("clock", 0, clock);
+    vm.register_native("random", 0, random);
+    vm.register_native("spawn", 2, spawn);
+}
+
+fn read_file(vm: &mut Vm, arguments: &[Value]) -> Result<Value, RuntimeError> {
+    let path = PathBuf::from(String::from_value(&arguments[0])?);
+    vm.require(Capability::Read(path.clone()))?;
+    fs::read_to_string(checked(&path)?)
+        .map(IntoValue::into_value)
+        .map_err(|error| {
+            RuntimeError::new(format!("Could not read '{}': {error}.", path.display()))
+        })
+}
+
+/// The file at `path` as `Vm::require` resolved it, so what is opened is
+/// what was allowed.
+fn checked(path: &Path) -> Result<PathBuf, RuntimeError> {
+    resolve(path).ok_or_else(|| {
+        RuntimeError::new(format!(
+            "Could not resolve '{}': it changed while being opened.",
+            path.display()
+        ))
+    })
+}
+
+fn write_file(vm: &mut Vm, arguments: &[Value]) -> Result<Value, RuntimeError> {
+    let path = PathBuf::from(String::from_value(&arguments[0])?);
+    let contents = String::from_value(&arguments[1])?;
+    vm.require(Capability::Write(path.clone()))?;
+    fs::write(checked(&path)?, contents)
+        .map(IntoValue::into_value)
+        .map_err(|error| {
+            RuntimeError::new(format!("Could not write '{}': {error}.", path.display()))
+        })
+}
+
+/// The variable's value, or `null` if it isn't set.
+fn env_var(vm: &mut Vm, arguments: &[Value]) -> Result<Value, RuntimeError> {
+    let name = String::from_value(&arguments[0])?;
+    vm.require(Capability::Env)?;
+    Ok(env::var(name).ok().into_value())
+}
+
+/// Seconds since the Unix epoch.
+fn clock(vm: &mut Vm, _arguments: &[Value]) -> Result<Value, RuntimeError> {
+    vm.require(Capability::Clock)?;
+    let elapsed = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    Ok(elapsed.as_secs_f64().into_value())
+}
+
+/// A number in `[0, 1)`, from a xorshift generator seeded when the `Vm`
+/// was built. Not for cryptography.
+fn random(vm: &mut Vm, _arguments: &[Value]) -> Result<Value, RuntimeError> {
+    vm.require(Capability::Random)?;
+    let mut x = vm.rng;
+    x ^= x << 13;
+    x ^= x >> 7;
+    x ^= x << 17;
+    vm.rng = x;
+    Ok(((x >> 11) as f64 / (1u64 << 53) as f64).into_value())
+}
+
+/// Runs `program` with a list of arguments, returning what it printed.
+fn spawn(vm: &mut Vm, arguments: &[Value]) -> Result<Value, RuntimeError> {
+    let program = String::from_value(&arguments[0])?;
+    let args = Vec::<String>::from_value(&arguments[1])?;
+    vm.require(Capability::Spawn)?;
+    let output = Command::new(&program)
+        .args(args)
+        .output()
+        .map_err(|error| RuntimeError::new(format!("Could not run '{program}': {error}.")))?;
+    if !output.status.success() {
+        return Err(RuntimeError::new(format!(
+            "'{program}' failed with {}.",
+            output.status
+        )));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .into_owned()
+        .into_value())
+}
@@ -1 +1,353 @@
-pub type Value = f64;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    rc::Rc,
+};
+
+use crate::{
+    class::{NativeClass, Userdata},
+    function::{Closure, Function},
+    vm::{RuntimeError, Vm},
+};
+
+/// A rabbit value. Strings, lists and maps are reference counted, so
+/// cloning a value is cheap.
+///
+/// This is `ValueKind` itself, unless the `nan_boxing` feature packs it
+/// into 64 bits. Either way values are made the same way, as in
+/// `Value::Number(1.0)`, and code that must build with both looks inside
+/// them through `kind` and `into_kind`.
+#[cfg(not(feature = "nan_boxing"))]
+pub type Value = ValueKind;
+#[cfg(feature = "nan_boxing")]
+pub type Value = crate::nan_box::NanBox;
+
+/// What a value is, with the data it holds.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ValueKind {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(Rc<str>),
+    List(Rc<Vec<Value>>),
+    /// Maps have no literal syntax; scripts get them from native functions
+    /// and read them by indexing with a string.
+    Map(Rc<BTreeMap<Rc<str>, Value>>),
+    Function(Rc<Function>),
+    Closure(Rc<Closure>),
+    Native(Rc<Native>),
+    Class(Rc<NativeClass>),
+    Userdata(Rc<Userdata>),
+    /// A method read off an object, remembering the object to call it on.
+    BoundMethod(Rc<Userdata>, Rc<str>),
+}
+
+impl ValueKind {
+    /// Whether the value counts as false in a condition: only `null` and
+    /// `false` do.
+    pub fn is_falsey(&self) -> bool {
+        matches!(self, ValueKind::Null | ValueKind::Bool(false))
+    }
+    /// The name of the value's type, as used in error messages.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            ValueKind::Null => "null",
+            ValueKind::Bool(_) => "bool",
+            ValueKind::Number(_) => "number",
+            ValueKind::String(_) => "string",
+            ValueKind::List(_) => "list",
+            ValueKind::Map(_) => "map",
+            ValueKind::Function(_)
+            | ValueKind::Closure(_)
+            | ValueKind::Native(_)
+            | ValueKind::BoundMethod(..) => "function",
+            ValueKind::Class(_) => "class",
+            ValueKind::Userdata(_) => "object",
+        }
+    }
+}
+
+/// What `NanBox` has too, so code using them builds with either.
+#[cfg(not(feature = "nan_boxing"))]
+impl ValueKind {
+    /// The value, sharing its data.
+    pub fn kind(&self) -> ValueKind {
+        self.clone()
+    }
+    pub fn into_kind(self) -> ValueKind {
+        self
+    }
+    pub fn as_number(&self) -> Option<f64> {
+        match self {
+            ValueKind::Number(number) => Some(*number),
+            _ => None,
+        }
+    }
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            ValueKind::String(string) => Some(string),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ValueKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Strings are quoted inside collections, so `["a"]` and `[a]` differ.
+        fn item(f: &mut fmt::Formatter<'_>, item: &Value) -> fmt::Result {
+            match item.as_str() {
+                Some(s) => write!(f, "{s:?}"),
+                None => write!(f, "{item}"),
+            }
+        }
+        match self {
+            ValueKind::Null => write!(f, "null"),
+            ValueKind::Bool(b) => write!(f, "{b}"),
+            ValueKind::Number(n) => write!(f, "{n}"),
+            ValueKind::String(s) => write!(f, "{s}"),
+            ValueKind::List(items) => {
+                write!(f, "[")?;
+                for (i, value) in items.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    item(f, value)?;
+                }
+                write!(f, "]")
+            }
+            ValueKind::Map(entries) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in entries.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{key:?}: ")?;
+                    item(f, value)?;
+                }
+                write!(f, "}}")
+            }
+            ValueKind::Function(function) => write!(f, "{function}"),
+            ValueKind::Closure(closure) => write!(f, "{}", closure.function),
+            ValueKind::Native(native) => write!(f, "<native fn {}>", native.name),
+            ValueKind::Class(class) => write!(f, "<class {}>", class.name()),
+            ValueKind::Userdata(object) => write!(f, "<{} object>", object.class().name()),
+            ValueKind::BoundMethod(object, method) => {
+                write!(f, "<native fn {}.{method}>", object.class().name())
+            }
+        }
+    }
+}
+
+/// The signature of Rust functions callable from scripts. Arguments have
+/// already been checked against the declared arity.
+pub type NativeFn = fn(&mut Vm, &[Value]) -> Result<Value, RuntimeError>;
+
+/// A Rust function registered with `Vm::register_native`.
+pub struct Native {
+    pub name: Rc<str>,
+    pub arity: usize,
+    pub function: NativeFn,
+}
+
+impl fmt::Debug for Native {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Native({}/{})", self.name, self.arity)
+    }
+}
+
+impl PartialEq for Native {
+    /// Natives are only equal to themselves, like functions in scripts.
+    fn eq(&self, other: &Native) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+/// Conversion of Rust values into rabbit values, for the results of native
+/// functions.
+pub trait IntoValue {
+    fn into_value(self) -> Value;
+}
+
+/// Conversion of rabbit values into Rust values, for the arguments of
+/// native functions. Fails with a runtime error naming the expected type.
+pub trait FromValue: Sized {
+    fn from_value(value: &Value) -> Result<Self, RuntimeError>;
+}
+
+fn type_error<T>(expected: &str, value: &Value) -> Result<T, RuntimeError> {
+    Err(RuntimeError::new(format!(
+        "Expected {expected} but got {}.",
+        value.type_name()
+    )))
+}
+
+impl IntoValue for Value {
+    fn into_value(self) -> Value {
+        self
+    }
+}
+
+impl FromValue for Value {
+    fn from_value(value: &Value) -> Result<Self, RuntimeError> {
+        Ok(value.clone())
+    }
+}
+
+#[cfg(feature = "nan_boxing")]
+impl IntoValue for ValueKind {
+    fn into_value(self) -> Value {
+        Value::from(self)
+    }
+}
+
+impl IntoValue for () {
+    fn into_value(self) -> Value {
+        Value::Null
+    }
+}
+
+impl IntoValue for f64 {
+    fn into_value(self) -> Value {
+        Value::Number(self)
+    }
+}
+
+impl FromValue for f64 {
+    fn from_value(value: &Value) -> Result<Self, RuntimeError> {
+        match value.as_number() {
+            Some(n) => Ok(n),
+            None => type_error("a number", value),
+        }
+    }
+}
+
+impl IntoValue for bool {
+    fn into_value(self) -> Value {
+        Value::Bool(self)
+    }
+}
+
+impl FromValue for bool {
+    fn from_value(value: &Value) -> Result<Self, RuntimeError> {
+        match value.kind() {
+            ValueKind::Bool(b) => Ok(b),
+            _ => type_error("a bool", value),
+        }
+    }
+}
+
+impl IntoValue for &str {
+    fn into_value(self) -> Value {
+        Value::String(Rc::from(self))
+    }
+}
+
+impl IntoValue for String {
+    fn into_value(self) -> Value {
+        Value::String(Rc::from(self))
+    }
+}
+
+impl FromValue for String {
+    fn from_value(value: &Value) -> Result<Self, RuntimeError> {
+        match value.as_str() {
+            Some(s) => Ok(s.to_string()),
+            None => type_error("a string", value),
+        }
+    }
+}
+
+impl<T: IntoValue> IntoValue for Vec<T> {
+    fn into_value(self) -> Value {
+        Value::List(Rc::new(self.into_iter().map(T::into_value).collect()))
+    }
+}
+
+impl<T: FromValue> FromValue for Vec<T> {
+    fn from_value(value: &Value) -> Result<Self, RuntimeError> {
+        match value.kind() {
+            ValueKind::List(items) => items.iter().map(T::from_value).collect(),
+            _ => type_error("a list", value),
+        }
+    }
+}
+
+impl<T: IntoValue> IntoValue for HashMap<String, T> {
+    fn into_value(self) -> Value {
+        let entries = self
+            .into_iter()
+            .map(|(key, value)| (Rc::from(key), value.into_value()));
+        Value::Map(Rc::new(entries.collect()))
+    }
+}
+
+impl<T: FromValue> FromValue for HashMap<String, T> {
+    fn from_value(value: &Value) -> Result<Self, RuntimeError> {
+        match value.kind() {
+            ValueKind::Map(entries) => entries
+                .iter()
+                .map(|(key, value)| Ok((key.to_string(), T::from_value(value)?)))
+                .collect(),
+            _ => type_error("a map", value),
+        }
+    }
+}
+
+/// `None` is `null`.
+impl<T: IntoValue> IntoValue for Option<T> {
+    fn into_value(self) -> Value {
+        self.map_or(Value::Null, T::into_value)
+    }
+}
+
+impl<T: FromValue> FromValue for Option<T> {
+    fn from_value(value: &Value) -> Result<Self, RuntimeError> {
+        match value.kind() {
+            ValueKind::Null => Ok(None),
+            _ => T::from_value(value).map(Some),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rust_values_round_trip() {
+        let list = vec![Some(1.0), None].into_value();
+        assert_eq!(list.to_string(), "[1, null]");
+        assert_eq!(
+            Vec::<Option<f64>>::from_value(&list),
+            Ok(vec![Some(1.0), None])
+        );
+
+        let map = HashMap::from([(String::from("b"), vec![true]), (String::from("a"), vec![])]);
+        let value = map.clone().into_value();
+        assert_eq!(value.to_string(), "{\"a\": [], \"b\": [true]}");
+        assert_eq!(HashMap::from_value(&value), Ok(map));
+
+        assert_eq!(String::from_value(&"s".into_value()), Ok(String::from("s")));
+    }
+
+    #[test]
+    fn mismatched_types_are_reported() {
+        let error = Vec::<f64>::from_value(&vec!["a"].into_value()).unwrap_err();
+        assert_eq!(error.to_string(), "Expected a number but got string.");
+        assert!(bool::from_value(&Value::Null).is_err());
+        assert!(Option::<bool>::from_value(&Value::Number(1.0)).is_err());
+    }
+
+    #[test]
+    fn values_stay_on_their_thread() {
+        // Naming `item` is ambiguous, and doesn't compile, if `Value` is
+        // `Send` or `Sync`: it holds `Rc`s, which count without atomics.
+        trait AmbiguousIfShared<A> {
+            fn item() {}
+        }
+        impl<T: ?Sized> AmbiguousIfShared<()> for T {}
+        impl<T: ?Sized + Send> AmbiguousIfShared<u8> for T {}
+        impl<T: ?Sized + Sync> AmbiguousIfShared<u16> for T {}
+        <Value as AmbiguousIfShared<_>>::item();
+    }
+}
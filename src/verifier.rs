@@ -0,0 +1,355 @@
+use std::{error::Error, fmt};
+
+use crate::{
+    chunk::{Chunk, OpCode, VerifiedAs},
+    function::{Capture, Closure, Function},
+    value::ValueKind,
+};
+
+/// Checks that running `chunk` as a script can't make the VM index out of
+/// bounds: constants, locals, upvalues and jump targets exist, the stack
+/// never underflows and has the same height however an instruction is
+/// reached, and execution can't run off the end. Nested functions are
+/// checked too.
+pub fn verify(chunk: &Chunk) -> Result<(), VerifyError> {
+    verify_chunk(chunk, "<script>", VerifiedAs::SCRIPT)
+}
+
+/// Checks a closure made outside of any script, as a nested function
+/// would be, against the upvalues it actually has.
+pub(crate) fn verify_closure(closure: &Closure) -> Result<(), VerifyError> {
+    let function = &closure.function;
+    verify_chunk(
+        &function.chunk,
+        &function.to_string(),
+        VerifiedAs::function(function.arity, closure.upvalues.len()),
+    )
+}
+
+/// Why a chunk was rejected, and the instruction at fault.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VerifyError {
+    /// The function the instruction belongs to, as `<fn name>` or
+    /// `<script>`.
+    pub function: String,
+    pub offset: usize,
+    pub message: String,
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Invalid bytecode in {} at offset {}: {}",
+            self.function, self.offset, self.message
+        )
+    }
+}
+
+impl Error for VerifyError {}
+
+/// `verified.height` is what the stack holds above the frame's base on
+/// entry, the parameters of a function, and `verified.upvalues` how many
+/// upvalues the enclosing function has for captures to refer to. Once
+/// verified, the chunk remembers the most stack slots it needs when run
+/// that way, so the VM can run it without bounds checks.
+fn verify_chunk(chunk: &Chunk, name: &str, verified: VerifiedAs) -> Result<(), VerifyError> {
+    let VerifiedAs {
+        height,
+        upvalues,
+        is_function,
+    } = verified;
+    let code = chunk.code();
+    let error = |offset: usize, message: String| VerifyError {
+        function: name.to_string(),
+        offset,
+        message,
+    };
+    if code.is_empty() {
+        return Err(error(0, String::from("The chunk has no instructions.")));
+    }
+    let constant = |offset: usize, index: usize| {
+        chunk
+            .constants()
+            .get(index)
+            .ok_or_else(|| error(offset, format!("Constant {index} doesn't exist.")))
+    };
+    let mut starts = vec![false; code.len()];
+    for (offset, _) in chunk.instructions() {
+        starts[offset] = true;
+    }
+    let mut heights: Vec<Option<usize>> = vec![None; code.len()];
+    let mut slots = height;
+    let mut pending = vec![(0, height)];
+    while let Some((offset, height)) = pending.pop() {
+        match heights[offset] {
+            Some(seen) if seen == height => continue,
+            Some(seen) => {
+                return Err(error(
+                    offset,
+                    format!("Stack height is {seen} on one path here and {height} on another."),
+                ))
+            }
+            None => heights[offset] = Some(height),
+        }
+        let instruction = chunk.decode(offset);
+        let (pops, pushes) = stack_effect(instruction);
+        if height < pops {
+            return Err(error(
+                offset,
+                format!("Needs {pops} values on the stack but there are {height}."),
+            ));
+        }
+        match instruction {
+            OpCode::Constant(index) | OpCode::ConstantLong(index) => {
+                constant(offset, index)?;
+            }
+            OpCode::AddLocalConst(slot, index) => {
+                if slot >= height {
+                    return Err(error(offset, format!("Local slot {slot} is out of range.")));
+                }
+                constant(offset, index)?;
+            }
+            OpCode::GetGlobal(index)
+            | OpCode::DefineGlobal(index)
+            | OpCode::SetGlobal(index)
+            | OpCode::GetProperty(index)
+            | OpCode::SetProperty(index) => {
+                let Some(_) = constant(offset, index)?.as_str() else {
+                    return Err(error(offset, format!("Constant {index} isn't a name.")));
+                };
+            }
+            OpCode::GetLocal(slot) | OpCode::SetLocal(slot) | OpCode::IncrementLocal(slot)
+                if slot >= height =>
+            {
+                return Err(error(offset, format!("Local slot {slot} is out of range.")));
+            }
+            OpCode::GetUpvalue(index) | OpCode::SetUpvalue(index) if index >= upvalues => {
+                return Err(error(offset, format!("Upvalue {index} doesn't exist.")));
+            }
+            OpCode::Closure(index) => {
+                let ValueKind::Function(function) = constant(offset, index)?.kind() else {
+                    return Err(error(offset, format!("Constant {index} isn't a function.")));
+                };
+                verify_function(&function, upvalues)?;
+                for capture in &function.captures {
+                    match *capture {
+                        Capture::Local(slot) if slot >= height => {
+                            return Err(error(
+                                offset,
+                                format!("Captured local slot {slot} is out of range."),
+                            ));
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            OpCode::Return if is_function && height == 0 => {
+                return Err(error(offset, String::from("Nothing to return.")));
+            }
+            _ => {}
+        }
+        let height = height - pops + pushes;
+        slots = slots.max(height);
+        let next = offset + instruction.size();
+        let jump = match instruction {
+            OpCode::Jump(distance)
+            | OpCode::JumpIfFalse(distance)
+            | OpCode::JumpIfNotLess(distance) => Some(next.checked_add(distance)),
+            OpCode::Loop(distance) => Some(next.checked_sub(distance)),
+            _ => None,
+        };
+        match jump {
+            Some(Some(target)) if target < code.len() && starts[target] => {
+                pending.push((target, height))
+            }
+            Some(Some(target)) if target < code.len() => {
+                return Err(error(
+                    offset,
+                    String::from("Jump target is inside an instruction."),
+                ))
+            }
+            Some(_) => return Err(error(offset, String::from("Jump target is out of range."))),
+            None => {}
+        }
+        if !matches!(
+            instruction,
+            OpCode::Jump(_) | OpCode::Loop(_) | OpCode::Return
+        ) {
+            if next == code.len() {
+                return Err(error(
+                    offset,
+                    String::from("Execution runs off the end of the chunk."),
+                ));
+            }
+            pending.push((next, height));
+        }
+    }
+    chunk.set_verified_stack(verified, slots);
+    Ok(())
+}
+
+fn verify_function(function: &Function, upvalues: usize) -> Result<(), VerifyError> {
+    for capture in &function.captures {
+        if let Capture::Upvalue(index) = *capture {
+            if index >= upvalues {
+                return Err(VerifyError {
+                    function: function.to_string(),
+                    offset: 0,
+                    message: format!("Captured upvalue {index} doesn't exist."),
+                });
+            }
+        }
+    }
+    verify_chunk(
+        &function.chunk,
+        &function.to_string(),
+        VerifiedAs::function(function.arity, function.captures.len()),
+    )
+}
+
+/// How many values `instruction` pops, then pushes.
+fn stack_effect(instruction: OpCode) -> (usize, usize) {
+    match instruction {
+        OpCode::Constant(_)
+        | OpCode::ConstantLong(_)
+        | OpCode::Null
+        | OpCode::True
+        | OpCode::False
+        | OpCode::GetLocal(_)
+        | OpCode::GetGlobal(_)
+        | OpCode::GetUpvalue(_)
+        | OpCode::Closure(_)
+        | OpCode::AddLocalConst(..) => (0, 1),
+        OpCode::Pop | OpCode::DefineGlobal(_) | OpCode::Print | OpCode::CloseUpvalue => (1, 0),
+        OpCode::SetLocal(_)
+        | OpCode::SetGlobal(_)
+        | OpCode::SetUpvalue(_)
+        | OpCode::GetProperty(_)
+        | OpCode::Not
+        | OpCode::Negate
+        | OpCode::JumpIfFalse(_) => (1, 1),
+        OpCode::SetProperty(_)
+        | OpCode::Equal
+        | OpCode::Greater
+        | OpCode::Less
+        | OpCode::Add
+        | OpCode::Subtract
+        | OpCode::Multiply
+        | OpCode::Divide
+        | OpCode::Modulo
+        | OpCode::Index => (2, 1),
+        OpCode::BuildList(count) => (count, 1),
+        OpCode::Call(count) => (count + 1, 1),
+        OpCode::JumpIfNotLess(_) => (2, 0),
+        OpCode::Jump(_) | OpCode::Loop(_) | OpCode::Return | OpCode::IncrementLocal(_) => (0, 0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::{chunk::Span, compiler::compile, error::RabbitError, value::Value, vm::Vm};
+
+    fn chunk(code: &[OpCode], constants: Vec<Value>) -> Chunk {
+        let mut chunk = Chunk::new();
+        for &opcode in code {
+            chunk.write(opcode, Span { line: 1, column: 1 });
+        }
+        for constant in constants {
+            chunk.add_constant(constant);
+        }
+        chunk
+    }
+
+    fn message(code: &[OpCode], constants: Vec<Value>) -> (usize, String) {
+        let error = verify(&chunk(code, constants)).unwrap_err();
+        (error.offset, error.message)
+    }
+
+    #[test]
+    fn compiled_chunks_verify() {
+        let source = "
+            fn counter(step) {
+                let count = 0;
+                fn next() { count += step; return count; }
+                return next;
+            }
+            let c = counter(2);
+            { let a = [1, 2]; while a[0] < 10 { if a[1] { a = [a[0] + c(), false]; } } }
+            print c().x;
+        ";
+        assert_eq!(verify(&compile(source).unwrap()), Ok(()));
+    }
+
+    #[test]
+    fn bad_chunks_are_rejected_at_the_offending_offset() {
+        use OpCode::*;
+        let number = || Value::Number(1.0);
+        assert_eq!(
+            message(&[Constant(1), Pop, Return], vec![number()]),
+            (0, String::from("Constant 1 doesn't exist."))
+        );
+        assert_eq!(
+            message(&[Constant(0), Add, Return], vec![number()]),
+            (
+                2,
+                String::from("Needs 2 values on the stack but there are 1.")
+            )
+        );
+        assert_eq!(
+            message(&[GetGlobal(0), Pop, Return], vec![number()]),
+            (0, String::from("Constant 0 isn't a name."))
+        );
+        assert_eq!(
+            message(&[Null, GetLocal(1), Return], vec![]),
+            (1, String::from("Local slot 1 is out of range."))
+        );
+        assert_eq!(
+            message(&[GetUpvalue(0), Return], vec![]),
+            (0, String::from("Upvalue 0 doesn't exist."))
+        );
+        assert_eq!(
+            message(&[True, JumpIfFalse(5), Return], vec![]),
+            (1, String::from("Jump target is out of range."))
+        );
+        assert_eq!(
+            message(&[True, JumpIfFalse(1), GetLocal(0), Pop, Return], vec![]),
+            (1, String::from("Jump target is inside an instruction."))
+        );
+        assert_eq!(
+            message(&[True, JumpIfFalse(1), Pop, Return], vec![]),
+            (
+                5,
+                String::from("Stack height is 0 on one path here and 1 on another.")
+            )
+        );
+        assert_eq!(
+            message(&[Null, Pop], vec![]),
+            (1, String::from("Execution runs off the end of the chunk."))
+        );
+        let result = Vm::new().interpret(chunk(&[Pop, Return], vec![]));
+        assert!(matches!(result, Err(RabbitError::Verify(_))));
+    }
+
+    #[test]
+    fn nested_functions_are_verified() {
+        let function = Function {
+            name: Some(Rc::from("f")),
+            arity: 1,
+            chunk: chunk(&[OpCode::GetLocal(1), OpCode::Return], vec![]),
+            captures: vec![],
+        };
+        let script = chunk(
+            &[OpCode::Closure(0), OpCode::Pop, OpCode::Return],
+            vec![Value::Function(Rc::new(function))],
+        );
+        let error = verify(&script).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Invalid bytecode in <fn f> at offset 0: Local slot 1 is out of range."
+        );
+    }
+}
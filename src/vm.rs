@@ -1,80 +1,1244 @@
+use std::{
+    cell::RefCell, cmp::Ordering, collections::HashMap, error::Error, fmt, io::Write, mem::size_of,
+    rc::Rc, time::Instant,
+};
+
 use crate::{
-    chunk::{Chunk, OpCode},
-    value::Value,
+    chunk::{Chunk, OpCode, Span, VerifiedAs},
+    class::ClassBuilder,
+    error::RabbitError,
+    function::{Capture, Closure, Function, Upvalue},
+    limits::{Limit, Limits},
+    sandbox::Capability,
+    stdlib,
+    value::{Native, NativeFn, Value, ValueKind},
+    verifier::{verify, verify_closure},
 };
 
-pub struct Vm {
-    chunk: Chunk,
+/// How deep calls can nest before the script is stopped with a stack
+/// overflow.
+const MAX_FRAMES: usize = 1024;
+
+/// How many values the stack holds, allocated once so pushing never has to
+/// grow it. That's 64 for each frame on average; deeper use is a stack
+/// overflow too.
+const STACK_MAX: usize = MAX_FRAMES * 64;
+
+struct CallFrame {
+    closure: Rc<Closure>,
     ip: usize,
+    /// The stack slot of the first argument. The callee sits right below.
+    base: usize,
+}
+
+/// Why `run_frame` stopped running its frame.
+enum Exit {
+    /// A call or return changed the frame on top, which runs next.
+    Switch,
+    /// The frame `run` was waiting for returned this.
+    Done(Option<Value>),
+}
+
+/// The bytecode interpreter. Globals, and settings such as tracing, live
+/// as long as the `Vm` does, across every chunk it runs.
+#[derive(Default)]
+pub struct Vm {
+    frames: Vec<CallFrame>,
     stack: Vec<Value>,
+    globals: HashMap<Rc<str>, Value>,
+    /// Upvalues still pointing into the stack, which must be closed when
+    /// their slot is popped.
+    open_upvalues: Vec<Rc<RefCell<Upvalue>>>,
+    trace: Option<Box<dyn Write>>,
+    limits: Limits,
+    /// Instructions executed and bytes allocated since the outermost call
+    /// started, for `limits`.
+    executed: u64,
+    allocated: usize,
+    /// What the standard library may do, when the `Vm` has one.
+    pub(crate) capabilities: Vec<Capability>,
+    pub(crate) stdlib: bool,
+    /// State of the generator behind `random()`.
+    pub(crate) rng: u64,
 }
 
 macro_rules! binary_op {
-    ($self:tt, $op:tt) => {
-       if let Some(b) = $self.stack.pop() {
-            if let Some(a) = $self.stack.pop() {
-                $self.stack.push(a $op b);
-            }
+    ($self:ident, $value_type:path, $op:tt) => {{
+        let b = $self.pop();
+        let a = $self.pop();
+        match (a.as_number(), b.as_number()) {
+            (Some(a), Some(b)) => $self.push($value_type(a $op b)),
+            _ => return Err($self.runtime_error("Operands must be numbers.")),
         }
-    };
+    }};
 }
 
 impl Vm {
-    pub fn new(chunk: Chunk) -> Self {
-        Self {
-            chunk,
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Makes the VM write the stack and each instruction to `out` before
+    /// executing it, or stop doing so when `out` is `None`.
+    pub fn set_trace(&mut self, out: Option<Box<dyn Write>>) {
+        self.trace = out;
+    }
+    /// Bounds every script run from now on, or lifts the bounds with
+    /// `Limits::default()`.
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+    /// Runs `chunk` to completion. Globals outlive the call, so a REPL can
+    /// keep feeding chunks to the same `Vm`. The result is the value of a
+    /// final bare expression of a REPL entry, `null` if there is none.
+    pub fn interpret(&mut self, chunk: Chunk) -> Result<Value, RabbitError> {
+        Ok(self.evaluate(chunk)?.unwrap_or(Value::Null))
+    }
+    /// Like `interpret`, but tells apart entries that leave no value.
+    /// Either way, the chunk is verified before it runs.
+    pub fn evaluate(&mut self, chunk: Chunk) -> Result<Option<Value>, RabbitError> {
+        verify(&chunk)?;
+        Ok(self.run_script(chunk)?)
+    }
+    fn run_script(&mut self, chunk: Chunk) -> Result<Option<Value>, RuntimeError> {
+        let script = Closure {
+            function: Rc::new(Function {
+                name: None,
+                arity: 0,
+                chunk,
+                captures: vec![],
+            }),
+            upvalues: vec![],
+        };
+        self.start_counting();
+        let slots = script.function.chunk.verified_stack(VerifiedAs::SCRIPT);
+        let slots = slots.expect("scripts are verified before they run");
+        self.check_room(self.stack.len() + 1, slots)?;
+        let depth = self.frames.len();
+        let script = Rc::new(script);
+        self.push(Value::Closure(script.clone()));
+        self.frames.push(CallFrame {
+            closure: script,
             ip: 0,
-            stack: vec![],
+            base: self.stack.len(),
+        });
+        self.run(depth)
+    }
+    /// Calls a rabbit function, or anything else scripts can call, with
+    /// `arguments`. Natives may use this to call back into scripts.
+    pub fn call(&mut self, callee: &Value, arguments: &[Value]) -> Result<Value, RuntimeError> {
+        let ValueKind::Closure(closure) = callee.kind() else {
+            return self.call_native(callee, arguments);
+        };
+        self.start_counting();
+        let height = self.stack.len();
+        self.check_room(height, arguments.len() + 1)?;
+        self.push(callee.clone());
+        for argument in arguments {
+            self.push(argument.clone());
+        }
+        let depth = self.frames.len();
+        if let Err(error) = self.call_closure(closure.clone(), arguments.len()) {
+            self.stack.truncate(height);
+            return Err(error);
         }
+        Ok(self.run(depth)?.unwrap_or(Value::Null))
+    }
+    /// Forgets every global, keeping the VM's settings and standard library.
+    /// Called by a native while a script runs, the script's stack is left
+    /// alone, as the script goes on once the native returns.
+    pub fn reset(&mut self) {
+        self.globals.clear();
+        if self.frames.is_empty() {
+            self.stack.clear();
+            self.open_upvalues.clear();
+        }
+        if self.stdlib {
+            stdlib::register(self);
+        }
+    }
+    pub fn get_global(&self, name: &str) -> Option<&Value> {
+        self.globals.get(name)
+    }
+    /// Defines or overwrites a global variable visible to scripts.
+    pub fn set_global(&mut self, name: &str, value: Value) {
+        self.globals.insert(Rc::from(name), value);
+    }
+    /// Makes the Rust `function` callable from scripts as the global `name`,
+    /// taking exactly `arity` arguments.
+    pub fn register_native(&mut self, name: &str, arity: usize, function: NativeFn) {
+        let native = Native {
+            name: Rc::from(name),
+            arity,
+            function,
+        };
+        self.set_global(name, Value::Native(Rc::new(native)));
+    }
+    /// Makes the Rust type described by `class` available to scripts under
+    /// the class's name.
+    pub fn register_class<T: 'static>(&mut self, class: ClassBuilder<T>) {
+        let class = class.build();
+        let name = Rc::from(class.name());
+        self.globals.insert(name, Value::Class(Rc::new(class)));
     }
-    pub fn interpret(&mut self) -> InterpretResult {
-        self.run()
+    /// Every global variable, in no particular order.
+    pub fn globals(&self) -> impl Iterator<Item = (&str, &Value)> {
+        self.globals.iter().map(|(name, value)| (&**name, value))
     }
-    fn run(&mut self) -> InterpretResult {
+    /// Runs until the frame at `depth` returns, which may be a nested call
+    /// made by a native function.
+    fn run(&mut self, depth: usize) -> Result<Option<Value>, RuntimeError> {
+        // Two copies of the loop, so the untraced one has no check at all.
+        let result = if self.trace.is_some() {
+            self.run_loop::<true>(depth)
+        } else {
+            self.run_loop::<false>(depth)
+        };
+        result.map_err(|error| self.unwind(error, depth))
+    }
+    fn run_loop<const TRACE: bool>(&mut self, depth: usize) -> Result<Option<Value>, RuntimeError> {
         loop {
-            let instruction = &self.chunk.code[self.ip];
-            #[cfg(feature = "debug_trace_execution")]
-            self.disassemble_instruction(instruction);
-            self.ip += 1;
+            let frame = self.frames.last().expect("a frame is running");
+            let closure = frame.closure.clone();
+            let base = frame.base;
+            let mut ip = frame.ip;
+            match self.run_frame::<TRACE>(&closure, &mut ip, base, depth) {
+                Ok(Exit::Switch) => {}
+                Ok(Exit::Done(result)) => return Ok(result),
+                Err(error) => {
+                    self.frame_mut().ip = ip;
+                    return Err(error);
+                }
+            }
+        }
+    }
+    /// Runs the frame on top, which is running `closure` from `base`, until
+    /// it calls or returns. The instruction pointer stays in `ip` meanwhile,
+    /// and is only stored in the frame before a call.
+    ///
+    /// Every chunk that gets a frame has been verified, so instructions,
+    /// constants and locals are read without bounds checks.
+    #[inline(always)]
+    fn run_frame<const TRACE: bool>(
+        &mut self,
+        closure: &Closure,
+        ip: &mut usize,
+        base: usize,
+        depth: usize,
+    ) -> Result<Exit, RuntimeError> {
+        let chunk = &closure.function.chunk;
+        let constants = chunk.constants();
+        loop {
+            // SAFETY: the verifier checked that execution only reaches
+            // whole instructions and never runs off the end.
+            let (instruction, size) = unsafe { chunk.read_unchecked(*ip) };
+            if TRACE {
+                self.disassemble_instruction(chunk, *ip, &instruction);
+            }
+            *ip += size;
+            self.executed += 1;
             match instruction {
-                OpCode::Constant(index) => {
-                    let constant = self.chunk.constants[*index];
-                    self.stack.push(constant);
-                }
-                OpCode::Add => binary_op!(self, +),
-                OpCode::Subtract => binary_op!(self, -),
-                OpCode::Multiply => binary_op!(self, *),
-                OpCode::Divide => binary_op!(self, /),
-                OpCode::Negate => {
-                    if let Some(value) = self.stack.pop() {
-                        self.stack.push(-value);
+                OpCode::Constant(index) | OpCode::ConstantLong(index) => {
+                    // SAFETY: the verifier checked that the constant exists.
+                    let constant = unsafe { constants.get_unchecked(index) }.clone();
+                    self.push(constant);
+                }
+                OpCode::Null => self.push(Value::Null),
+                OpCode::True => self.push(Value::Bool(true)),
+                OpCode::False => self.push(Value::Bool(false)),
+                OpCode::Pop => {
+                    self.pop();
+                }
+                OpCode::GetLocal(slot) => {
+                    let value = self.local(base + slot).clone();
+                    self.push(value);
+                }
+                OpCode::SetLocal(slot) => {
+                    *self.local_mut(base + slot) = self.peek().clone();
+                }
+                OpCode::GetUpvalue(index) => {
+                    let upvalue = closure.upvalues[index].clone();
+                    let value = match &*upvalue.borrow() {
+                        Upvalue::Open(slot) => self.stack[*slot].clone(),
+                        Upvalue::Closed(value) => value.clone(),
+                    };
+                    self.push(value);
+                }
+                OpCode::SetUpvalue(index) => {
+                    let upvalue = closure.upvalues[index].clone();
+                    let value = self.peek().clone();
+                    match &mut *upvalue.borrow_mut() {
+                        Upvalue::Open(slot) => self.stack[*slot] = value,
+                        Upvalue::Closed(closed) => *closed = value,
+                    };
+                }
+                OpCode::GetGlobal(index) => {
+                    let name = constant_name(constants, index);
+                    match self.globals.get(&name) {
+                        Some(value) => self.push(value.clone()),
+                        None => {
+                            return Err(self.runtime_error(&format!("Undefined variable '{name}'.")))
+                        }
+                    }
+                }
+                OpCode::DefineGlobal(index) => {
+                    let name = constant_name(constants, index);
+                    let value = self.pop();
+                    self.globals.insert(name, value);
+                }
+                OpCode::SetGlobal(index) => {
+                    let name = constant_name(constants, index);
+                    let value = self.peek().clone();
+                    match self.globals.get_mut(&name) {
+                        Some(global) => *global = value,
+                        None => {
+                            return Err(self.runtime_error(&format!("Undefined variable '{name}'.")))
+                        }
+                    }
+                }
+                OpCode::GetProperty(index) => {
+                    let name = constant_name(constants, index);
+                    let ValueKind::Userdata(object) = self.pop().into_kind() else {
+                        return Err(self.runtime_error("Only objects have properties."));
+                    };
+                    match object.get(&name) {
+                        Ok(Some(value)) => self.push(value),
+                        Ok(None) if object.has_method(&name) => {
+                            self.push(Value::BoundMethod(object, name))
+                        }
+                        Ok(None) => {
+                            return Err(self.runtime_error(&format!("Undefined property '{name}'.")))
+                        }
+                        Err(error) => return Err(error),
+                    }
+                }
+                OpCode::SetProperty(index) => {
+                    let name = constant_name(constants, index);
+                    let value = self.pop();
+                    let ValueKind::Userdata(object) = self.pop().into_kind() else {
+                        return Err(self.runtime_error("Only objects have properties."));
+                    };
+                    match object.set(&name, &value) {
+                        Ok(true) => self.push(value),
+                        Ok(false) if object.has_getter(&name) => {
+                            return Err(
+                                self.runtime_error(&format!("Property '{name}' is read-only."))
+                            )
+                        }
+                        Ok(false) => {
+                            return Err(self.runtime_error(&format!("Undefined property '{name}'.")))
+                        }
+                        Err(mut error) => {
+                            let setter = format!("{}.{name}", object.class().name());
+                            error.push_frame(TraceFrame::native(setter));
+                            return Err(error);
+                        }
+                    }
+                }
+                OpCode::Equal => {
+                    let b = self.pop();
+                    let a = self.pop();
+                    self.push(Value::Bool(a == b));
+                }
+                OpCode::Greater => binary_op!(self, Value::Bool, >),
+                OpCode::Less => binary_op!(self, Value::Bool, <),
+                OpCode::Add => {
+                    let b = self.pop();
+                    let a = self.pop();
+                    let sum = self.add(a, b)?;
+                    self.push(sum);
+                }
+                OpCode::Subtract => binary_op!(self, Value::Number, -),
+                OpCode::Multiply => binary_op!(self, Value::Number, *),
+                OpCode::Divide => binary_op!(self, Value::Number, /),
+                OpCode::Modulo => binary_op!(self, Value::Number, %),
+                OpCode::BuildList(count) => {
+                    let items: Vec<Value> = self.stack.drain(self.stack.len() - count..).collect();
+                    self.allocated += count * size_of::<Value>();
+                    self.push(Value::List(Rc::new(items)));
+                }
+                OpCode::Index => {
+                    let index = self.pop();
+                    match (self.pop().into_kind(), index.into_kind()) {
+                        (ValueKind::List(items), ValueKind::Number(index)) => {
+                            match items.get(index as usize) {
+                                Some(item) if index >= 0.0 && index.fract() == 0.0 => {
+                                    self.push(item.clone())
+                                }
+                                _ => return Err(self.runtime_error("List index out of range.")),
+                            }
+                        }
+                        (ValueKind::Map(entries), ValueKind::String(key)) => {
+                            match entries.get(&key) {
+                                Some(value) => self.push(value.clone()),
+                                None => {
+                                    return Err(
+                                        self.runtime_error(&format!("Undefined key '{key}'."))
+                                    )
+                                }
+                            }
+                        }
+                        _ => {
+                            return Err(self.runtime_error(
+                                "Only lists can be indexed by numbers and maps by strings.",
+                            ))
+                        }
+                    }
+                }
+                OpCode::Jump(distance) => *ip += distance,
+                OpCode::JumpIfFalse(distance) => {
+                    if self.peek().is_falsey() {
+                        *ip += distance;
+                    }
+                }
+                OpCode::Loop(distance) => {
+                    self.check_limits()?;
+                    *ip -= distance;
+                }
+                OpCode::Call(count) => {
+                    self.check_limits()?;
+                    let callee = self.stack[self.stack.len() - 1 - count].clone();
+                    if let ValueKind::Closure(callee) = callee.kind() {
+                        self.frame_mut().ip = *ip;
+                        self.call_closure(callee, count)?;
+                        return Ok(Exit::Switch);
+                    } else {
+                        let arguments: Vec<Value> =
+                            self.stack.drain(self.stack.len() - count..).collect();
+                        self.pop();
+                        let result = self.call_native(&callee, &arguments)?;
+                        self.allocated += allocation_size(&result);
+                        self.push(result);
+                    }
+                }
+                OpCode::Closure(index) => {
+                    let ValueKind::Function(function) = constants[index].kind() else {
+                        unreachable!("closures are made from function constants");
+                    };
+                    let upvalues = function
+                        .captures
+                        .iter()
+                        .map(|capture| match *capture {
+                            Capture::Local(slot) => self.capture_upvalue(base + slot),
+                            Capture::Upvalue(index) => closure.upvalues[index].clone(),
+                        })
+                        .collect();
+                    let closure = Closure { function, upvalues };
+                    self.allocated += size_of::<Closure>()
+                        + closure.upvalues.len() * size_of::<Rc<RefCell<Upvalue>>>();
+                    self.push(Value::Closure(Rc::new(closure)));
+                }
+                OpCode::CloseUpvalue => {
+                    self.close_upvalues(self.stack.len() - 1);
+                    self.pop();
+                }
+                OpCode::Not => {
+                    let value = self.pop();
+                    self.push(Value::Bool(value.is_falsey()));
+                }
+                OpCode::Negate => match self.pop().as_number() {
+                    Some(value) => self.push(Value::Number(-value)),
+                    None => return Err(self.runtime_error("Operand must be a number.")),
+                },
+                OpCode::Print => println!("{}", self.pop()),
+                OpCode::AddLocalConst(slot, index) => {
+                    let a = self.local(base + slot).clone();
+                    // SAFETY: as for `Constant`.
+                    let b = unsafe { constants.get_unchecked(index) }.clone();
+                    let sum = self.add(a, b)?;
+                    self.push(sum);
+                }
+                OpCode::IncrementLocal(slot) => match self.local(base + slot).as_number() {
+                    Some(value) => *self.local_mut(base + slot) = Value::Number(value + 1.0),
+                    None => {
+                        return Err(
+                            self.runtime_error("Operands must be two numbers or two strings.")
+                        )
+                    }
+                },
+                OpCode::JumpIfNotLess(distance) => {
+                    let b = self.pop();
+                    let a = self.pop();
+                    let (Some(a), Some(b)) = (a.as_number(), b.as_number()) else {
+                        return Err(self.runtime_error("Operands must be numbers."));
+                    };
+                    if a.partial_cmp(&b) != Some(Ordering::Less) {
+                        *ip += distance;
                     }
                 }
                 OpCode::Return => {
-                    if let Some(stack_top) = self.stack.pop() {
-                        println!("{stack_top}");
-                        return InterpretResult::Ok;
+                    // Scripts only leave a value behind when echoing it.
+                    let result = (self.stack.len() > base).then(|| self.pop());
+                    self.close_upvalues(base);
+                    self.stack.truncate(base - 1);
+                    self.frames.pop();
+                    if self.frames.len() == depth {
+                        return Ok(Exit::Done(result));
                     }
+                    self.push(result.unwrap_or(Value::Null));
+                    return Ok(Exit::Switch);
                 }
             }
         }
     }
+    /// What `+` makes of `a` and `b`.
+    fn add(&mut self, a: Value, b: Value) -> Result<Value, RuntimeError> {
+        if let (Some(a), Some(b)) = (a.as_number(), b.as_number()) {
+            return Ok(Value::Number(a + b));
+        }
+        match (a.as_str(), b.as_str()) {
+            (Some(a), Some(b)) => {
+                self.allocated += a.len() + b.len();
+                Ok(Value::String(Rc::from(format!("{a}{b}"))))
+            }
+            _ => Err(self.runtime_error("Operands must be two numbers or two strings.")),
+        }
+    }
+    // The stack is only touched through these while a frame runs. Frames
+    // start with room for every slot their verified code uses, and that
+    // code never pops or reads below its base, so none of them check.
+    #[inline(always)]
+    fn push(&mut self, value: Value) {
+        let len = self.stack.len();
+        debug_assert!(len < self.stack.capacity(), "stack overflow");
+        // SAFETY: `check_room` made sure the slot is allocated.
+        unsafe {
+            self.stack.as_mut_ptr().add(len).write(value);
+            self.stack.set_len(len + 1);
+        }
+    }
+    #[inline(always)]
+    fn pop(&mut self) -> Value {
+        debug_assert!(!self.stack.is_empty(), "stack underflow");
+        // SAFETY: the verifier checked the stack doesn't underflow, and
+        // the slot is forgotten before it's moved out.
+        unsafe {
+            let len = self.stack.len() - 1;
+            self.stack.set_len(len);
+            self.stack.as_ptr().add(len).read()
+        }
+    }
+    #[inline(always)]
+    fn peek(&self) -> &Value {
+        debug_assert!(!self.stack.is_empty(), "stack underflow");
+        // SAFETY: the verifier checked the stack isn't empty here.
+        unsafe { self.stack.get_unchecked(self.stack.len() - 1) }
+    }
+    #[inline(always)]
+    fn local(&self, slot: usize) -> &Value {
+        debug_assert!(slot < self.stack.len(), "local slot out of range");
+        // SAFETY: the verifier checked that locals are below the top.
+        unsafe { self.stack.get_unchecked(slot) }
+    }
+    #[inline(always)]
+    fn local_mut(&mut self, slot: usize) -> &mut Value {
+        debug_assert!(slot < self.stack.len(), "local slot out of range");
+        // SAFETY: as for `local`.
+        unsafe { self.stack.get_unchecked_mut(slot) }
+    }
+    /// Fails with a stack overflow unless a frame can use `slots` values
+    /// from `base` on. The stack is allocated here the first time.
+    fn check_room(&mut self, base: usize, slots: usize) -> Result<(), RuntimeError> {
+        if base + slots > STACK_MAX {
+            return Err(self.runtime_error("Stack overflow."));
+        }
+        if self.stack.capacity() < STACK_MAX {
+            self.stack.reserve_exact(STACK_MAX - self.stack.len());
+        }
+        Ok(())
+    }
+    fn frame_mut(&mut self) -> &mut CallFrame {
+        self.frames.last_mut().expect("a frame is running")
+    }
+    /// Starts running `closure`, whose arguments are the top `count` values
+    /// on the stack.
+    fn call_closure(&mut self, closure: Rc<Closure>, count: usize) -> Result<(), RuntimeError> {
+        if count != closure.function.arity {
+            return Err(self.runtime_error(&format!(
+                "Expected {} arguments but got {count}.",
+                closure.function.arity
+            )));
+        }
+        if self
+            .limits
+            .stack_depth
+            .is_some_and(|max| self.frames.len() >= max)
+        {
+            return Err(RuntimeError::limit_exceeded(Limit::StackDepth));
+        }
+        if self.frames.len() == MAX_FRAMES {
+            return Err(self.runtime_error("Stack overflow."));
+        }
+        let base = self.stack.len() - count;
+        // Closures made by scripts were verified with them, but the host
+        // can put together its own, or run a chunk verified another way.
+        let like = VerifiedAs::function(closure.function.arity, closure.upvalues.len());
+        let slots = match closure.function.chunk.verified_stack(like) {
+            Some(slots) => slots,
+            None => {
+                verify_closure(&closure).map_err(|error| RuntimeError::new(error.to_string()))?;
+                closure
+                    .function
+                    .chunk
+                    .verified_stack(like)
+                    .unwrap_or_default()
+            }
+        };
+        self.check_room(base, slots)?;
+        self.frames.push(CallFrame {
+            closure,
+            ip: 0,
+            base,
+        });
+        Ok(())
+    }
+    /// Calls a native function, constructs an object or calls a method on
+    /// one, adding the native frame to the trace of errors.
+    fn call_native(&mut self, callee: &Value, arguments: &[Value]) -> Result<Value, RuntimeError> {
+        let callee = callee.kind();
+        let arity = match &callee {
+            ValueKind::Native(native) => native.arity,
+            ValueKind::Class(class) => class.constructor_arity(),
+            ValueKind::BoundMethod(object, method) => {
+                object.class().method_arity(method).unwrap_or(0)
+            }
+            _ => return Err(self.runtime_error("Can only call functions and classes.")),
+        };
+        if arguments.len() != arity {
+            return Err(self.runtime_error(&format!(
+                "Expected {arity} arguments but got {}.",
+                arguments.len()
+            )));
+        }
+        let result = match &callee {
+            ValueKind::Native(native) => (native.function)(self, arguments),
+            ValueKind::Class(class) => class.construct(self, arguments),
+            ValueKind::BoundMethod(object, method) => object.invoke(self, method, arguments),
+            _ => unreachable!(),
+        };
+        result.map_err(|mut error| {
+            let name = match &callee {
+                ValueKind::Native(native) => native.name.to_string(),
+                ValueKind::Class(class) => class.name().to_string(),
+                ValueKind::BoundMethod(object, method) => {
+                    format!("{}.{method}", object.class().name())
+                }
+                _ => unreachable!(),
+            };
+            error.push_frame(TraceFrame::native(name));
+            error
+        })
+    }
+    fn capture_upvalue(&mut self, slot: usize) -> Rc<RefCell<Upvalue>> {
+        let open = self
+            .open_upvalues
+            .iter()
+            .find(|upvalue| matches!(*upvalue.borrow(), Upvalue::Open(s) if s == slot));
+        if let Some(upvalue) = open {
+            return upvalue.clone();
+        }
+        let upvalue = Rc::new(RefCell::new(Upvalue::Open(slot)));
+        self.open_upvalues.push(upvalue.clone());
+        upvalue
+    }
+    /// Moves the variables in stack slots from `first` up into the upvalues
+    /// capturing them.
+    fn close_upvalues(&mut self, first: usize) {
+        let stack = &self.stack;
+        self.open_upvalues.retain(|upvalue| {
+            let mut upvalue = upvalue.borrow_mut();
+            match *upvalue {
+                Upvalue::Open(slot) if slot >= first => {
+                    *upvalue = Upvalue::Closed(stack[slot].clone());
+                    false
+                }
+                _ => true,
+            }
+        });
+    }
+    /// Starts counting towards the limits, unless a script is already
+    /// running and this is a native calling back into it.
+    fn start_counting(&mut self) {
+        if self.frames.is_empty() {
+            self.executed = 0;
+            self.allocated = 0;
+        }
+    }
+    /// Fails if the running script went over one of its limits. Stack depth
+    /// is checked as frames are pushed instead.
+    fn check_limits(&self) -> Result<(), RuntimeError> {
+        let limits = &self.limits;
+        let exceeded = if limits.instructions.is_some_and(|max| self.executed > max) {
+            Limit::Instructions
+        } else if limits.heap_bytes.is_some_and(|max| self.allocated > max) {
+            Limit::HeapBytes
+        } else if limits
+            .deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
+        {
+            Limit::Deadline
+        } else {
+            return Ok(());
+        };
+        Err(RuntimeError::limit_exceeded(exceeded))
+    }
+    fn runtime_error(&self, message: &str) -> RuntimeError {
+        RuntimeError::new(message)
+    }
+    /// Adds the frames from `depth` up to the trace of `error` and drops
+    /// them, leaving the stack as it was before the call at `depth`.
+    fn unwind(&mut self, mut error: RuntimeError, depth: usize) -> RuntimeError {
+        for frame in self.frames[depth..].iter().rev() {
+            let function = &frame.closure.function;
+            let span = function.chunk.span_at(frame.ip - 1);
+            error.span.get_or_insert(span);
+            error.push_frame(TraceFrame {
+                function: function.name.as_deref().map(str::to_string),
+                span: Some(span),
+                repeated: 0,
+            });
+        }
+        let base = self.frames[depth].base;
+        self.frames.truncate(depth);
+        self.close_upvalues(base);
+        self.stack.truncate(base - 1);
+        error
+    }
 
-    #[cfg(feature = "debug_trace_execution")]
-    fn disassemble_instruction(&self, instruction: &OpCode) {
-        print!("          ");
+    fn disassemble_instruction(&mut self, chunk: &Chunk, offset: usize, instruction: &OpCode) {
+        let Some(out) = self.trace.as_mut() else {
+            return;
+        };
+        let mut stack = String::from("          ");
         for value in &self.stack {
-            print!("[ {value} ]");
+            stack.push_str(&format!("[ {value} ]"));
         }
-        println!("");
-        self.chunk.disassemble_instruction(self.ip, instruction);
+        // A broken trace destination should not stop the program.
+        let _ = writeln!(out, "{stack}")
+            .and_then(|_| chunk.disassemble_instruction(offset, instruction, out));
+    }
+}
+
+fn constant_name(constants: &[Value], index: usize) -> Rc<str> {
+    match constants[index].kind() {
+        ValueKind::String(name) => name,
+        _ => unreachable!("variable and property names are string constants"),
     }
 }
 
-#[derive(Debug)]
-pub enum InterpretResult {
-    Ok,
-    SyntaxError,
-    CompileError,
-    RuntimeError,
+/// Roughly how many bytes making `value` allocated, not counting what it
+/// shares with other values.
+fn allocation_size(value: &Value) -> usize {
+    match value.kind() {
+        ValueKind::String(string) => string.len(),
+        ValueKind::List(items) => items.len() * size_of::<Value>(),
+        ValueKind::Map(entries) => entries.len() * size_of::<(Rc<str>, Value)>(),
+        _ => 0,
+    }
+}
+
+/// An error raised while running a script, including by native functions.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuntimeError {
+    message: String,
+    /// The frames the error went through, innermost first.
+    trace: Vec<TraceFrame>,
+    span: Option<Span>,
+    limit: Option<Limit>,
+}
+
+impl RuntimeError {
+    pub fn new(message: impl Into<String>) -> RuntimeError {
+        RuntimeError {
+            message: message.into(),
+            trace: vec![],
+            span: None,
+            limit: None,
+        }
+    }
+    fn limit_exceeded(limit: Limit) -> RuntimeError {
+        RuntimeError {
+            limit: Some(limit),
+            ..RuntimeError::new(limit.to_string())
+        }
+    }
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+    /// The instruction that failed, or the call of the native that raised
+    /// the error. `None` until the error leaves the `Vm`.
+    pub fn span(&self) -> Option<Span> {
+        self.span
+    }
+    /// The limit the script went over, if that is what stopped it.
+    pub fn limit(&self) -> Option<Limit> {
+        self.limit
+    }
+    /// How the error got where it was raised, innermost frame first.
+    pub fn trace(&self) -> &[TraceFrame] {
+        &self.trace
+    }
+    /// Adds the next outer frame, folding it into the last one when a
+    /// function keeps calling itself from the same place.
+    fn push_frame(&mut self, frame: TraceFrame) {
+        match self.trace.last_mut() {
+            Some(last) if last.function == frame.function && last.span == frame.span => {
+                last.repeated += 1
+            }
+            _ => self.trace.push(frame),
+        }
+    }
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)?;
+        for frame in &self.trace {
+            write!(f, "\n{frame}")?;
+            if frame.repeated > 0 {
+                write!(f, "\n... repeated {} more times", frame.repeated)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Error for RuntimeError {}
+
+/// A call a runtime error went through.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceFrame {
+    /// The name of the function, `None` for the top-level script.
+    pub function: Option<String>,
+    /// Where the function was when the error went through it, `None` for
+    /// natives.
+    pub span: Option<Span>,
+    /// How many more times the same frame follows this one, as in deep
+    /// recursion.
+    pub repeated: usize,
+}
+
+impl TraceFrame {
+    fn native(name: String) -> TraceFrame {
+        TraceFrame {
+            function: Some(name),
+            span: None,
+            repeated: 0,
+        }
+    }
+}
+
+impl fmt::Display for TraceFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.span {
+            Some(Span { line, column }) => write!(f, "[line {line}:{column}]")?,
+            None => write!(f, "[native]")?,
+        }
+        match &self.function {
+            Some(name) => write!(f, " in fn {name}()"),
+            None => write!(f, " in script"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        compiler::{compile, compile_repl},
+        value::{FromValue, IntoValue},
+    };
+
+    fn run(vm: &mut Vm, source: &str) -> Result<Value, RabbitError> {
+        vm.interpret(compile(source).unwrap())
+    }
+
+    #[test]
+    fn globals_persist_across_chunks() {
+        let mut vm = Vm::new();
+        assert!(run(&mut vm, "let a = 1;").is_ok());
+        assert_eq!(
+            vm.evaluate(compile_repl("a = a + 2").unwrap()),
+            Ok(Some(Value::Number(3.0)))
+        );
+        assert_eq!(vm.globals.get("a"), Some(&Value::Number(3.0)));
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn locals_and_strings_work() {
+        let mut vm = Vm::new();
+        let source = "let s = \"a\"; { let t = s + \"b\"; t += \"c\"; s = t; }";
+        assert!(run(&mut vm, source).is_ok());
+        assert_eq!(vm.globals.get("s"), Some(&Value::String(Rc::from("abc"))));
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn lists_can_be_built_and_indexed() {
+        let mut vm = Vm::new();
+        let source = "let l = [1, \"a\", [true]]; let x = l[2][0]; let s = \"\" + l[1];";
+        assert!(run(&mut vm, source).is_ok());
+        assert_eq!(vm.globals.get("x"), Some(&Value::Bool(true)));
+        assert_eq!(
+            vm.globals.get("l").unwrap().to_string(),
+            "[1, \"a\", [true]]"
+        );
+        assert!(run(&mut vm, "l[3];").is_err());
+        assert!(run(&mut vm, "l[0.5];").is_err());
+        assert!(run(&mut vm, "1[0];").is_err());
+    }
+
+    #[test]
+    fn tracing_writes_each_instruction() {
+        #[derive(Clone, Default)]
+        struct Buffer(Rc<std::cell::RefCell<Vec<u8>>>);
+        impl Write for Buffer {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.borrow_mut().extend_from_slice(buf);
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let buffer = Buffer::default();
+        let mut vm = Vm::new();
+        vm.set_trace(Some(Box::new(buffer.clone())));
+        assert!(run(&mut vm, "{ let a = 1; print a * 2; }").is_ok());
+        assert_eq!(
+            String::from_utf8(buffer.0.borrow().clone()).unwrap(),
+            "          [ <script> ]
+0000    1:11   OP_CONSTANT         0 '1'
+          [ <script> ][ 1 ]
+0002    1:20   OP_GET_LOCAL        0
+          [ <script> ][ 1 ][ 1 ]
+0004    1:24   OP_CONSTANT         1 '2'
+          [ <script> ][ 1 ][ 1 ][ 2 ]
+0006    1:22   OP_MULTIPLY
+          [ <script> ][ 1 ][ 2 ]
+0007    1:25   OP_PRINT
+          [ <script> ][ 1 ]
+0008    1:27   OP_POP
+          [ <script> ]
+0009    1:27   OP_RETURN
+"
+        );
+    }
+
+    fn sum(_vm: &mut Vm, arguments: &[Value]) -> Result<Value, RuntimeError> {
+        let numbers = Vec::<f64>::from_value(&arguments[0])?;
+        Ok(numbers.iter().sum::<f64>().into_value())
+    }
+
+    fn keys(_vm: &mut Vm, arguments: &[Value]) -> Result<Value, RuntimeError> {
+        let map = HashMap::<String, Value>::from_value(&arguments[0])?;
+        let mut keys: Vec<String> = map.into_keys().collect();
+        keys.sort();
+        Ok(keys.into_value())
+    }
+
+    fn wipe(vm: &mut Vm, _arguments: &[Value]) -> Result<Value, RuntimeError> {
+        vm.reset();
+        Ok(Value::Number(2.0))
+    }
+
+    #[test]
+    fn natives_resetting_the_vm_leave_the_stack_alone() {
+        let mut vm = Vm::new();
+        vm.register_native("wipe", 0, wipe);
+        let source = "{ let a = 1; let b = 3; let c = a + wipe() + b; total = c; }";
+        let error = run(&mut vm, source).unwrap_err();
+        assert!(error.to_string().starts_with("Undefined variable 'total'."));
+        assert!(vm.stack.is_empty() && vm.frames.is_empty());
+        assert_eq!(vm.get_global("wipe"), None);
+    }
+
+    #[test]
+    fn chunks_verified_as_scripts_are_verified_again_as_functions() {
+        let call = |arity: usize| {
+            let chunk = compile("print 1;").unwrap();
+            verify(&chunk).unwrap();
+            let function = Function {
+                name: Some(Rc::from("f")),
+                arity,
+                chunk,
+                captures: vec![],
+            };
+            let callee = Value::Closure(Rc::new(Closure {
+                function: Rc::new(function),
+                upvalues: vec![],
+            }));
+            let mut vm = Vm::new();
+            let result = vm.call(&callee, &vec![Value::Null; arity]);
+            assert!(vm.stack.is_empty() && vm.frames.is_empty());
+            result
+        };
+        assert!(call(2).is_ok());
+        let error = call(u16::MAX as usize).unwrap_err();
+        assert!(error.to_string().starts_with("Stack overflow."));
+    }
+
+    #[test]
+    fn natives_can_be_called() {
+        let mut vm = Vm::new();
+        vm.register_native("sum", 1, sum);
+        vm.register_native("keys", 1, keys);
+        vm.set_global("m", HashMap::from([(String::from("k"), 1.0)]).into_value());
+        let source = "let s = sum([1, 2, m[\"k\"]]); let k = keys(m)[0];";
+        assert!(run(&mut vm, source).is_ok());
+        assert_eq!(vm.globals.get("s"), Some(&Value::Number(4.0)));
+        assert_eq!(vm.globals.get("k"), Some(&Value::String(Rc::from("k"))));
+        assert_eq!(
+            vm.globals.get("sum").unwrap().to_string(),
+            "<native fn sum>"
+        );
+    }
+
+    #[test]
+    fn native_errors_stop_the_script() {
+        let mut vm = Vm::new();
+        vm.register_native("sum", 1, sum);
+        assert!(run(&mut vm, "sum();").is_err());
+        assert!(run(&mut vm, "sum([true]);").is_err());
+        assert!(run(&mut vm, "1();").is_err());
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn functions_and_closures_work() {
+        let mut vm = Vm::new();
+        let source = "
+            fn counter() {
+                let count = 0;
+                fn next() { count += 1; return count; }
+                return next;
+            }
+            let c = counter();
+            c();
+            let n = c();
+            let adder = fn(a) { return fn(b) { return a + b; }; };
+            let sum = adder(1)(2);
+        ";
+        assert!(run(&mut vm, source).is_ok());
+        assert_eq!(vm.get_global("n"), Some(&Value::Number(2.0)));
+        assert_eq!(vm.get_global("sum"), Some(&Value::Number(3.0)));
+        assert!(vm.stack.is_empty());
+    }
+
+    fn sort_by(vm: &mut Vm, arguments: &[Value]) -> Result<Value, RuntimeError> {
+        let mut items = Vec::<Value>::from_value(&arguments[0])?;
+        let mut error = None;
+        items.sort_by(
+            |a, b| match vm.call(&arguments[1], &[a.clone(), b.clone()]) {
+                Ok(result) => result
+                    .as_number()
+                    .and_then(|n| n.partial_cmp(&0.0))
+                    .unwrap_or(std::cmp::Ordering::Equal),
+                Err(e) => {
+                    error.get_or_insert(e);
+                    std::cmp::Ordering::Equal
+                }
+            },
+        );
+        match error {
+            Some(error) => Err(error),
+            None => Ok(items.into_value()),
+        }
+    }
+
+    #[test]
+    fn natives_can_call_back_into_scripts() {
+        let mut vm = Vm::new();
+        vm.register_native("sort_by", 2, sort_by);
+        let source = "let sorted = sort_by([3, 1, 2], fn(a, b) { return b - a; });";
+        assert!(run(&mut vm, source).is_ok());
+        assert_eq!(vm.get_global("sorted").unwrap().to_string(), "[3, 2, 1]");
+
+        let source = "fn negate(x) { return -x; }";
+        assert!(run(&mut vm, source).is_ok());
+        let negate = vm.get_global("negate").unwrap().clone();
+        assert_eq!(
+            vm.call(&negate, &[Value::Number(1.0)]),
+            Ok(Value::Number(-1.0))
+        );
+        assert!(vm.call(&negate, &[]).is_err());
+
+        let error = vm.call(&negate, &[Value::Null]).unwrap_err();
+        assert_eq!(
+            error.trace(),
+            [TraceFrame {
+                function: Some(String::from("negate")),
+                span: Some(Span {
+                    line: 1,
+                    column: 23
+                }),
+                repeated: 0,
+            }]
+        );
+        assert!(vm.stack.is_empty() && vm.frames.is_empty());
+    }
+
+    #[test]
+    fn errors_unwind_through_natives() {
+        let mut vm = Vm::new();
+        vm.register_native("sort_by", 2, sort_by);
+        let error = vm.call(&Value::Null, &[]).unwrap_err();
+        assert_eq!(error.message(), "Can only call functions and classes.");
+        let source = "fn f(a, b) { return a.x; }\nsort_by([1, 2], f);";
+        let chunk = compile(source).unwrap();
+        verify(&chunk).unwrap();
+        let error = vm.run_script(chunk).unwrap_err();
+        assert_eq!(error.message(), "Only objects have properties.");
+        assert_eq!(
+            error.to_string(),
+            "Only objects have properties.
+[line 1:23] in fn f()
+[native] in fn sort_by()
+[line 2:8] in script"
+        );
+        assert!(vm.stack.is_empty() && vm.frames.is_empty());
+    }
+
+    #[test]
+    fn control_flow_works() {
+        let mut vm = Vm::new();
+        let source = "
+            fn fib(n) { if n < 2 { return n; } return fib(n - 1) + fib(n - 2); }
+            let i = 0;
+            let evens = 0;
+            while i < 10 {
+                if i % 2 == 0 { evens += 1; } else if i == 9 { evens = -evens; }
+                i += 1;
+            }
+            let f = fib(10);
+        ";
+        assert!(run(&mut vm, source).is_ok());
+        assert_eq!(vm.get_global("evens"), Some(&Value::Number(-5.0)));
+        assert_eq!(vm.get_global("f"), Some(&Value::Number(55.0)));
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn limits_stop_runaway_scripts() {
+        fn limit(vm: &mut Vm, source: &str) -> Option<Limit> {
+            match run(vm, source) {
+                Err(RabbitError::LimitExceeded(error)) => error.limit(),
+                _ => None,
+            }
+        }
+
+        let mut vm = Vm::new();
+        vm.set_limits(Limits {
+            instructions: Some(1000),
+            ..Limits::default()
+        });
+        assert_eq!(limit(&mut vm, "while true {}"), Some(Limit::Instructions));
+        assert!(run(&mut vm, "let i = 0; while i < 10 { i += 1; }").is_ok());
+        assert!(vm.stack.is_empty() && vm.frames.is_empty());
+
+        vm.set_limits(Limits {
+            heap_bytes: Some(1000),
+            ..Limits::default()
+        });
+        let source = "let s = \"\"; while true { s = s + \"abcd\"; }";
+        assert_eq!(limit(&mut vm, source), Some(Limit::HeapBytes));
+
+        vm.set_limits(Limits {
+            stack_depth: Some(10),
+            ..Limits::default()
+        });
+        assert_eq!(
+            limit(&mut vm, "fn f() { f(); } f();"),
+            Some(Limit::StackDepth)
+        );
+
+        vm.set_limits(Limits {
+            deadline: Some(Instant::now()),
+            ..Limits::default()
+        });
+        let error = run(&mut vm, "while true {}").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Time limit exceeded.\n[line 1:13] in script"
+        );
+    }
+
+    #[test]
+    fn deep_recursion_overflows() {
+        let mut vm = Vm::new();
+        let error = run(&mut vm, "fn f() { f(); } f();").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Stack overflow.
+[line 1:11] in fn f()
+... repeated 1022 more times
+[line 1:18] in script"
+        );
+        assert!(vm.stack.is_empty() && vm.frames.is_empty());
+    }
+
+    #[test]
+    fn the_stack_is_allocated_once() {
+        let mut vm = Vm::new();
+        run(&mut vm, "fn f(n) { if n > 0 { f(n - 1); } } f(1000);").unwrap();
+        assert_eq!(vm.stack.capacity(), STACK_MAX);
+        let f = vm.get_global("f").unwrap().clone();
+        let error = vm.call(&f, &vec![Value::Null; STACK_MAX]).unwrap_err();
+        assert_eq!(error.message(), "Stack overflow.");
+        assert!(vm.stack.is_empty() && vm.frames.is_empty());
+    }
+
+    #[test]
+    fn closures_from_the_host_are_verified() {
+        let function = Function {
+            name: Some(Rc::from("f")),
+            arity: 1,
+            chunk: {
+                let mut chunk = Chunk::new();
+                for opcode in [OpCode::GetLocal(1), OpCode::Return] {
+                    chunk.write(opcode, Span { line: 1, column: 1 });
+                }
+                chunk
+            },
+            captures: vec![],
+        };
+        let closure = Value::Closure(Rc::new(Closure {
+            function: Rc::new(function),
+            upvalues: vec![],
+        }));
+        let error = Vm::new().call(&closure, &[Value::Null]).unwrap_err();
+        assert_eq!(
+            error.message(),
+            "Invalid bytecode in <fn f> at offset 0: Local slot 1 is out of range."
+        );
+    }
+
+    #[test]
+    fn runtime_errors_reset_the_stack() {
+        let mut vm = Vm::new();
+        assert!(run(&mut vm, "1 + -true;").is_err());
+        assert!(vm.stack.is_empty());
+        assert!(run(&mut vm, "x = 1;").is_err());
+        assert!(run(&mut vm, "let b = !null == (7 % 4 >= 3);").is_ok());
+        assert_eq!(vm.globals.get("b"), Some(&Value::Bool(true)));
+    }
+
+    #[cfg(feature = "nan_boxing")]
+    #[test]
+    fn values_are_nan_boxed() {
+        assert_eq!(size_of::<Value>(), 8);
+    }
+
+    #[test]
+    fn folded_code_keeps_its_spans() {
+        let error = run(&mut Vm::new(), "print -(1 + 2) * \"x\";").unwrap_err();
+        assert_eq!(
+            error.span(),
+            Some(Span {
+                line: 1,
+                column: 16
+            })
+        );
+    }
 }